@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, ensure};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    time,
+};
+
+use crate::config::{AdmissionPolicy, ClientLimit};
+
+pub struct Admission {
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+    policy: AdmissionPolicy,
+}
+
+impl Admission {
+    pub fn new(limit: ClientLimit) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(limit.max_clients)),
+            queued: AtomicUsize::new(0),
+            policy: limit.policy,
+        }
+    }
+
+    pub async fn admit(&self) -> anyhow::Result<OwnedSemaphorePermit> {
+        match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => return Ok(permit),
+            Err(TryAcquireError::Closed) => bail!("server is shutting down"),
+            Err(TryAcquireError::NoPermits) => {}
+        }
+
+        let AdmissionPolicy::Queue { timeout, capacity } = self.policy else {
+            bail!("server is full");
+        };
+        let _place = QueuePlace::take(&self.queued, capacity)?;
+        match time::timeout(timeout, self.slots.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => bail!("server is shutting down"),
+            Err(_) => bail!("timed out waiting for a free client slot"),
+        }
+    }
+}
+
+struct QueuePlace<'a> {
+    queued: &'a AtomicUsize,
+}
+
+impl<'a> QueuePlace<'a> {
+    fn take(queued: &'a AtomicUsize, capacity: usize) -> anyhow::Result<Self> {
+        if queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < capacity).then_some(n + 1)
+            })
+            .is_err()
+        {
+            bail!("server is full and the wait queue is at capacity");
+        }
+        Ok(Self { queued })
+    }
+}
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub struct IdentityLimit {
+    max_connections: usize,
+    active: Mutex<HashMap<String, usize>>,
+}
+
+pub struct IdentitySlot<'a> {
+    limit: &'a IdentityLimit,
+    common_name: String,
+}
+
+impl IdentityLimit {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn acquire(&self, common_name: &str) -> anyhow::Result<IdentitySlot<'_>> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(common_name.to_owned()).or_default();
+        ensure!(
+            *count < self.max_connections,
+            "{common_name} already has {count} connections"
+        );
+        *count += 1;
+        Ok(IdentitySlot {
+            limit: self,
+            common_name: common_name.to_owned(),
+        })
+    }
+}
+
+impl Drop for IdentitySlot<'_> {
+    fn drop(&mut self) {
+        let mut active = self.limit.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.common_name) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.common_name);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cores: &[usize]) -> anyhow::Result<()> {
+    use anyhow::{ensure, Context};
+
+    // SAFETY: cpu_set_t is a plain bitmask for which all zeroes is a valid value
+    let mut allowed: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: allowed is a live cpu_set_t of the size passed
+    let res = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &raw mut allowed) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error()).context("could not query CPU affinity");
+    }
+
+    // SAFETY: as above
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &core in cores {
+        let available = core < libc::CPU_SETSIZE as usize && {
+            // SAFETY: core was checked to be below CPU_SETSIZE, so the bit lies within allowed
+            unsafe { libc::CPU_ISSET(core, &allowed) }
+        };
+        ensure!(
+            available,
+            "CPU core {core} does not exist or is not available to this process"
+        );
+        // SAFETY: core was checked to be below CPU_SETSIZE
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+
+    // SAFETY: set is a live cpu_set_t of the size passed
+    let res = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &raw const set) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("could not pin to CPU cores {cores:?}"));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cores: &[usize]) -> anyhow::Result<()> {
+    anyhow::bail!("CPU affinity is only supported on Linux")
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn current_cores() -> Vec<usize> {
+        // SAFETY: as in pin_current_thread
+        let mut allowed: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        // SAFETY: as in pin_current_thread
+        let res =
+            unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &raw mut allowed) };
+        assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+        (0..libc::CPU_SETSIZE as usize)
+            // SAFETY: core is below CPU_SETSIZE
+            .filter(|&core| unsafe { libc::CPU_ISSET(core, &allowed) })
+            .collect()
+    }
+
+    // pinning is per thread, a fresh one leaves the test harness alone
+    #[test]
+    fn thread_is_pinned_to_an_available_core() {
+        thread::spawn(|| {
+            let core = *current_cores().last().unwrap();
+            pin_current_thread(&[core]).unwrap();
+            assert_eq!(current_cores(), [core]);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn core_beyond_the_cpu_set_is_refused() {
+        thread::spawn(|| {
+            let before = current_cores();
+            let err = pin_current_thread(&[libc::CPU_SETSIZE as usize]).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("does not exist or is not available"),
+                "{err}"
+            );
+            assert_eq!(current_cores(), before);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,22 +1,107 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
-use futures::io;
-use tokio::{net::TcpStream, sync::watch};
-use tokio_rustls::{rustls, TlsConnector};
-use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use anyhow::{anyhow, bail, Context};
+use futures::io::{self, BufReader, BufWriter};
+use log::{debug, info, warn};
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    net::{TcpStream, UdpSocket},
+    sync::watch,
+    time::{self, MissedTickBehavior},
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            ResolvesClientCert, WebPkiServerVerifier,
+        },
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        sign::CertifiedKey,
+        AlertDescription, DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 use tun::AbstractDevice;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 use crate::{
-    common::get_root_cert_store,
-    config::{ClientConfig, TlsConfig},
-    packet_stream::{PacketReceiver, PacketSender, TunReceiver, TunSender},
-    protocol::{Connection, NetworkConfig},
+    common::{get_root_cert_store, random_u64, SplitMix64},
+    config::{
+        BufferSizes, ClientConfig, ErrorClass, InterfaceStyle, Jitter, Keepalive, ProxyConfig,
+        ReconnectConfig, TlsConfig, TlsIdentity, Transport,
+    },
+    cover,
+    packet_stream::{
+        DatagramKeys, KeepaliveReceiver, KeepaliveSender, MeteredReceiver, MeteredSender,
+        PacketReceiver, PacketSender, TimeoutSender, TrafficCounter, TrafficSnapshot, TunReceiver,
+        TunSender, UdpEndpoint, UdpPacketReceiver, UdpPacketSender, DATAGRAM_IDLE_TIMEOUT,
+        DATAGRAM_KEEPALIVE,
+    },
+    protocol::{
+        as_rejection, is_heartbeat, negotiate_keepalive, Connection, NetworkConfig, ProtocolError,
+        Rejection,
+    },
+    proxy,
+    resolver::{resolve, AddressCache},
+    system_dns::DnsOverride,
+    system_route::DefaultRoute,
+    undo_script::UndoScript,
 };
 
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+// long enough to spare reconnects the lookup, short enough to follow a DNS failover
+const ADDRESS_TTL: Duration = Duration::from_secs(300);
+
+type ClientConnection = Connection<
+    BufReader<Compat<ReadHalf<TlsStream<TcpStream>>>>,
+    BufWriter<Compat<WriteHalf<TlsStream<TcpStream>>>>,
+>;
+
+// the undo script is removed only after the route and DNS settings have been restored
+struct Interface {
+    route: Option<DefaultRoute>,
+    dns: Option<DnsOverride>,
+    undo: Option<UndoScript>,
+    config: NetworkConfig,
+    name: String,
+    receiver: TunReceiver,
+    sender: TimeoutSender<TunSender>,
+}
+
+struct DatagramChannel {
+    _endpoint: UdpEndpoint,
+    sender: UdpPacketSender,
+    receiver: UdpPacketReceiver,
+}
+
 pub struct Client {
     connector: TlsConnector,
-    socket_address: SocketAddr,
+    host: String,
+    port: u16,
+    resolver: Option<IpAddr>,
+    server_name: Option<ServerName<'static>>,
+    addresses: AddressCache,
+    write_timeout: Option<Duration>,
+    close_linger: Duration,
+    keepalive: Option<Keepalive>,
+    interface_style: InterfaceStyle,
+    cover_request: Option<Vec<u8>>,
+    buffers: BufferSizes,
+    transport: Transport,
+    reconnect: Option<ReconnectConfig>,
+    default_route: bool,
+    apply_dns: bool,
+    undo_script: Option<PathBuf>,
+    advertised_routes: Vec<(Ipv4Addr, u8)>,
+    proxy: Option<ProxyConfig>,
     stop_sender: watch::Sender<bool>,
     stop_receiver: watch::Receiver<bool>,
 }
@@ -26,7 +111,24 @@ impl Client {
         let (sender, receiver) = watch::channel(false);
         Ok(Self {
             connector: Arc::new(configure_tls(tls)?).into(),
-            socket_address: config.address,
+            host: config.host,
+            port: config.port,
+            resolver: config.resolver,
+            server_name: config.server_name,
+            addresses: AddressCache::new(ADDRESS_TTL),
+            write_timeout: config.write_timeout,
+            close_linger: config.close_linger,
+            keepalive: config.keepalive,
+            interface_style: config.interface_style,
+            cover_request: config.cover_request,
+            buffers: config.buffers,
+            transport: config.transport,
+            reconnect: config.reconnect,
+            default_route: config.default_route,
+            apply_dns: config.apply_dns,
+            undo_script: config.undo_script,
+            advertised_routes: config.advertised_routes,
+            proxy: config.proxy,
             stop_sender: sender,
             stop_receiver: receiver,
         })
@@ -36,55 +138,736 @@ impl Client {
         self.stop_sender.clone()
     }
 
-    pub async fn run(self) -> anyhow::Result<()> {
-        let socket = TcpStream::connect(self.socket_address).await?;
-        let client = self
-            .connector
-            .connect(self.socket_address.ip().into(), socket)
+    pub async fn test_connect(self) -> anyhow::Result<()> {
+        let (client, _) = self.connect().await?;
+        let (_, connection) = client.get_ref();
+        if let Some(version) = connection.protocol_version() {
+            println!("protocol: {version:?}");
+        }
+        if let Some(suite) = connection.negotiated_cipher_suite() {
+            println!("cipher suite: {:?}", suite.suite());
+        }
+        let cert = connection
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .context("server did not present a certificate")?;
+        let (_, cert) = X509Certificate::from_der(cert).context("could not parse certificate")?;
+        println!("subject: {}", cert.subject());
+        println!("issuer: {}", cert.issuer());
+        println!("valid until: {}", cert.validity().not_after);
+        Ok(())
+    }
+
+    async fn connect(&self) -> anyhow::Result<(TlsStream<TcpStream>, Option<u16>)> {
+        if let Some(proxy) = &self.proxy {
+            let socket = proxy::connect(proxy, &self.host, self.port).await?;
+            // the proxy resolves the host, so only a literal address is known here
+            let server_name = self.server_name(self.host.parse().ok())?;
+            // the path MTU of the hop to the proxy says nothing about the path to the server
+            return Ok((self.secure(socket, server_name).await?, None));
+        }
+        let socket_address = self
+            .addresses
+            .get_or_resolve(resolve(&self.host, self.port, self.resolver))
             .await?;
+        let res = self.connect_to(socket_address).await;
+        // the server may have moved, the next attempt looks it up again
+        if res.is_err() {
+            self.addresses.invalidate();
+        }
+        res
+    }
+
+    async fn connect_to(
+        &self,
+        socket_address: SocketAddr,
+    ) -> anyhow::Result<(TlsStream<TcpStream>, Option<u16>)> {
+        let socket = TcpStream::connect(socket_address)
+            .await
+            .with_context(|| format!("could not connect to {socket_address}"))?;
+        let path_mtu = path_mtu(&socket);
+        let server_name = self.server_name(Some(socket_address.ip()))?;
+        let client = self.secure(socket, server_name).await?;
+        Ok((client, path_mtu))
+    }
+
+    // the same certificate has to pass with and without a proxy, so both check it
+    // against the configured server name, or else the server's IP
+    fn server_name(&self, ip: Option<IpAddr>) -> anyhow::Result<ServerName<'static>> {
+        match (&self.server_name, ip) {
+            (Some(server_name), _) => Ok(server_name.clone()),
+            (None, Some(ip)) => Ok(ip.into()),
+            (None, None) => bail!("a proxy with a server host name requires 'server_name'"),
+        }
+    }
+
+    async fn secure(
+        &self,
+        mut socket: TcpStream,
+        server_name: ServerName<'static>,
+    ) -> anyhow::Result<TlsStream<TcpStream>> {
+        if let Some(request) = &self.cover_request {
+            cover::request(&mut (&mut socket).compat(), request)
+                .await
+                .context("cover exchange failed")?;
+        }
+        self.connector
+            .connect(server_name, socket)
+            .await
+            .context("TLS handshake failed")
+    }
+
+    async fn handshake(
+        &self,
+    ) -> anyhow::Result<(
+        ClientConnection,
+        NetworkConfig,
+        SocketAddr,
+        Option<DatagramChannel>,
+        Option<Keepalive>,
+    )> {
+        let (client, path_mtu) = self.connect().await?;
+        let server_address = client.get_ref().0.peer_addr()?;
+        let datagram_keys = match self.transport {
+            Transport::Tcp => None,
+            Transport::Udp => Some(DatagramKeys::export(client.get_ref().1, false)?),
+        };
         let (client_reader, client_writer) = tokio::io::split(client);
-        let client_reader = client_reader.compat();
-        let client_writer = client_writer.compat_write();
+        let client_reader = BufReader::with_capacity(self.buffers.read, client_reader.compat());
+        let client_writer =
+            BufWriter::with_capacity(self.buffers.write, client_writer.compat_write());
         let mut protocol_connection = Connection::new(client_reader, client_writer);
 
+        protocol_connection
+            .send_version()
+            .await
+            .context("could not send protocol version")?;
+        protocol_connection
+            .send_path_mtu(path_mtu)
+            .await
+            .context("could not send path MTU")?;
+        protocol_connection
+            .send_transport(self.transport)
+            .await
+            .context("could not request transport")?;
+        protocol_connection
+            .send_routes(&self.advertised_routes)
+            .await
+            .context("could not advertise routes")?;
+        protocol_connection
+            .send_keepalive(self.keepalive.map(|keepalive| keepalive.interval))
+            .await
+            .context("could not announce keepalive interval")?;
         let network_config = protocol_connection
             .receive_config()
             .await
             .context("could not receive network config")?;
-        let tun_config = configure_tun(network_config);
+        let server_keepalive = protocol_connection
+            .receive_keepalive()
+            .await
+            .context("could not receive server keepalive interval")?;
+        debug!("received network config {network_config}");
+        let datagrams = match datagram_keys {
+            Some(keys) => Some(open_datagrams(keys, server_address, network_config.mtu).await?),
+            None => None,
+        };
+        Ok((
+            protocol_connection,
+            network_config,
+            server_address,
+            datagrams,
+            negotiate_keepalive(self.keepalive, server_keepalive),
+        ))
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        let mut interface = None;
+        let mut attempts = Attempts::new(random_u64());
+        let res = loop {
+            let res = self.session(&mut interface, &mut attempts).await;
+            if *self.stop_receiver.borrow() {
+                break res;
+            }
+            let Some(reconnect) = &self.reconnect else {
+                break res;
+            };
+            let delay = match attempts.retry(reconnect, res) {
+                Ok(delay) => delay,
+                Err(err) => break Err(err),
+            };
+            if let Some(interface) = &mut interface {
+                interface.suspend();
+            }
+            let mut stop_token = self.stop_receiver.clone();
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = stop_token.wait_for(|&stop| stop) => break Ok(()),
+            }
+        };
+        if let Some(interface) = interface {
+            info!("interface {} down", interface.name);
+        }
+        res
+    }
+
+    // the interface outlives the session, so routes on it survive a reconnect
+    // unless the server pushes a different network config
+    async fn session(
+        &self,
+        interface: &mut Option<Interface>,
+        attempts: &mut Attempts,
+    ) -> anyhow::Result<()> {
+        let mut stop_token = self.stop_receiver.clone();
+        let (protocol_connection, network_config, server_address, datagrams, keepalive) = tokio::select! {
+            res = self.handshake() => res?,
+            _ = stop_token.wait_for(|&stop| stop) => {
+                info!("stopped before the handshake completed");
+                return Ok(());
+            }
+        };
+        if attempts.connected() {
+            info!("reconnected to {}:{}", self.host, self.port);
+        }
+        if interface
+            .as_ref()
+            .is_some_and(|interface| interface.config != network_config)
+        {
+            let old = interface.take().unwrap();
+            info!("interface {} down, network config changed", old.name);
+        }
+        let interface = match interface {
+            Some(interface) => interface,
+            None => interface.insert(self.create_interface(network_config.clone())?),
+        };
+        if let Some(route) = &mut interface.route {
+            route.reroute(
+                server_address.ip(),
+                network_config.server_ip,
+                &interface.name,
+            )?;
+        }
+        if self.apply_dns
+            && interface.dns.is_none()
+            && (!network_config.dns.is_empty() || network_config.search_domain.is_some())
+        {
+            interface.dns = Some(DnsOverride::apply(
+                &interface.name,
+                &network_config.dns,
+                network_config.search_domain.as_deref(),
+            )?);
+        }
+        interface.write_undo_script()?;
+
+        let (packet_sender, packet_receiver) = protocol_connection.into_parts();
+        // keepalives go through the write timeout as well, they share the stream with packets
+        let mut packet_sender = KeepaliveSender::new(
+            TimeoutSender::new(packet_sender, self.write_timeout, self.close_linger),
+            keepalive.map(|keepalive| keepalive.interval),
+        );
+        let packet_receiver = KeepaliveReceiver::new(
+            packet_receiver,
+            keepalive.map(|keepalive| keepalive.timeout),
+        );
+        let tun_receiver = &mut interface.receiver;
+        let tun_sender = &mut interface.sender;
+        match datagrams {
+            None => {
+                self.tunnel(tun_receiver, tun_sender, packet_receiver, packet_sender)
+                    .await?
+            }
+            Some(datagrams) => {
+                // the TLS stream stays open as the control channel of the session
+                let keepalive = keep_datagrams_alive(
+                    packet_receiver,
+                    datagrams.sender.clone(),
+                    self.stop_receiver.clone(),
+                );
+                let tunnel = self.tunnel(
+                    tun_receiver,
+                    tun_sender,
+                    KeepaliveReceiver::new(datagrams.receiver, Some(DATAGRAM_IDLE_TIMEOUT)),
+                    TimeoutSender::new(datagrams.sender, self.write_timeout, self.close_linger),
+                );
+                let res = tokio::try_join!(tunnel, keepalive).map(|_| ());
+                if let Err(e) = packet_sender.close().await {
+                    debug!("could not close control connection: {e}");
+                }
+                res?
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_interface(&self, network_config: NetworkConfig) -> anyhow::Result<Interface> {
+        let tun_config = configure_tun(&network_config, self.interface_style);
         let device = tun::create_as_async(&tun_config)?;
         let mtu = device.mtu().unwrap() as usize;
+        let name = device.tun_name().unwrap_or_else(|_| "tun".into());
+        info!(
+            "interface {name} up with address {}/{}, MTU {mtu}",
+            network_config.client_ip,
+            network_config.netmask.to_bits().count_ones()
+        );
 
         let (tun_writer, tun_reader) = device.split()?;
-        let tun_receiver = TunReceiver::new(tun_reader, mtu);
-        let tun_sender: TunSender = tun_writer.into();
-        let (packet_sender, packet_receiver) = protocol_connection.into_parts();
+        Ok(Interface {
+            route: self.default_route.then(DefaultRoute::try_new).transpose()?,
+            dns: None,
+            config: network_config,
+            name,
+            receiver: TunReceiver::new(tun_reader, mtu),
+            sender: TimeoutSender::new(
+                TunSender::from(tun_writer),
+                self.write_timeout,
+                self.close_linger,
+            ),
+            undo: self.undo_script.clone().map(UndoScript::new),
+        })
+    }
+
+    async fn tunnel<R: PacketReceiver, S: PacketSender>(
+        &self,
+        tun_receiver: &mut TunReceiver,
+        tun_sender: &mut TimeoutSender<TunSender>,
+        packet_receiver: R,
+        packet_sender: S,
+    ) -> io::Result<()> {
+        let rx_counter = Arc::new(TrafficCounter::default());
+        let tx_counter = Arc::new(TrafficCounter::default());
+        let packet_receiver = MeteredReceiver::new(packet_receiver, rx_counter.clone());
+        let packet_sender = MeteredSender::new(packet_sender, tx_counter.clone());
 
         let send_fut = forward_packets(packet_receiver, tun_sender, self.stop_receiver.clone());
         let receive_fut = forward_packets(tun_receiver, packet_sender, self.stop_receiver.clone());
-        tokio::try_join!(send_fut, receive_fut)?;
-
+        let status_fut = report_traffic(rx_counter, tx_counter, self.stop_receiver.clone());
+        tokio::try_join!(send_fut, receive_fut, status_fut)?;
         Ok(())
     }
 }
 
-fn configure_tls(tls: TlsConfig) -> anyhow::Result<rustls::ClientConfig> {
-    Ok(rustls::ClientConfig::builder()
-        .with_root_certificates(get_root_cert_store(tls.root_certificate.clone())?)
-        .with_client_auth_cert(vec![tls.certificate, tls.root_certificate], tls.key)?)
+impl Interface {
+    // with routes and DNS still pointing into the dead tunnel, reconnecting would black-hole,
+    // resolving the server name included. Both are applied again after the next handshake
+    fn suspend(&mut self) {
+        if let Some(route) = &mut self.route {
+            if let Err(e) = route.reset() {
+                warn!("{e:#}");
+            }
+        }
+        self.dns = None;
+        if let Err(e) = self.write_undo_script() {
+            warn!("{e:#}");
+        }
+    }
+
+    fn write_undo_script(&self) -> anyhow::Result<()> {
+        let Some(undo) = &self.undo else {
+            return Ok(());
+        };
+        let mut commands = Vec::new();
+        if let Some(route) = &self.route {
+            commands.extend(route.undo_commands());
+        }
+        if let Some(dns) = &self.dns {
+            commands.extend(dns.undo_commands());
+        }
+        undo.write(&commands)
+    }
 }
 
-fn configure_tun(network_config: NetworkConfig) -> tun::Configuration {
+fn configure_tls(mut tls: TlsConfig) -> anyhow::Result<rustls::ClientConfig> {
+    let alpn = tls.alpn.take();
+    let mut config = configure_identities(tls)?;
+    config.alpn_protocols.extend(alpn);
+    Ok(config)
+}
+
+fn configure_identities(tls: TlsConfig) -> anyhow::Result<rustls::ClientConfig> {
+    if tls.extra_identities.is_empty() {
+        return Ok(rustls::ClientConfig::builder()
+            .with_root_certificates(get_root_cert_store(tls.root_certificate.clone())?)
+            .with_client_auth_cert(vec![tls.certificate, tls.root_certificate], tls.key)?);
+    }
+
+    let builder = rustls::ClientConfig::builder();
+    let primary = TlsIdentity {
+        root_certificate: tls.root_certificate,
+        certificate: tls.certificate,
+        key: tls.key,
+    };
+    let (identities, verifiers) = std::iter::once(primary)
+        .chain(tls.extra_identities)
+        .map(|identity| {
+            let verifier = WebPkiServerVerifier::builder(
+                get_root_cert_store(identity.root_certificate.clone())?.into(),
+            )
+            .build()?;
+            let identity = certified_identity(identity, builder.crypto_provider())?;
+            Ok((identity, verifier))
+        })
+        .collect::<anyhow::Result<(Vec<_>, Vec<_>)>>()?;
+    let selected = Arc::new(Mutex::new(None));
+    Ok(builder
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(IdentityVerifier {
+            verifiers,
+            selected: selected.clone(),
+        }))
+        .with_client_cert_resolver(Arc::new(IdentityResolver {
+            identities,
+            selected,
+        })))
+}
+
+fn certified_identity(
+    identity: TlsIdentity,
+    provider: &rustls::crypto::CryptoProvider,
+) -> anyhow::Result<(Vec<u8>, Arc<CertifiedKey>)> {
+    let issuer = X509Certificate::from_der(&identity.certificate)
+        .context("could not parse client certificate")?
+        .1
+        .issuer()
+        .as_raw()
+        .to_vec();
+    let key = CertifiedKey::from_der(
+        vec![identity.certificate, identity.root_certificate],
+        identity.key,
+        provider,
+    )?;
+    Ok((issuer, Arc::new(key)))
+}
+
+// the index of the identity offered in the current handshake, handshakes of one
+// client never overlap
+type SelectedIdentity = Arc<Mutex<Option<usize>>>;
+
+#[derive(Debug)]
+struct IdentityResolver {
+    identities: Vec<(Vec<u8>, Arc<CertifiedKey>)>,
+    selected: SelectedIdentity,
+}
+
+impl ResolvesClientCert for IdentityResolver {
+    fn resolve(
+        &self,
+        root_hint_subjects: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        // a server that asks for none of the CAs gets no certificate at all
+        let index = self.identities.iter().position(|(issuer, key)| {
+            root_hint_subjects.contains(&issuer.as_slice())
+                && key.key.choose_scheme(sigschemes).is_some()
+        });
+        *self.selected.lock().unwrap() = index;
+        Some(self.identities[index?].1.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+// rustls picks the client certificate before it verifies the server, so the server
+// only has to be trusted by the CA of the identity it asked for. Otherwise a server
+// of one PKI could pass as a server of another one
+#[derive(Debug)]
+struct IdentityVerifier {
+    verifiers: Vec<Arc<WebPkiServerVerifier>>,
+    selected: SelectedIdentity,
+}
+
+impl ServerCertVerifier for IdentityVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(index) = self.selected.lock().unwrap().take() else {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::UnknownIssuer,
+            ));
+        };
+        self.verifiers[index].verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifiers[0].verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifiers[0].verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifiers[0].supported_verify_schemes()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn path_mtu(socket: &TcpStream) -> Option<u16> {
+    use std::os::fd::AsRawFd;
+
+    let (level, option) = match socket.peer_addr().ok()? {
+        std::net::SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        std::net::SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: mtu and len point to live locals of the size getsockopt is told about
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            (&raw mut mtu).cast(),
+            &raw mut len,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+    u16::try_from(mtu).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_mtu(_socket: &TcpStream) -> Option<u16> {
+    None
+}
+
+fn classify(err: &anyhow::Error) -> ErrorClass {
+    for cause in err.chain() {
+        let io_error = cause.downcast_ref::<io::Error>();
+        // a disconnect reaches us wrapped in the I/O error of the packet stream
+        let protocol_error = cause.downcast_ref().or_else(|| {
+            io_error
+                .and_then(|e| e.get_ref())
+                .and_then(|e| e.downcast_ref())
+        });
+        match protocol_error {
+            // retrying does not help a client the server does not speak with
+            Some(
+                ProtocolError::UnsupportedVersion(_)
+                | ProtocolError::OutdatedServer
+                | ProtocolError::Rejected(Rejection::ProtocolMismatch),
+            ) => return ErrorClass::Version,
+            // maintenance ends on its own, limits free up and an expired session wants a fresh
+            // one, the server is retried like after a close
+            Some(ProtocolError::Rejected(_) | ProtocolError::Disconnected(_)) => {
+                return ErrorClass::Closed
+            }
+            None => {}
+        }
+        // rustls errors reach us wrapped in the I/O error of the TLS stream
+        let tls_error = cause.downcast_ref::<rustls::Error>().or_else(|| {
+            io_error
+                .and_then(|e| e.get_ref())
+                .and_then(|e| e.downcast_ref())
+        });
+        if tls_error.is_some_and(is_auth_failure) {
+            return ErrorClass::Auth;
+        }
+        match io_error.map(io::Error::kind) {
+            Some(io::ErrorKind::TimedOut) => return ErrorClass::Timeout,
+            Some(io::ErrorKind::UnexpectedEof) => return ErrorClass::Closed,
+            _ => {}
+        }
+    }
+    ErrorClass::Network
+}
+
+fn is_auth_failure(err: &rustls::Error) -> bool {
+    match err {
+        rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented => true,
+        rustls::Error::AlertReceived(alert) => matches!(
+            alert,
+            AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::CertificateRequired
+                | AlertDescription::UnknownCA
+                | AlertDescription::AccessDenied
+        ),
+        _ => false,
+    }
+}
+
+fn backoff_delay(reconnect: &ReconnectConfig, attempt: u32) -> Duration {
+    reconnect
+        .backoff
+        .saturating_mul(1 << attempt.min(16))
+        .min(reconnect.max_backoff)
+}
+
+// jitter keeps a fleet of clients that lost the same server from reconnecting in waves
+struct Backoff {
+    rng: SplitMix64,
+    previous: Duration,
+}
+
+impl Backoff {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: SplitMix64::new(seed),
+            previous: Duration::ZERO,
+        }
+    }
+
+    fn delay(&mut self, reconnect: &ReconnectConfig, attempt: u32) -> Duration {
+        if attempt == 0 {
+            self.previous = reconnect.backoff;
+        }
+        let delay = match reconnect.jitter {
+            Jitter::None => backoff_delay(reconnect, attempt),
+            Jitter::Full => backoff_delay(reconnect, attempt).mul_f64(self.rng.next_f64()),
+            Jitter::Decorrelated => {
+                let spread = self.previous.saturating_mul(3) - reconnect.backoff;
+                (reconnect.backoff + spread.mul_f64(self.rng.next_f64())).min(reconnect.max_backoff)
+            }
+        };
+        self.previous = delay;
+        delay
+    }
+}
+
+// failed attempts are counted since the last successful handshake
+struct Attempts {
+    count: u32,
+    backoff: Backoff,
+}
+
+impl Attempts {
+    fn new(seed: u64) -> Self {
+        Self {
+            count: 0,
+            backoff: Backoff::new(seed),
+        }
+    }
+
+    // tells whether this handshake ended a run of failed attempts
+    fn connected(&mut self) -> bool {
+        std::mem::take(&mut self.count) > 0
+    }
+
+    // the delay before the next attempt, or the error to give up with
+    fn retry(
+        &mut self,
+        reconnect: &ReconnectConfig,
+        res: anyhow::Result<()>,
+    ) -> anyhow::Result<Duration> {
+        let (err, class) = match res {
+            Ok(()) => (anyhow!("server closed the connection"), ErrorClass::Closed),
+            Err(err) => {
+                let class = classify(&err);
+                (err, class)
+            }
+        };
+        if !reconnect.reconnect_on.contains(&class) {
+            return Err(err);
+        }
+        let attempt = self.count;
+        if reconnect.max_attempts.is_some_and(|max| attempt >= max) {
+            return Err(err.context(format!("giving up after {attempt} reconnect attempts")));
+        }
+        let delay = self.backoff.delay(reconnect, attempt);
+        self.count += 1;
+        warn!(
+            "{err:#}; reconnecting in {delay:?} (attempt {})",
+            self.count
+        );
+        Ok(delay)
+    }
+}
+
+fn configure_tun(network_config: &NetworkConfig, style: InterfaceStyle) -> tun::Configuration {
     let mut config = tun::configure();
     config
         .address(network_config.client_ip)
-        .destination(network_config.server_ip)
         .netmask(network_config.netmask)
         .mtu(network_config.mtu)
         .up();
+    // a point-to-point peer narrows the connected route to the server alone,
+    // leaving other clients in the subnet unreachable
+    if let InterfaceStyle::PointToPoint = style {
+        config.destination(network_config.server_ip);
+    }
     config
 }
 
+async fn open_datagrams(
+    keys: DatagramKeys,
+    server_address: SocketAddr,
+    mtu: u16,
+) -> anyhow::Result<DatagramChannel> {
+    let local_address: SocketAddr = if server_address.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local_address)
+        .await
+        .context("could not bind UDP socket")?;
+    let endpoint = UdpEndpoint::new(socket);
+    let (sender, receiver) = endpoint.open(keys, Some(server_address), mtu.into());
+    Ok(DatagramChannel {
+        _endpoint: endpoint,
+        sender,
+        receiver,
+    })
+}
+
+// the first tick fires at once, which tells the server where to send datagrams.
+// Moving to another network breaks the control connection but not the datagram
+// session, which goes on until datagrams stop arriving as well
+async fn keep_datagrams_alive<R: PacketReceiver>(
+    mut control: R,
+    mut sender: UdpPacketSender,
+    mut stop_token: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let mut interval = time::interval(DATAGRAM_KEEPALIVE);
+    let mut control_open = true;
+    while !*stop_token.borrow_and_update() {
+        tokio::select! {
+            res = stop_token.changed() => {
+                if res.is_err() {
+                    break;
+                }
+            }
+            res = control.receive(), if control_open => match res {
+                Ok(frame) => disconnected(&frame)?,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(e),
+                Err(e) => {
+                    info!("control connection lost, continuing over datagrams: {e}");
+                    control_open = false;
+                }
+            },
+            _ = interval.tick() => sender.send(&[]).await?,
+        }
+    }
+    Ok(())
+}
+
 async fn forward_packets<R: PacketReceiver, S: PacketSender>(
     mut receiver: R,
     mut sender: S,
@@ -102,9 +885,714 @@ async fn forward_packets<R: PacketReceiver, S: PacketSender>(
             }
             packet_res = packet_fut => {
                 let packet = packet_res?;
+                if is_heartbeat(&packet) {
+                    continue;
+                }
+                disconnected(&packet)?;
                 sender.send(&packet).await?;
             }
         }
     }
     sender.close().await
 }
+
+// only the server sends these, a TUN packet always starts with its IP version
+fn disconnected(frame: &[u8]) -> io::Result<()> {
+    match as_rejection(frame) {
+        Some(rejection) => Err(io::Error::other(ProtocolError::Disconnected(rejection))),
+        None => Ok(()),
+    }
+}
+
+async fn report_traffic(
+    rx_counter: Arc<TrafficCounter>,
+    tx_counter: Arc<TrafficCounter>,
+    mut stop_token: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let mut interval = time::interval(STATUS_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    let mut last_rx = rx_counter.snapshot();
+    let mut last_tx = tx_counter.snapshot();
+    let mut last_time = Instant::now();
+    while !*stop_token.borrow_and_update() {
+        tokio::select! {
+            res = stop_token.changed() => {
+                if res.is_err() {
+                    break;
+                }
+            }
+            _ = interval.tick() => {
+                let (rx, tx) = (rx_counter.snapshot(), tx_counter.snapshot());
+                let elapsed = last_time.elapsed();
+                info!(
+                    "rx: {}; tx: {}",
+                    format_traffic(rx, last_rx, elapsed),
+                    format_traffic(tx, last_tx, elapsed)
+                );
+                (last_rx, last_tx, last_time) = (rx, tx, Instant::now());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn format_traffic(
+    current: TrafficSnapshot,
+    previous: TrafficSnapshot,
+    elapsed: Duration,
+) -> String {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let packet_rate = (current.packets - previous.packets) as f64 / seconds;
+    let byte_rate = (current.bytes - previous.bytes) as f64 / seconds;
+    format!(
+        "{} packets, {} bytes ({packet_rate:.1} packets/s, {byte_rate:.1} B/s)",
+        current.packets, current.bytes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{pem::PemObject, PrivateKeyDer},
+            server::WebPkiClientVerifier,
+        },
+        TlsAcceptor,
+    };
+
+    use super::*;
+    use crate::{
+        common::ClientIdentity, config::ProxyProtocol, packet_stream::mock,
+        protocol::rejection_frame,
+    };
+
+    fn reconnects(err: &anyhow::Error) -> bool {
+        // the classes read_reconnect retries when reconnect_on is not set
+        [ErrorClass::Network, ErrorClass::Timeout, ErrorClass::Closed].contains(&classify(err))
+    }
+
+    fn tls_failure(err: rustls::Error) -> anyhow::Error {
+        anyhow::Error::from(io::Error::new(io::ErrorKind::InvalidData, err))
+            .context("could not receive network config")
+    }
+
+    #[test]
+    fn rejected_certificate_stops_reconnecting() {
+        let err = tls_failure(rustls::Error::AlertReceived(
+            AlertDescription::CertificateRevoked,
+        ));
+        assert_eq!(classify(&err), ErrorClass::Auth);
+        assert!(!reconnects(&err));
+    }
+
+    #[test]
+    fn untrusted_server_stops_reconnecting() {
+        let err = tls_failure(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::UnknownIssuer,
+        ))
+        .context("TLS handshake failed");
+        assert_eq!(classify(&err), ErrorClass::Auth);
+    }
+
+    #[test]
+    fn connection_reset_reconnects() {
+        let err = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionReset))
+            .context("could not send protocol version");
+        assert_eq!(classify(&err), ErrorClass::Network);
+        assert!(reconnects(&err));
+    }
+
+    #[test]
+    fn other_tls_failures_reconnect() {
+        let err = tls_failure(rustls::Error::AlertReceived(
+            AlertDescription::InternalError,
+        ));
+        assert_eq!(classify(&err), ErrorClass::Network);
+    }
+
+    #[test]
+    fn timeouts_and_closes_are_classified() {
+        let timeout = anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(classify(&timeout), ErrorClass::Timeout);
+        let closed = anyhow::Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert_eq!(classify(&closed), ErrorClass::Closed);
+    }
+
+    #[test]
+    fn version_mismatch_stops_reconnecting() {
+        let err = anyhow::Error::from(ProtocolError::UnsupportedVersion(2))
+            .context("could not receive network config");
+        assert_eq!(classify(&err), ErrorClass::Version);
+        assert!(!reconnects(&err));
+    }
+
+    #[tokio::test]
+    async fn expired_session_is_reconnected() {
+        let (mut server, receiver) = mock::channel();
+        let (tun, mut tun_packets) = mock::channel();
+        let packet = [0x45; 20];
+        server.send(&packet).await.unwrap();
+        server
+            .send(&rejection_frame(Rejection::SessionExpired))
+            .await
+            .unwrap();
+        let (_stop_sender, stop_receiver) = watch::channel(false);
+        let err = forward_packets(receiver, tun, stop_receiver)
+            .await
+            .unwrap_err();
+        assert_eq!(tun_packets.try_receive().as_deref(), Some(&packet[..]));
+        assert_eq!(tun_packets.try_receive(), None);
+        let err = anyhow::Error::from(err).context("connection terminated");
+        assert_eq!(classify(&err), ErrorClass::Closed);
+        assert_eq!(
+            format!("{err:#}"),
+            "connection terminated: server ended the session: session reached its maximum lifetime"
+        );
+    }
+
+    #[test]
+    fn outdated_server_stops_reconnecting() {
+        let err = anyhow::Error::from(ProtocolError::OutdatedServer)
+            .context("could not receive network config");
+        assert_eq!(classify(&err), ErrorClass::Version);
+    }
+
+    #[test]
+    fn maintenance_rejection_is_retried() {
+        let err = anyhow::Error::from(ProtocolError::Rejected(Rejection::Maintenance))
+            .context("could not receive network config");
+        assert_eq!(classify(&err), ErrorClass::Closed);
+        assert!(format!("{err:#}").contains("server is in maintenance mode"));
+    }
+
+    #[test]
+    fn protocol_mismatch_stops_reconnecting() {
+        let err = anyhow::Error::from(ProtocolError::Rejected(Rejection::ProtocolMismatch))
+            .context("could not receive network config");
+        assert_eq!(classify(&err), ErrorClass::Version);
+    }
+
+    #[test]
+    fn exhausted_limits_are_retried() {
+        for rejection in [
+            Rejection::IdentityLimit,
+            Rejection::ServerFull,
+            Rejection::NoFreeAddress,
+            Rejection::Throttled,
+        ] {
+            let err = anyhow::Error::from(ProtocolError::Rejected(rejection))
+                .context("could not receive network config");
+            assert_eq!(classify(&err), ErrorClass::Closed);
+        }
+    }
+
+    #[tokio::test]
+    async fn metered_forwarding_counts_every_packet() {
+        let (mut input, receiver) = mock::channel();
+        let (sender, mut output) = mock::channel();
+        let rx_counter = Arc::new(TrafficCounter::default());
+        let tx_counter = Arc::new(TrafficCounter::default());
+        for size in 1..=10 {
+            input.send(&vec![0; size * 100]).await.unwrap();
+        }
+        input.close().await.unwrap();
+
+        let (_stop_sender, stop_receiver) = watch::channel(false);
+        let res = forward_packets(
+            MeteredReceiver::new(receiver, rx_counter.clone()),
+            MeteredSender::new(sender, tx_counter.clone()),
+            stop_receiver,
+        )
+        .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        for counter in [rx_counter, tx_counter] {
+            let snapshot = counter.snapshot();
+            assert_eq!(snapshot.packets, 10);
+            assert_eq!(snapshot.bytes, 5500);
+        }
+        assert_eq!(std::iter::from_fn(|| output.try_receive()).count(), 10);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_are_not_forwarded() {
+        let (mut input, receiver) = mock::channel();
+        let (sender, mut output) = mock::channel();
+        for packet in [&[0x45, 0][..], &[], &[0x45, 1]] {
+            input.send(packet).await.unwrap();
+        }
+        input.close().await.unwrap();
+
+        let (_stop_sender, stop_receiver) = watch::channel(false);
+        _ = forward_packets(receiver, sender, stop_receiver).await;
+        let forwarded: Vec<_> = std::iter::from_fn(|| output.try_receive()).collect();
+        assert_eq!(forwarded, [[0x45, 0].into(), [0x45, 1].into()]);
+    }
+
+    #[test]
+    fn traffic_rate_covers_the_interval() {
+        let previous = TrafficSnapshot {
+            packets: 10,
+            bytes: 1000,
+        };
+        let current = TrafficSnapshot {
+            packets: 30,
+            bytes: 5000,
+        };
+        assert_eq!(
+            format_traffic(current, previous, Duration::from_secs(4)),
+            "30 packets, 5000 bytes (5.0 packets/s, 1000.0 B/s)"
+        );
+    }
+
+    fn reconnect_config(jitter: Jitter) -> ReconnectConfig {
+        ReconnectConfig {
+            max_attempts: None,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            reconnect_on: Vec::new(),
+            jitter,
+        }
+    }
+
+    fn delays(jitter: Jitter, seed: u64) -> Vec<Duration> {
+        let reconnect = reconnect_config(jitter);
+        let mut backoff = Backoff::new(seed);
+        (0..8)
+            .map(|attempt| backoff.delay(&reconnect, attempt))
+            .collect()
+    }
+
+    #[test]
+    fn backoff_without_jitter_doubles() {
+        assert_eq!(
+            delays(Jitter::None, 1),
+            [1, 2, 4, 8, 16, 32, 60, 60].map(Duration::from_secs)
+        );
+    }
+
+    #[test]
+    fn full_jitter_stays_below_the_backoff() {
+        let unjittered = delays(Jitter::None, 1);
+        for seed in 0..16 {
+            let jittered = delays(Jitter::Full, seed);
+            assert!(jittered
+                .iter()
+                .zip(&unjittered)
+                .all(|(delay, max)| delay < max));
+            assert_ne!(jittered, delays(Jitter::Full, seed + 16));
+        }
+    }
+
+    #[test]
+    fn decorrelated_jitter_grows_from_the_previous_delay() {
+        let reconnect = reconnect_config(Jitter::Decorrelated);
+        for seed in 0..16 {
+            let jittered = delays(Jitter::Decorrelated, seed);
+            let mut previous = reconnect.backoff;
+            for &delay in &jittered {
+                assert!(delay >= reconnect.backoff);
+                assert!(delay <= (previous * 3).min(reconnect.max_backoff));
+                previous = delay;
+            }
+            assert!(jittered.windows(2).any(|pair| pair[0] != pair[1]));
+        }
+    }
+
+    fn network_failure() -> anyhow::Result<()> {
+        Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+    }
+
+    fn limited_reconnect(max_attempts: Option<u32>) -> ReconnectConfig {
+        ReconnectConfig {
+            max_attempts,
+            reconnect_on: vec![ErrorClass::Network, ErrorClass::Closed],
+            ..reconnect_config(Jitter::None)
+        }
+    }
+
+    #[test]
+    fn reconnecting_gives_up_after_the_maximum_attempts() {
+        let reconnect = limited_reconnect(Some(3));
+        let mut attempts = Attempts::new(1);
+        for _ in 0..3 {
+            assert!(attempts.retry(&reconnect, network_failure()).is_ok());
+        }
+        let err = attempts.retry(&reconnect, network_failure()).unwrap_err();
+        assert_eq!(err.to_string(), "giving up after 3 reconnect attempts");
+    }
+
+    #[test]
+    fn successful_handshake_resets_the_attempts() {
+        let reconnect = limited_reconnect(Some(2));
+        let mut attempts = Attempts::new(1);
+        assert!(!attempts.connected());
+        for _ in 0..2 {
+            assert!(attempts.retry(&reconnect, network_failure()).is_ok());
+        }
+        assert!(attempts.connected());
+        assert_eq!(
+            attempts.retry(&reconnect, network_failure()).unwrap(),
+            reconnect.backoff
+        );
+        assert!(attempts.retry(&reconnect, network_failure()).is_ok());
+        assert!(attempts.retry(&reconnect, network_failure()).is_err());
+    }
+
+    #[test]
+    fn unlimited_attempts_keep_reconnecting() {
+        let reconnect = limited_reconnect(None);
+        let mut attempts = Attempts::new(1);
+        assert!((0..100).all(|_| attempts.retry(&reconnect, network_failure()).is_ok()));
+    }
+
+    fn certificate(pem: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem.as_bytes()).unwrap()
+    }
+
+    fn key(pem: &str) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::from_pem_slice(pem.as_bytes()).unwrap()
+    }
+
+    fn alice() -> TlsIdentity {
+        TlsIdentity {
+            root_certificate: certificate(include_str!("../testdata/ca-a.pem")),
+            certificate: certificate(include_str!("../testdata/alice.pem")),
+            key: key(include_str!("../testdata/alice.key")),
+        }
+    }
+
+    fn bob() -> TlsIdentity {
+        TlsIdentity {
+            root_certificate: certificate(include_str!("../testdata/ca-b.pem")),
+            certificate: certificate(include_str!("../testdata/bob.pem")),
+            key: key(include_str!("../testdata/bob.key")),
+        }
+    }
+
+    fn alice_and_bob() -> Arc<rustls::ClientConfig> {
+        let alice = alice();
+        let config = configure_identities(TlsConfig {
+            root_certificate: alice.root_certificate,
+            certificate: alice.certificate,
+            key: alice.key,
+            extra_identities: vec![bob()],
+            alpn: None,
+        });
+        Arc::new(config.unwrap())
+    }
+
+    // the common name of the certificate the server received, if the handshake succeeded
+    async fn client_seen_by(
+        client_ca: &str,
+        server_certificate: &str,
+        server_key: &str,
+    ) -> Option<String> {
+        let verifier = WebPkiClientVerifier::builder(
+            get_root_cert_store(certificate(client_ca)).unwrap().into(),
+        )
+        .build()
+        .unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![certificate(server_certificate)], key(server_key))
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let accept = async {
+            let (socket, _) = listener.accept().await.ok()?;
+            let stream = TlsAcceptor::from(Arc::new(server_config))
+                .accept(socket)
+                .await
+                .ok()?;
+            let certificates = stream.get_ref().1.peer_certificates()?;
+            Some(
+                ClientIdentity::from_certificate(&certificates[0])
+                    .unwrap()
+                    .common_name,
+            )
+        };
+        let connect = async {
+            let socket = TcpStream::connect(address).await.unwrap();
+            TlsConnector::from(alice_and_bob())
+                .connect(address.ip().into(), socket)
+                .await
+        };
+        let (accepted, connected) = tokio::join!(accept, connect);
+        connected.ok().and(accepted)
+    }
+
+    #[tokio::test]
+    async fn each_server_gets_the_identity_of_its_ca() {
+        assert_eq!(
+            client_seen_by(
+                include_str!("../testdata/ca-a.pem"),
+                include_str!("../testdata/server-a.pem"),
+                include_str!("../testdata/server-a.key"),
+            )
+            .await
+            .as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            client_seen_by(
+                include_str!("../testdata/ca-b.pem"),
+                include_str!("../testdata/server-b.pem"),
+                include_str!("../testdata/server-b.key"),
+            )
+            .await
+            .as_deref(),
+            Some("bob")
+        );
+    }
+
+    #[tokio::test]
+    async fn server_must_be_trusted_by_the_ca_it_asks_for() {
+        // a server of CA B asking for identities of CA A
+        assert_eq!(
+            client_seen_by(
+                include_str!("../testdata/ca-a.pem"),
+                include_str!("../testdata/server-b.pem"),
+                include_str!("../testdata/server-b.key"),
+            )
+            .await,
+            None
+        );
+    }
+
+    fn client_config(port: u16) -> ClientConfig {
+        ClientConfig {
+            host: "127.0.0.1".into(),
+            port,
+            resolver: None,
+            server_name: None,
+            write_timeout: None,
+            close_linger: Duration::from_secs(1),
+            keepalive: None,
+            interface_style: InterfaceStyle::Subnet,
+            cover_request: None,
+            buffers: BufferSizes {
+                read: 4096,
+                write: 4096,
+            },
+            transport: Transport::Tcp,
+            reconnect: None,
+            default_route: false,
+            apply_dns: false,
+            undo_script: None,
+            advertised_routes: Vec::new(),
+            proxy: None,
+        }
+    }
+
+    // its certificate only names 127.0.0.1
+    fn server_a() -> TlsAcceptor {
+        let verifier = WebPkiClientVerifier::builder(
+            get_root_cert_store(certificate(include_str!("../testdata/ca-a.pem")))
+                .unwrap()
+                .into(),
+        )
+        .build()
+        .unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![certificate(include_str!("../testdata/server-a.pem"))],
+                key(include_str!("../testdata/server-a.key")),
+            )
+            .unwrap();
+        TlsAcceptor::from(Arc::new(server_config))
+    }
+
+    fn alice_tls() -> TlsConfig {
+        let alice = alice();
+        TlsConfig {
+            root_certificate: alice.root_certificate,
+            certificate: alice.certificate,
+            key: alice.key,
+            extra_identities: Vec::new(),
+            alpn: None,
+        }
+    }
+
+    // drops the first connection before the TLS handshake and serves the second one
+    async fn flaky_server(listener: tokio::net::TcpListener, config: NetworkConfig) {
+        drop(listener.accept().await.unwrap());
+        let (socket, _) = listener.accept().await.unwrap();
+        let stream = server_a().accept(socket).await.unwrap();
+        let (reader, writer) = tokio::io::split(stream);
+        let mut connection = Connection::new(reader.compat(), writer.compat_write());
+        connection.receive_version().await.unwrap();
+        connection.receive_path_mtu().await.unwrap();
+        connection.receive_transport().await.unwrap();
+        connection.receive_routes().await.unwrap();
+        connection.receive_keepalive().await.unwrap();
+        connection.send_config(&config).await.unwrap();
+        connection.send_keepalive(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_recovers_when_the_first_connection_drops() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let network_config = NetworkConfig {
+            client_ip: Ipv4Addr::new(10, 0, 0, 2),
+            server_ip: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            mtu: 1400,
+            dns: Vec::new(),
+            search_domain: None,
+        };
+        let server = tokio::spawn(flaky_server(listener, network_config.clone()));
+        let client = Client::try_new(client_config(port), alice_tls()).unwrap();
+        let reconnect = limited_reconnect(Some(1));
+        let mut attempts = Attempts::new(1);
+
+        let first = client.handshake().await.map(drop);
+        assert!(first.is_err());
+        assert!(attempts.retry(&reconnect, first).is_ok());
+        let (_connection, received, _, _, _) = client.handshake().await.unwrap();
+        assert_eq!(received, network_config);
+        assert!(attempts.connected());
+        server.await.unwrap();
+    }
+
+    // a SOCKS5 proxy without authentication that dials the requested host itself
+    async fn socks5_proxy(listener: tokio::net::TcpListener) {
+        let (mut client, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).await.unwrap();
+        client.write_all(&[5, 0]).await.unwrap();
+        let mut request = [0u8; 5];
+        client.read_exact(&mut request).await.unwrap();
+        let host = match request[3] {
+            1 => {
+                let mut rest = [0u8; 3];
+                client.read_exact(&mut rest).await.unwrap();
+                Ipv4Addr::new(request[4], rest[0], rest[1], rest[2]).to_string()
+            }
+            _ => {
+                let mut host = vec![0u8; request[4].into()];
+                client.read_exact(&mut host).await.unwrap();
+                String::from_utf8(host).unwrap()
+            }
+        };
+        let mut port = [0u8; 2];
+        client.read_exact(&mut port).await.unwrap();
+        let mut server = TcpStream::connect((host, u16::from_be_bytes(port)))
+            .await
+            .unwrap();
+        client
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+    }
+
+    // whether the server certificate passed, directly and through a proxy
+    async fn certificate_passes(
+        host: &str,
+        server_name: Option<&str>,
+        proxied: bool,
+    ) -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = client_config(listener.local_addr().unwrap().port());
+        config.host = host.into();
+        config.server_name = server_name.map(|name| ServerName::try_from(name).unwrap().to_owned());
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            // the handshake only fails on the client, which rejects the certificate
+            _ = server_a().accept(socket).await;
+        });
+        let proxy = if proxied {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            config.proxy = Some(ProxyConfig {
+                protocol: ProxyProtocol::Socks5,
+                host: "127.0.0.1".into(),
+                port: listener.local_addr().unwrap().port(),
+                credentials: None,
+            });
+            Some(tokio::spawn(socks5_proxy(listener)))
+        } else {
+            None
+        };
+        let res = Client::try_new(config, alice_tls())
+            .unwrap()
+            .connect()
+            .await
+            .map(drop);
+        server.abort();
+        if let Some(proxy) = proxy {
+            proxy.abort();
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn certificate_is_checked_the_same_way_with_and_without_a_proxy() {
+        for proxied in [false, true] {
+            // without a server name the IP is checked
+            assert!(certificate_passes("127.0.0.1", None, proxied).await.is_ok());
+            // a configured name is checked even when the server is dialed by IP
+            let err = certificate_passes("127.0.0.1", Some("vpn.example.com"), proxied)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "TLS handshake failed");
+            // and a host name passes wherever the server name matches the certificate
+            assert!(certificate_passes("localhost", Some("127.0.0.1"), proxied)
+                .await
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn unknown_ca_gets_no_certificate() {
+        let provider = rustls::ClientConfig::builder().crypto_provider().clone();
+        let resolver = IdentityResolver {
+            identities: vec![certified_identity(alice(), &provider).unwrap()],
+            selected: Arc::default(),
+        };
+        let ca_b = certificate(include_str!("../testdata/ca-b.pem"));
+        let (_, ca_b) = X509Certificate::from_der(&ca_b).unwrap();
+        let ca_b_subject = ca_b.subject().as_raw();
+        let schemes = [SignatureScheme::ECDSA_NISTP256_SHA256];
+        assert!(resolver.resolve(&[ca_b_subject], &schemes).is_none());
+        assert_eq!(*resolver.selected.lock().unwrap(), None);
+    }
+
+    fn tun_config(style: InterfaceStyle) -> String {
+        let network_config = NetworkConfig {
+            client_ip: Ipv4Addr::new(10, 0, 0, 2),
+            server_ip: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            mtu: 1400,
+            dns: Vec::new(),
+            search_domain: None,
+        };
+        format!("{:?}", configure_tun(&network_config, style))
+    }
+
+    #[test]
+    fn subnet_interface_has_no_peer() {
+        let config = tun_config(InterfaceStyle::Subnet);
+        assert!(config.contains("netmask: Some(255.255.255.0)"));
+        assert!(config.contains("destination: None"));
+    }
+
+    #[test]
+    fn point_to_point_interface_peers_with_the_server() {
+        let config = tun_config(InterfaceStyle::PointToPoint);
+        assert!(config.contains("netmask: Some(255.255.255.0)"));
+        assert!(config.contains("destination: Some(10.0.0.1)"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_defaults_to_a_subnet_interface() {
+        assert_eq!(InterfaceStyle::default(), InterfaceStyle::Subnet);
+    }
+}
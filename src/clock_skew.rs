@@ -0,0 +1,184 @@
+use std::{sync::Arc, time::Duration};
+
+use log::warn;
+use tokio_rustls::rustls::{
+    client::danger::HandshakeSignatureValid,
+    pki_types::{CertificateDer, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    CertificateError, DigitallySignedStruct, DistinguishedName, Error, SignatureScheme,
+};
+
+#[derive(Debug)]
+pub struct SkewTolerantVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    tolerance: Duration,
+}
+
+impl SkewTolerantVerifier {
+    pub fn new(inner: Arc<dyn ClientCertVerifier>, tolerance: Duration) -> Self {
+        Self { inner, tolerance }
+    }
+}
+
+impl ClientCertVerifier for SkewTolerantVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        let err = match self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)
+        {
+            Err(Error::InvalidCertificate(err)) => err,
+            res => return res,
+        };
+        // re-check at the edge of the tolerance window, the chain and signatures are validated again
+        let (shifted, problem) = match err {
+            CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => (
+                now.as_secs().saturating_add(self.tolerance.as_secs()),
+                "not valid yet",
+            ),
+            CertificateError::Expired | CertificateError::ExpiredContext { .. } => (
+                now.as_secs().saturating_sub(self.tolerance.as_secs()),
+                "expired",
+            ),
+            err => return Err(Error::InvalidCertificate(err)),
+        };
+        let shifted = UnixTime::since_unix_epoch(Duration::from_secs(shifted));
+        match self
+            .inner
+            .verify_client_cert(end_entity, intermediates, shifted)
+        {
+            Ok(verified) => {
+                warn!(
+                    "accepting client certificate that is {problem} within the {}s clock skew \
+                     tolerance, check the clocks of both peers",
+                    self.tolerance.as_secs()
+                );
+                Ok(verified)
+            }
+            Err(_) => Err(Error::InvalidCertificate(err)),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+
+    fn requires_raw_public_keys(&self) -> bool {
+        self.inner.requires_raw_public_keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::{pki_types::pem::PemObject, server::WebPkiClientVerifier};
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    use super::*;
+    use crate::common::get_root_cert_store;
+
+    const TOLERANCE: Duration = Duration::from_secs(300);
+
+    fn certificate(pem: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem.as_bytes()).unwrap()
+    }
+
+    fn verifier() -> SkewTolerantVerifier {
+        let roots = get_root_cert_store(certificate(include_str!("../testdata/ca-a.pem")));
+        let inner = WebPkiClientVerifier::builder(roots.unwrap().into())
+            .build()
+            .unwrap();
+        SkewTolerantVerifier::new(inner, TOLERANCE)
+    }
+
+    // alice's validity period, the CA's covers the same span
+    fn validity() -> (u64, u64) {
+        let alice = certificate(include_str!("../testdata/alice.pem"));
+        let (_, alice) = X509Certificate::from_der(&alice).unwrap();
+        let validity = alice.validity();
+        (
+            validity.not_before.timestamp() as u64,
+            validity.not_after.timestamp() as u64,
+        )
+    }
+
+    fn verify_at(secs: u64) -> Result<ClientCertVerified, Error> {
+        let alice = certificate(include_str!("../testdata/alice.pem"));
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(secs));
+        verifier().verify_client_cert(&alice, &[], now)
+    }
+
+    #[test]
+    fn barely_not_yet_valid_certificate_is_accepted() {
+        let (not_before, _) = validity();
+        assert!(verify_at(not_before - 60).is_ok());
+        assert!(verify_at(not_before - TOLERANCE.as_secs()).is_ok());
+    }
+
+    #[test]
+    fn certificate_valid_well_after_the_tolerance_is_rejected() {
+        let (not_before, _) = validity();
+        let err = verify_at(not_before - TOLERANCE.as_secs() - 3600).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidCertificate(
+                CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. }
+            )
+        ));
+    }
+
+    #[test]
+    fn just_expired_certificate_is_accepted_within_the_tolerance() {
+        let (_, not_after) = validity();
+        assert!(verify_at(not_after + 60).is_ok());
+        let err = verify_at(not_after + TOLERANCE.as_secs() + 3600).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidCertificate(
+                CertificateError::Expired | CertificateError::ExpiredContext { .. }
+            )
+        ));
+    }
+
+    #[test]
+    fn other_certificate_errors_are_not_tolerated() {
+        let (not_before, _) = validity();
+        // bob's certificate is issued by a CA this verifier does not trust
+        let bob = certificate(include_str!("../testdata/bob.pem"));
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(not_before + 60));
+        assert!(verifier().verify_client_cert(&bob, &[], now).is_err());
+    }
+}
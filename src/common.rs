@@ -1,7 +1,137 @@
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use aws_lc_rs::digest::{digest, SHA256};
+use data_encoding::HEXLOWER;
 use tokio_rustls::rustls::{pki_types::CertificateDer, RootCertStore};
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 pub fn get_root_cert_store(root_cert: CertificateDer<'static>) -> anyhow::Result<RootCertStore> {
     let mut store = RootCertStore::empty();
     store.add(root_cert)?;
     Ok(store)
 }
+
+pub struct ClientIdentity {
+    pub common_name: String,
+    pub key_hash: u64,
+}
+
+impl ClientIdentity {
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> anyhow::Result<Self> {
+        let (_, parsed) = X509Certificate::from_der(cert).context("could not parse certificate")?;
+        // certificates without a common name are told apart by their fingerprint
+        let common_name = match parsed.subject().iter_common_name().next() {
+            Some(common_name) => common_name
+                .as_str()
+                .context("certificate common name is not a string")?
+                .to_owned(),
+            None => format!("sha256:{}", HEXLOWER.encode(digest(&SHA256, cert).as_ref())),
+        };
+        Ok(Self {
+            common_name,
+            key_hash: fnv1a(parsed.public_key().raw),
+        })
+    }
+}
+
+pub fn random_u64() -> u64 {
+    // RandomState is seeded from the OS once per thread and rekeyed for every instance
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+// a small seedable generator, for randomness that does not have to be unpredictable
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// appended rather than swapped in, so that no other file name maps to the same temporary file
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    name.into()
+}
+
+// stable across builds and platforms, unlike std's DefaultHasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+    use super::*;
+
+    fn identity(pem: &str) -> ClientIdentity {
+        ClientIdentity::from_certificate(&CertificateDer::from_pem_slice(pem.as_bytes()).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn identity_is_named_after_the_common_name() {
+        let alice = identity(include_str!("../testdata/alice.pem"));
+        assert_eq!(alice.common_name, "alice");
+    }
+
+    #[test]
+    fn identity_without_common_name_uses_the_fingerprint() {
+        let anonymous = identity(include_str!("../testdata/anonymous.pem"));
+        assert_eq!(
+            anonymous.common_name,
+            "sha256:2cc07a8b7a93d48953ee77f7a58ac9ea01bd81d2fb63cc58e6bc076726eaafc8"
+        );
+    }
+
+    #[test]
+    fn key_hash_differs_between_keys() {
+        let alice = identity(include_str!("../testdata/alice.pem"));
+        let bob = identity(include_str!("../testdata/bob.pem"));
+        assert_ne!(alice.key_hash, bob.key_hash);
+    }
+
+    #[test]
+    fn temporary_file_keeps_the_full_name() {
+        assert_eq!(
+            temp_path(Path::new("/run/leases.tmp")),
+            Path::new("/run/leases.tmp.tmp")
+        );
+        assert_eq!(
+            temp_path(Path::new("/run/leases.json")),
+            Path::new("/run/leases.json.tmp")
+        );
+    }
+}
@@ -1,120 +1,1439 @@
 use std::{
+    collections::HashMap,
     fs::File,
     io::Read,
-    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
-    path::Path,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{bail, ensure, Context};
-use serde::Deserialize;
-use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use flate2::read::GzDecoder;
+use serde::{de, Deserialize, Deserializer};
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+
+use crate::{cover, sealed::unseal};
 
 pub struct ClientConfig {
-    pub address: SocketAddr,
+    pub host: String,
+    pub port: u16,
+    pub resolver: Option<IpAddr>,
+    // the name the server certificate is checked against instead of the server's IP
+    pub server_name: Option<ServerName<'static>>,
+    pub write_timeout: Option<Duration>,
+    pub close_linger: Duration,
+    pub keepalive: Option<Keepalive>,
+    pub interface_style: InterfaceStyle,
+    // the head sent ahead of the TLS handshake
+    pub cover_request: Option<Vec<u8>>,
+    pub buffers: BufferSizes,
+    pub transport: Transport,
+    pub reconnect: Option<ReconnectConfig>,
+    pub default_route: bool,
+    pub apply_dns: bool,
+    // a shell script restoring the routes and DNS settings, for when the client dies uncleanly
+    pub undo_script: Option<PathBuf>,
+    pub advertised_routes: Vec<(Ipv4Addr, u8)>,
+    pub proxy: Option<ProxyConfig>,
+}
+
+pub struct ProxyConfig {
+    pub protocol: ProxyProtocol,
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<ProxyCredentials>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocol {
+    Socks5,
+    Http,
+}
+
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+pub struct ReconnectConfig {
+    // None retries forever
+    pub max_attempts: Option<u32>,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub reconnect_on: Vec<ErrorClass>,
+    pub jitter: Jitter,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+    #[default]
+    None,
+    // anywhere between no delay and the exponential backoff
+    Full,
+    // between the initial backoff and three times the previous delay
+    Decorrelated,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    // connection failures, resets and anything not classified otherwise
+    Network,
+    Timeout,
+    // the server ended the session
+    Closed,
+    // either side rejected the other's certificate
+    Auth,
+    Version,
+}
+
+#[derive(Clone, Copy)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub struct BufferSizes {
+    pub read: usize,
+    pub write: usize,
+}
+
+// a profile fills in whichever of its settings are not given explicitly
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Profile {
+    #[default]
+    Custom,
+    // keepalive every 15s with a 45s timeout to hold carrier NAT mappings open, 15s
+    // write timeout for slow radio links, reconnect backoff from 500ms up to 30s
+    Mobile,
+    // keepalive every 60s with a 180s timeout, 5s write timeout, reconnect backoff
+    // from 1s up to 60s
+    Datacenter,
+}
+
+struct ProfileDefaults {
+    keepalive_interval_secs: u64,
+    keepalive_timeout_secs: u64,
+    write_timeout_ms: u64,
+    backoff_ms: u64,
+    max_backoff_ms: u64,
+}
+
+impl Profile {
+    fn defaults(self) -> Option<ProfileDefaults> {
+        match self {
+            Self::Custom => None,
+            Self::Mobile => Some(ProfileDefaults {
+                keepalive_interval_secs: 15,
+                keepalive_timeout_secs: 45,
+                write_timeout_ms: 15_000,
+                backoff_ms: 500,
+                max_backoff_ms: 30_000,
+            }),
+            Self::Datacenter => Some(ProfileDefaults {
+                keepalive_interval_secs: 60,
+                keepalive_timeout_secs: 180,
+                write_timeout_ms: 5_000,
+                backoff_ms: 1_000,
+                max_backoff_ms: 60_000,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceStyle {
+    Subnet,
+    PointToPoint,
+}
+
+// Linux installs the connected subnet route for an interface without a peer, other
+// platforms may require one, so they stay point-to-point unless configured otherwise
+impl Default for InterfaceStyle {
+    fn default() -> Self {
+        if cfg!(target_os = "linux") {
+            Self::Subnet
+        } else {
+            Self::PointToPoint
+        }
+    }
 }
 
 pub struct ServerConfig {
     pub port: u16,
     pub virtual_address: Ipv4Addr,
     pub subnet_mask: Ipv4Addr,
+    pub forward_broadcast: bool,
+    pub max_pps: Option<u32>,
+    pub pps_overrides: HashMap<String, u32>,
+    pub static_leases: HashMap<String, Ipv4Addr>,
+    pub write_timeout: Option<Duration>,
+    pub close_linger: Duration,
+    pub keepalive: Option<Keepalive>,
+    pub unix_socket: Option<PathBuf>,
+    pub echo_mode: bool,
+    pub watchdog: Option<Duration>,
+    pub allocation: AllocationStrategy,
+    pub client_limit: Option<ClientLimit>,
+    pub max_connects_per_sec: Option<u32>,
+    // bytes per second a TLS handshake has to average to not be dropped
+    pub min_handshake_rate: Option<u64>,
+    pub blocked_destinations: Vec<Ipv4Addr>,
+    pub max_session: Option<Duration>,
+    pub event_socket: Option<PathBuf>,
+    pub expect_cover: bool,
+    pub cover_response: Vec<u8>,
+    pub max_connections_per_cn: Option<usize>,
+    pub lease_file: Option<PathBuf>,
+    pub clock_skew_tolerance: Option<Duration>,
+    pub buffers: BufferSizes,
+    pub ip_reclaim_grace: Option<Duration>,
+    pub transport: Transport,
+    pub subnet_routes: Vec<SubnetRoute>,
+    // subnets each client may announce as reachable through itself
+    pub route_allowlist: HashMap<String, Vec<(Ipv4Addr, u8)>>,
+    pub dns: Vec<Ipv4Addr>,
+    pub search_domain: Option<String>,
+}
+
+pub struct SubnetRoute {
+    pub prefix: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+}
+
+pub struct ClientLimit {
+    pub max_clients: usize,
+    pub policy: AdmissionPolicy,
+}
+
+#[derive(Clone, Copy)]
+pub enum AdmissionPolicy {
+    Reject,
+    Queue { timeout: Duration, capacity: usize },
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationStrategy {
+    #[default]
+    #[serde(alias = "ascending")]
+    Sequential,
+    Descending,
+    Random,
+    DeriveFromKey,
 }
 
 pub enum Mode {
     Client(ClientConfig),
-    Server(ServerConfig),
+    Server(Box<ServerConfig>),
+    Loopback(Box<ServerConfig>, ClientConfig),
 }
 
 pub struct TlsConfig {
     pub root_certificate: CertificateDer<'static>,
     pub certificate: CertificateDer<'static>,
     pub key: PrivateKeyDer<'static>,
+    pub extra_identities: Vec<TlsIdentity>,
+    pub alpn: Option<Vec<u8>>,
+}
+
+pub struct TlsIdentity {
+    pub root_certificate: CertificateDer<'static>,
+    pub certificate: CertificateDer<'static>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Clone for TlsConfig {
+    fn clone(&self) -> Self {
+        Self {
+            root_certificate: self.root_certificate.clone(),
+            certificate: self.certificate.clone(),
+            key: self.key.clone_key(),
+            extra_identities: self.extra_identities.clone(),
+            alpn: self.alpn.clone(),
+        }
+    }
+}
+
+impl Clone for TlsIdentity {
+    fn clone(&self) -> Self {
+        Self {
+            root_certificate: self.root_certificate.clone(),
+            certificate: self.certificate.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+pub struct LogConfig {
+    pub file: PathBuf,
+    pub max_size: Option<u64>,
+    pub keep: u32,
 }
 
 pub struct Config {
     pub mode: Mode,
     pub tls: TlsConfig,
+    pub pid_file: Option<PathBuf>,
+    pub log: Option<LogConfig>,
+    pub cpu_affinity: Vec<usize>,
 }
 
 #[derive(Deserialize)]
 struct RawClient {
     address: String,
+    #[serde(deserialize_with = "deserialize_port")]
+    port: u16,
+    resolver: Option<IpAddr>,
+    server_name: Option<String>,
+    #[serde(default)]
+    profile: Profile,
+    write_timeout_ms: Option<u64>,
+    close_linger_ms: Option<u64>,
+    keepalive_interval_secs: Option<u64>,
+    keepalive_timeout_secs: Option<u64>,
+    #[serde(default)]
+    interface_style: InterfaceStyle,
+    cover_host: Option<String>,
+    cover_request: Option<String>,
+    read_buffer_size: Option<usize>,
+    write_buffer_size: Option<usize>,
+    #[serde(default)]
+    transport: Transport,
+    reconnect: Option<RawReconnect>,
+    #[serde(default)]
+    default_route: bool,
+    #[serde(default)]
+    apply_dns: bool,
+    undo_script: Option<PathBuf>,
+    #[serde(default)]
+    advertise_routes: Vec<String>,
+    proxy: Option<RawProxy>,
+}
+
+#[derive(Deserialize)]
+struct RawProxy {
+    protocol: ProxyProtocol,
+    address: String,
+    #[serde(deserialize_with = "deserialize_port")]
     port: u16,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawReconnect {
+    #[serde(alias = "max_retries")]
+    max_reconnect_attempts: Option<u32>,
+    backoff_ms: Option<u64>,
+    max_backoff_ms: Option<u64>,
+    reconnect_on: Option<Vec<ErrorClass>>,
+    #[serde(default)]
+    reconnect_jitter: Jitter,
+}
+
+#[derive(Deserialize)]
+struct RawSubnetRoute {
+    subnet: String,
+    gateway: Ipv4Addr,
 }
 
 #[derive(Deserialize)]
 struct RawServer {
+    #[serde(deserialize_with = "deserialize_port")]
     port: u16,
     virtual_address: Ipv4Addr,
     subnet_mask: Ipv4Addr,
+    #[serde(default)]
+    forward_broadcast: bool,
+    max_pps: Option<u32>,
+    #[serde(default)]
+    pps_overrides: HashMap<String, u32>,
+    #[serde(default)]
+    static_leases: HashMap<String, Ipv4Addr>,
+    static_leases_file: Option<PathBuf>,
+    write_timeout_ms: Option<u64>,
+    close_linger_ms: Option<u64>,
+    keepalive_interval_secs: Option<u64>,
+    keepalive_timeout_secs: Option<u64>,
+    unix_socket: Option<PathBuf>,
+    #[serde(default)]
+    echo_mode: bool,
+    watchdog_secs: Option<u64>,
+    #[serde(default)]
+    allocation: AllocationStrategy,
+    max_clients: Option<usize>,
+    #[serde(default)]
+    admission: RawAdmission,
+    queue_timeout_secs: Option<u64>,
+    queue_size: Option<usize>,
+    max_connects_per_sec: Option<u32>,
+    min_handshake_bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    blocked_destinations: Vec<Ipv4Addr>,
+    max_session_secs: Option<u64>,
+    event_socket: Option<PathBuf>,
+    #[serde(default)]
+    expect_cover: bool,
+    cover_response: Option<String>,
+    max_connections_per_cn: Option<usize>,
+    lease_file: Option<PathBuf>,
+    clock_skew_tolerance_secs: Option<u64>,
+    ip_reclaim_grace_secs: Option<u64>,
+    read_buffer_size: Option<usize>,
+    write_buffer_size: Option<usize>,
+    #[serde(default)]
+    transport: Transport,
+    #[serde(default)]
+    subnet_routes: Vec<RawSubnetRoute>,
+    #[serde(default)]
+    route_allowlist: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    dns: Vec<Ipv4Addr>,
+    search_domain: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawAdmission {
+    #[default]
+    Reject,
+    Queue,
 }
 
 #[derive(Deserialize)]
 struct RawTls {
-    root_certificate: String,
-    certificate: String,
-    key: String,
+    root_certificate: Option<String>,
+    root_certificate_path: Option<PathBuf>,
+    certificate: Option<String>,
+    certificate_path: Option<PathBuf>,
+    key: Option<String>,
+    key_path: Option<PathBuf>,
+    #[serde(default)]
+    extra_identities: Vec<RawIdentity>,
+    alpn: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawIdentity {
+    root_certificate: Option<String>,
+    root_certificate_path: Option<PathBuf>,
+    certificate: Option<String>,
+    certificate_path: Option<PathBuf>,
+    key: Option<String>,
+    key_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct RawLog {
+    file: PathBuf,
+    max_size: Option<u64>,
+    keep: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawMode {
+    Loopback,
 }
 
 #[derive(Deserialize)]
 struct RawConfig {
+    mode: Option<RawMode>,
     client: Option<RawClient>,
     server: Option<RawServer>,
     tls: RawTls,
+    pid_file: Option<PathBuf>,
+    log: Option<RawLog>,
+    #[serde(default)]
+    cpu_affinity: Vec<usize>,
 }
 
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
 pub fn load_config<P: AsRef<Path>>(path: P) -> anyhow::Result<Config> {
     let mut file = File::open(path).context("could not open config file")?;
-    let mut raw = String::new();
+    let mut bytes = Vec::new();
     _ = file
-        .read_to_string(&mut raw)
+        .read_to_end(&mut bytes)
         .context("could not read config file")?;
+    let raw = String::from_utf8(decompress(bytes)?).context("config is not valid UTF-8")?;
 
-    let raw_config: RawConfig = toml::from_str(&raw).context("could not parse config")?;
+    let mut raw_config: toml::Value = toml::from_str(&raw).context("could not parse config")?;
+    substitute_env(&mut raw_config, &|name| std::env::var(name).ok())?;
+    let raw_config: RawConfig = raw_config.try_into().context("could not parse config")?;
     read_config(raw_config)
 }
 
+// only string values are expanded, so comments are left alone and a variable
+// cannot change the structure of the config
+fn substitute_env(
+    value: &mut toml::Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(text) => *text = expand_env(text, lookup)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                substitute_env(value, lookup)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                substitute_env(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// `$${` stands for a literal `${`
+fn expand_env(text: &str, lookup: &dyn Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if let Some(before) = rest[..start].strip_suffix('$') {
+            expanded.push_str(before);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .context("unterminated environment variable reference in config")?;
+        let name = &reference[..end];
+        let value = lookup(name)
+            .with_context(|| format!("environment variable {name} used in config is not set"))?;
+        expanded.push_str(&value);
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn deserialize_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawPort {
+        Number(u16),
+        Text(String),
+    }
+
+    match RawPort::deserialize(deserializer)? {
+        RawPort::Number(port) => Ok(port),
+        RawPort::Text(text) => text.parse().map_err(de::Error::custom),
+    }
+}
+
+fn decompress(bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    if bytes.starts_with(GZIP_MAGIC) {
+        _ = GzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut decompressed)
+            .context("could not decompress gzip config")?;
+    } else if bytes.starts_with(ZSTD_MAGIC) {
+        _ = zstd::Decoder::new(bytes.as_slice())?
+            .read_to_end(&mut decompressed)
+            .context("could not decompress zstd config")?;
+    } else {
+        return Ok(bytes);
+    }
+    Ok(decompressed)
+}
+
 fn read_config(raw_config: RawConfig) -> anyhow::Result<Config> {
+    let mode = match (raw_config.mode, raw_config.client, raw_config.server) {
+        (Some(RawMode::Loopback), Some(raw_client), Some(raw_server)) => {
+            Mode::Loopback(read_server(raw_server)?.into(), read_client(raw_client)?)
+        }
+        (Some(RawMode::Loopback), _, _) => {
+            bail!("loopback mode requires both 'client' and 'server' sections")
+        }
+        (None, Some(_), Some(_)) => bail!(
+            "config cannot contain both 'client' and 'server' sections \
+             unless mode is 'loopback'"
+        ),
+        (None, Some(raw_client), None) => Mode::Client(read_client(raw_client)?),
+        (None, None, Some(raw_server)) => Mode::Server(read_server(raw_server)?.into()),
+        (None, None, None) => bail!("config must contain either 'client' or 'server' section"),
+    };
+    let tls = read_tls(raw_config.tls)?;
+
+    Ok(Config {
+        mode,
+        tls,
+        pid_file: raw_config.pid_file,
+        log: raw_config.log.map(read_log),
+        cpu_affinity: raw_config.cpu_affinity,
+    })
+}
+
+fn read_client(mut raw_client: RawClient) -> anyhow::Result<ClientConfig> {
+    apply_profile(&mut raw_client);
+    // datagrams would have to bypass the proxy, which is what it is there to prevent
+    ensure!(
+        raw_client.proxy.is_none() || raw_client.transport == Transport::Tcp,
+        "the UDP transport cannot be used through a proxy"
+    );
+    // the proxy resolves the host, so without a server name there is no IP to check against
     ensure!(
-        raw_config.client.is_none() || raw_config.server.is_none(),
-        "config cannot contain both 'client' and 'server' sections"
+        raw_client.proxy.is_none()
+            || raw_client.server_name.is_some()
+            || raw_client.address.parse::<IpAddr>().is_ok(),
+        "a proxy with a server host name requires 'server_name'"
     );
+    ensure!(
+        cfg!(target_os = "linux") || raw_client.undo_script.is_none(),
+        "undo scripts are only supported on Linux"
+    );
+    Ok(ClientConfig {
+        host: raw_client.address,
+        port: raw_client.port,
+        resolver: raw_client.resolver,
+        server_name: raw_client
+            .server_name
+            .map(ServerName::try_from)
+            .transpose()
+            .context("invalid server_name")?,
+        write_timeout: raw_client.write_timeout_ms.map(Duration::from_millis),
+        close_linger: read_close_linger(raw_client.close_linger_ms)?,
+        keepalive: read_keepalive(
+            raw_client.keepalive_interval_secs,
+            raw_client.keepalive_timeout_secs,
+        )?,
+        interface_style: raw_client.interface_style,
+        cover_request: read_cover_request(raw_client.cover_host, raw_client.cover_request)?,
+        buffers: read_buffer_sizes(raw_client.read_buffer_size, raw_client.write_buffer_size)?,
+        transport: raw_client.transport,
+        reconnect: raw_client.reconnect.map(read_reconnect).transpose()?,
+        default_route: raw_client.default_route,
+        apply_dns: raw_client.apply_dns,
+        undo_script: raw_client.undo_script,
+        advertised_routes: raw_client
+            .advertise_routes
+            .iter()
+            .map(|subnet| parse_subnet(subnet))
+            .collect::<anyhow::Result<_>>()?,
+        proxy: raw_client.proxy.map(read_proxy).transpose()?,
+    })
+}
 
-    let mode = if let Some(raw_client) = raw_config.client {
-        Mode::Client(read_client(raw_client)?)
-    } else if let Some(raw_server) = raw_config.server {
-        Mode::Server(read_server(raw_server)?)
-    } else {
-        bail!("config must contain either 'client' or 'server' section");
+fn apply_profile(raw_client: &mut RawClient) {
+    let Some(defaults) = raw_client.profile.defaults() else {
+        return;
     };
-    let tls = read_tls(raw_config.tls)?;
+    // an explicit interval keeps deriving its timeout from itself
+    if raw_client.keepalive_interval_secs.is_none() {
+        raw_client.keepalive_interval_secs = Some(defaults.keepalive_interval_secs);
+        raw_client
+            .keepalive_timeout_secs
+            .get_or_insert(defaults.keepalive_timeout_secs);
+    }
+    raw_client
+        .write_timeout_ms
+        .get_or_insert(defaults.write_timeout_ms);
+    // reconnecting stays opt-in, the profile only shapes the backoff
+    if let Some(reconnect) = &mut raw_client.reconnect {
+        let backoff_ms = *reconnect.backoff_ms.get_or_insert(defaults.backoff_ms);
+        reconnect
+            .max_backoff_ms
+            .get_or_insert(defaults.max_backoff_ms.max(backoff_ms));
+    }
+}
 
-    Ok(Config { mode, tls })
+fn read_cover_request(
+    host: Option<String>,
+    request: Option<String>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    match (host, request) {
+        (Some(_), Some(_)) => bail!("cover_host and cover_request cannot be used together"),
+        (Some(host), None) => Ok(Some(cover::default_request(&host))),
+        (None, Some(request)) => {
+            cover::validate_request(request.as_bytes()).context("invalid cover_request")?;
+            Ok(Some(request.into_bytes()))
+        }
+        (None, None) => Ok(None),
+    }
 }
 
-fn read_client(raw_client: RawClient) -> anyhow::Result<ClientConfig> {
-    let address = (raw_client.address.as_str(), raw_client.port)
-        .to_socket_addrs()?
-        .next()
-        .context("could not parse server address")?;
-    Ok(ClientConfig { address })
+fn read_cover_response(expect_cover: bool, response: Option<String>) -> anyhow::Result<Vec<u8>> {
+    let Some(response) = response else {
+        return Ok(cover::DEFAULT_RESPONSE.into());
+    };
+    ensure!(expect_cover, "cover_response requires expect_cover");
+    cover::validate_response(response.as_bytes()).context("invalid cover_response")?;
+    Ok(response.into_bytes())
+}
+
+fn read_proxy(raw_proxy: RawProxy) -> anyhow::Result<ProxyConfig> {
+    let credentials = match (raw_proxy.username, raw_proxy.password) {
+        (Some(username), Some(password)) => Some(ProxyCredentials {
+            username,
+            password: unseal(password).context("could not unseal proxy password")?,
+        }),
+        (None, None) => None,
+        _ => bail!("proxy username and password must be given together"),
+    };
+    if let (ProxyProtocol::Socks5, Some(credentials)) = (raw_proxy.protocol, &credentials) {
+        ensure!(
+            (1..=255).contains(&credentials.username.len())
+                && (1..=255).contains(&credentials.password.len()),
+            "SOCKS5 proxy username and password must be 1 to 255 bytes long"
+        );
+    }
+    Ok(ProxyConfig {
+        protocol: raw_proxy.protocol,
+        host: raw_proxy.address,
+        port: raw_proxy.port,
+        credentials,
+    })
 }
 
-fn read_server(raw_server: RawServer) -> anyhow::Result<ServerConfig> {
+fn read_reconnect(raw_reconnect: RawReconnect) -> anyhow::Result<ReconnectConfig> {
+    const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+    const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+    // a rejected certificate or an incompatible server will not fix itself
+    const DEFAULT_RECONNECT_ON: [ErrorClass; 3] =
+        [ErrorClass::Network, ErrorClass::Timeout, ErrorClass::Closed];
+    let reconnect = ReconnectConfig {
+        // zero reads as no limit, like leaving it out
+        max_attempts: raw_reconnect.max_reconnect_attempts.filter(|&max| max > 0),
+        backoff: raw_reconnect
+            .backoff_ms
+            .map_or(DEFAULT_BACKOFF, Duration::from_millis),
+        max_backoff: raw_reconnect
+            .max_backoff_ms
+            .map_or(DEFAULT_MAX_BACKOFF, Duration::from_millis),
+        reconnect_on: raw_reconnect
+            .reconnect_on
+            .unwrap_or_else(|| DEFAULT_RECONNECT_ON.into()),
+        jitter: raw_reconnect.reconnect_jitter,
+    };
+    ensure!(
+        !reconnect.backoff.is_zero(),
+        "reconnect backoff must be positive"
+    );
+    ensure!(
+        reconnect.max_backoff >= reconnect.backoff,
+        "maximum reconnect backoff must not be below the initial backoff"
+    );
+    Ok(reconnect)
+}
+
+fn read_close_linger(close_linger_ms: Option<u64>) -> anyhow::Result<Duration> {
+    const DEFAULT_CLOSE_LINGER: Duration = Duration::from_secs(2);
+    // anything shorter times out every close before a flush can get through
+    const MIN_CLOSE_LINGER: Duration = Duration::from_millis(100);
+    let close_linger = close_linger_ms.map_or(DEFAULT_CLOSE_LINGER, Duration::from_millis);
+    ensure!(
+        close_linger >= MIN_CLOSE_LINGER,
+        "close linger must be at least {}ms",
+        MIN_CLOSE_LINGER.as_millis()
+    );
+    Ok(close_linger)
+}
+
+fn read_keepalive(
+    interval_secs: Option<u64>,
+    timeout_secs: Option<u64>,
+) -> anyhow::Result<Option<Keepalive>> {
+    // a few keepalives may be lost or delayed before the peer counts as dead
+    const DEFAULT_TIMEOUT_INTERVALS: u32 = 3;
+    let Some(interval_secs) = interval_secs else {
+        ensure!(
+            timeout_secs.is_none(),
+            "keepalive timeout requires a keepalive interval"
+        );
+        return Ok(None);
+    };
+    let interval = Duration::from_secs(interval_secs);
+    let keepalive = Keepalive {
+        interval,
+        timeout: timeout_secs.map_or(interval * DEFAULT_TIMEOUT_INTERVALS, Duration::from_secs),
+    };
+    ensure!(!interval.is_zero(), "keepalive interval must be positive");
+    ensure!(
+        keepalive.timeout > keepalive.interval,
+        "keepalive timeout must be longer than the keepalive interval"
+    );
+    Ok(Some(keepalive))
+}
+
+fn read_buffer_sizes(read: Option<usize>, write: Option<usize>) -> anyhow::Result<BufferSizes> {
+    // one maximum-size TLS record
+    const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+    // a buffer must at least fit a length prefix
+    const MIN_BUFFER_SIZE: usize = 64;
+    let sizes = BufferSizes {
+        read: read.unwrap_or(DEFAULT_BUFFER_SIZE),
+        write: write.unwrap_or(DEFAULT_BUFFER_SIZE),
+    };
+    ensure!(
+        sizes.read >= MIN_BUFFER_SIZE && sizes.write >= MIN_BUFFER_SIZE,
+        "buffer sizes must be at least {MIN_BUFFER_SIZE} bytes"
+    );
+    Ok(sizes)
+}
+
+fn read_server(mut raw_server: RawServer) -> anyhow::Result<ServerConfig> {
+    if let Some(path) = raw_server.static_leases_file.take() {
+        merge_static_leases(&mut raw_server.static_leases, &path)
+            .with_context(|| format!("could not load static leases from {}", path.display()))?;
+    }
+    ensure!(
+        raw_server.max_pps != Some(0) && !raw_server.pps_overrides.values().any(|&pps| pps == 0),
+        "packet rate limits must be positive"
+    );
+    ensure!(
+        raw_server.max_connects_per_sec != Some(0),
+        "connection rate limit must be positive"
+    );
+    ensure!(
+        raw_server.min_handshake_bytes_per_sec != Some(0),
+        "minimum handshake rate must be positive"
+    );
+    ensure!(
+        raw_server.dns.len() <= u8::MAX.into(),
+        "at most {} DNS servers can be pushed",
+        u8::MAX
+    );
+    ensure!(
+        raw_server.watchdog_secs != Some(0),
+        "watchdog timeout must be positive"
+    );
+    ensure!(
+        raw_server.max_connections_per_cn != Some(0),
+        "per-identity connection limit must be positive"
+    );
+    ensure!(
+        raw_server.max_session_secs != Some(0),
+        "maximum session lifetime must be positive"
+    );
+    let search_domain = raw_server
+        .search_domain
+        .take()
+        .filter(|domain| !domain.is_empty());
+    if let Some(domain) = &search_domain {
+        validate_domain(domain).context("invalid search domain")?;
+    }
+    validate_static_leases(&raw_server)?;
+    let subnet_routes = read_subnet_routes(&raw_server)?;
+    let route_allowlist = read_route_allowlist(&raw_server)?;
+    let client_limit = read_client_limit(&raw_server)?;
+    #[cfg(not(unix))]
+    ensure!(
+        raw_server.unix_socket.is_none() && raw_server.event_socket.is_none(),
+        "unix sockets are not supported on this platform"
+    );
+
     Ok(ServerConfig {
         port: raw_server.port,
         virtual_address: raw_server.virtual_address,
         subnet_mask: raw_server.subnet_mask,
+        forward_broadcast: raw_server.forward_broadcast,
+        max_pps: raw_server.max_pps,
+        pps_overrides: raw_server.pps_overrides,
+        static_leases: raw_server.static_leases,
+        write_timeout: raw_server.write_timeout_ms.map(Duration::from_millis),
+        close_linger: read_close_linger(raw_server.close_linger_ms)?,
+        keepalive: read_keepalive(
+            raw_server.keepalive_interval_secs,
+            raw_server.keepalive_timeout_secs,
+        )?,
+        unix_socket: raw_server.unix_socket,
+        echo_mode: raw_server.echo_mode,
+        watchdog: raw_server.watchdog_secs.map(Duration::from_secs),
+        allocation: raw_server.allocation,
+        client_limit,
+        max_connects_per_sec: raw_server.max_connects_per_sec,
+        min_handshake_rate: raw_server.min_handshake_bytes_per_sec,
+        blocked_destinations: raw_server.blocked_destinations,
+        max_session: raw_server.max_session_secs.map(Duration::from_secs),
+        event_socket: raw_server.event_socket,
+        expect_cover: raw_server.expect_cover,
+        cover_response: read_cover_response(raw_server.expect_cover, raw_server.cover_response)?,
+        max_connections_per_cn: raw_server.max_connections_per_cn,
+        lease_file: raw_server.lease_file,
+        clock_skew_tolerance: raw_server
+            .clock_skew_tolerance_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        buffers: read_buffer_sizes(raw_server.read_buffer_size, raw_server.write_buffer_size)?,
+        ip_reclaim_grace: raw_server
+            .ip_reclaim_grace_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        transport: raw_server.transport,
+        subnet_routes,
+        route_allowlist,
+        dns: raw_server.dns,
+        search_domain,
     })
 }
 
+fn read_client_limit(raw_server: &RawServer) -> anyhow::Result<Option<ClientLimit>> {
+    const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    let queue_options_set =
+        raw_server.queue_timeout_secs.is_some() || raw_server.queue_size.is_some();
+    let Some(max_clients) = raw_server.max_clients else {
+        ensure!(
+            matches!(raw_server.admission, RawAdmission::Reject) && !queue_options_set,
+            "admission settings require 'max_clients'"
+        );
+        return Ok(None);
+    };
+    ensure!(max_clients > 0, "max_clients must be positive");
+
+    let policy = match raw_server.admission {
+        RawAdmission::Reject => {
+            ensure!(
+                !queue_options_set,
+                "queue settings require admission = \"queue\""
+            );
+            AdmissionPolicy::Reject
+        }
+        RawAdmission::Queue => {
+            ensure!(
+                raw_server.queue_timeout_secs != Some(0) && raw_server.queue_size != Some(0),
+                "admission queue timeout and size must be positive"
+            );
+            AdmissionPolicy::Queue {
+                timeout: raw_server
+                    .queue_timeout_secs
+                    .map_or(DEFAULT_QUEUE_TIMEOUT, Duration::from_secs),
+                capacity: raw_server.queue_size.unwrap_or(max_clients),
+            }
+        }
+    };
+    Ok(Some(ClientLimit {
+        max_clients,
+        policy,
+    }))
+}
+
+fn merge_static_leases(
+    static_leases: &mut HashMap<String, Ipv4Addr>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let raw = std::fs::read_to_string(path)?;
+    let from_file: HashMap<String, Ipv4Addr> = toml::from_str(&raw)?;
+    for (common_name, addr) in from_file {
+        ensure!(
+            !static_leases.contains_key(&common_name),
+            "'{common_name}' also has an inline static lease"
+        );
+        _ = static_leases.insert(common_name, addr);
+    }
+    Ok(())
+}
+
+fn validate_static_leases(raw_server: &RawServer) -> anyhow::Result<()> {
+    let netmask = raw_server.subnet_mask.to_bits();
+    let subnet = raw_server.virtual_address.to_bits() & netmask;
+    let mut seen = HashMap::new();
+    for (common_name, addr) in &raw_server.static_leases {
+        ensure!(
+            addr.to_bits() & netmask == subnet,
+            "static lease {addr} for '{common_name}' is outside the virtual subnet"
+        );
+        ensure!(
+            *addr != raw_server.virtual_address,
+            "static lease for '{common_name}' uses the server address {addr}"
+        );
+        let host_bits = addr.to_bits() & !netmask;
+        ensure!(
+            netmask.count_zeros() < 2 || (host_bits != 0 && host_bits != !netmask),
+            "static lease for '{common_name}' uses the network or broadcast address {addr}"
+        );
+        if let Some(other) = seen.insert(*addr, common_name) {
+            bail!("static lease {addr} is assigned to both '{other}' and '{common_name}'");
+        }
+    }
+    Ok(())
+}
+
+// a dynamically leased gateway would hand the subnet to whichever client gets its address,
+// static leases are validated to lie in the client subnet already
+fn read_subnet_routes(raw_server: &RawServer) -> anyhow::Result<Vec<SubnetRoute>> {
+    raw_server
+        .subnet_routes
+        .iter()
+        .map(|raw_route| {
+            let (prefix, prefix_len) = parse_subnet(&raw_route.subnet)?;
+            let gateway = raw_route.gateway;
+            ensure!(
+                raw_server
+                    .static_leases
+                    .values()
+                    .any(|&addr| addr == gateway),
+                "gateway {gateway} for {} must be a static lease",
+                raw_route.subnet
+            );
+            Ok(SubnetRoute {
+                prefix,
+                prefix_len,
+                gateway,
+            })
+        })
+        .collect()
+}
+
+fn read_route_allowlist(
+    raw_server: &RawServer,
+) -> anyhow::Result<HashMap<String, Vec<(Ipv4Addr, u8)>>> {
+    raw_server
+        .route_allowlist
+        .iter()
+        .map(|(common_name, subnets)| {
+            let subnets = subnets
+                .iter()
+                .map(|subnet| parse_subnet(subnet))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("invalid route allowlist for '{common_name}'"))?;
+            Ok((common_name.clone(), subnets))
+        })
+        .collect()
+}
+
+pub fn parse_subnet(subnet: &str) -> anyhow::Result<(Ipv4Addr, u8)> {
+    let (prefix, prefix_len) = subnet
+        .split_once('/')
+        .with_context(|| format!("subnet {subnet} is missing a prefix length"))?;
+    let prefix: Ipv4Addr = prefix
+        .parse()
+        .with_context(|| format!("invalid subnet address in {subnet}"))?;
+    let prefix_len: u8 = prefix_len
+        .parse()
+        .ok()
+        .filter(|&len| len <= 32)
+        .with_context(|| format!("invalid prefix length in {subnet}"))?;
+    let host_mask = u32::MAX.checked_shr(prefix_len.into()).unwrap_or(0);
+    ensure!(
+        prefix.to_bits() & host_mask == 0,
+        "subnet {subnet} has host bits set"
+    );
+    Ok((prefix, prefix_len))
+}
+
+// the pushed domain ends up in resolver configuration, so only plain hostname syntax passes
+pub fn validate_domain(domain: &str) -> anyhow::Result<()> {
+    const MAX_DOMAIN_SIZE: usize = 253;
+    const MAX_LABEL_SIZE: usize = 63;
+
+    ensure!(
+        domain.len() <= MAX_DOMAIN_SIZE,
+        "domain {domain} is longer than {MAX_DOMAIN_SIZE} characters"
+    );
+    for label in domain.split('.') {
+        ensure!(
+            (1..=MAX_LABEL_SIZE).contains(&label.len()),
+            "domain {domain} has an empty or overlong label"
+        );
+        ensure!(
+            label
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+                && !label.starts_with('-')
+                && !label.ends_with('-'),
+            "domain {domain} has an invalid label '{label}'"
+        );
+    }
+    Ok(())
+}
+
+fn read_log(raw_log: RawLog) -> LogConfig {
+    const DEFAULT_KEEP: u32 = 5;
+
+    LogConfig {
+        file: raw_log.file,
+        max_size: raw_log.max_size,
+        keep: raw_log.keep.unwrap_or(DEFAULT_KEEP),
+    }
+}
+
 fn read_tls(raw_tls: RawTls) -> anyhow::Result<TlsConfig> {
-    let root_cert = CertificateDer::from_pem_slice(raw_tls.root_certificate.as_bytes())?;
-    let cert = CertificateDer::from_pem_slice(raw_tls.certificate.as_bytes())?;
-    let key = PrivateKeyDer::from_pem_slice(raw_tls.key.as_bytes())?;
+    let root_cert = read_pem(
+        raw_tls.root_certificate,
+        raw_tls.root_certificate_path,
+        "root_certificate",
+    )?;
+    let root_cert = CertificateDer::from_pem_slice(root_cert.as_bytes())?;
+    let cert = read_pem(raw_tls.certificate, raw_tls.certificate_path, "certificate")?;
+    let cert = CertificateDer::from_pem_slice(cert.as_bytes())?;
+    let key = read_pem(raw_tls.key, raw_tls.key_path, "key")?;
+    let key = unseal(key).context("could not unseal TLS key")?;
+    let key = PrivateKeyDer::from_pem_slice(key.as_bytes())?;
+
+    let extra_identities = raw_tls
+        .extra_identities
+        .into_iter()
+        .enumerate()
+        .map(|(index, raw_identity)| {
+            read_identity(raw_identity)
+                .with_context(|| format!("could not read TLS identity #{}", index + 1))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    if let Some(alpn) = &raw_tls.alpn {
+        ensure!(
+            (1..=255).contains(&alpn.len()),
+            "ALPN protocol must be between 1 and 255 bytes long"
+        );
+    }
 
     Ok(TlsConfig {
         root_certificate: root_cert,
         certificate: cert,
         key,
+        extra_identities,
+        alpn: raw_tls.alpn.map(String::into_bytes),
     })
 }
+
+fn read_identity(raw_identity: RawIdentity) -> anyhow::Result<TlsIdentity> {
+    let root_cert = read_pem(
+        raw_identity.root_certificate,
+        raw_identity.root_certificate_path,
+        "root_certificate",
+    )?;
+    let cert = read_pem(
+        raw_identity.certificate,
+        raw_identity.certificate_path,
+        "certificate",
+    )?;
+    let key = read_pem(raw_identity.key, raw_identity.key_path, "key")?;
+    let key = unseal(key).context("could not unseal TLS key")?;
+    Ok(TlsIdentity {
+        root_certificate: CertificateDer::from_pem_slice(root_cert.as_bytes())?,
+        certificate: CertificateDer::from_pem_slice(cert.as_bytes())?,
+        key: PrivateKeyDer::from_pem_slice(key.as_bytes())?,
+    })
+}
+
+fn read_pem(inline: Option<String>, path: Option<PathBuf>, field: &str) -> anyhow::Result<String> {
+    match (inline, path) {
+        (Some(pem), None) => Ok(pem),
+        (None, Some(path)) => std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read {field} from {}", path.display())),
+        (Some(_), Some(_)) => bail!("only one of '{field}' and '{field}_path' may be given"),
+        (None, None) => bail!("either '{field}' or '{field}_path' is required"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const PLAIN_CONFIG: &str = r#"
+        [client]
+        address = "vpn.example.com"
+        port = 443
+    "#;
+
+    fn parse(bytes: Vec<u8>) -> toml::Table {
+        toml::from_str(&String::from_utf8(decompress(bytes).unwrap()).unwrap()).unwrap()
+    }
+
+    fn server(extra: &str) -> anyhow::Result<ServerConfig> {
+        read_server(
+            toml::from_str(&format!(
+                "port = 443\nvirtual_address = \"10.0.0.1\"\nsubnet_mask = \"255.255.255.0\"\n{extra}"
+            ))
+            .unwrap(),
+        )
+    }
+
+    fn client(extra: &str) -> anyhow::Result<ClientConfig> {
+        read_client(
+            toml::from_str(&format!(
+                "address = \"vpn.example.com\"\nport = 443\n{extra}"
+            ))
+            .unwrap(),
+        )
+    }
+
+    fn reconnect(raw: &str) -> ReconnectConfig {
+        read_reconnect(toml::from_str(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn proxied_host_name_needs_a_server_name() {
+        let proxy = "[proxy]\nprotocol = \"socks5\"\naddress = \"10.1.1.1\"\nport = 1080\n";
+        assert_eq!(
+            client(proxy).err().unwrap().to_string(),
+            "a proxy with a server host name requires 'server_name'"
+        );
+        let config = client(&format!("server_name = \"vpn.example.com\"\n{proxy}")).unwrap();
+        assert_eq!(
+            config.server_name,
+            Some(ServerName::try_from("vpn.example.com").unwrap())
+        );
+        assert!(client("server_name = \"not a name\"").is_err());
+    }
+
+    #[test]
+    fn reconnect_skips_auth_and_version_errors_by_default() {
+        assert_eq!(
+            reconnect("").reconnect_on,
+            [ErrorClass::Network, ErrorClass::Timeout, ErrorClass::Closed]
+        );
+    }
+
+    #[test]
+    fn reconnect_on_overrides_the_defaults() {
+        assert_eq!(
+            reconnect(r#"reconnect_on = ["network", "auth"]"#).reconnect_on,
+            [ErrorClass::Network, ErrorClass::Auth]
+        );
+    }
+
+    #[test]
+    fn zero_reconnect_attempts_means_no_limit() {
+        assert_eq!(reconnect("").max_attempts, None);
+        assert_eq!(reconnect("max_reconnect_attempts = 0").max_attempts, None);
+        assert_eq!(
+            reconnect("max_reconnect_attempts = 5").max_attempts,
+            Some(5)
+        );
+        assert_eq!(reconnect("max_retries = 5").max_attempts, Some(5));
+    }
+
+    #[test]
+    fn reconnect_jitter_is_off_unless_configured() {
+        assert_eq!(reconnect("").jitter, Jitter::None);
+        assert_eq!(
+            reconnect(r#"reconnect_jitter = "decorrelated""#).jitter,
+            Jitter::Decorrelated
+        );
+    }
+
+    #[test]
+    fn mobile_profile_fills_in_its_defaults() {
+        let config = client("profile = \"mobile\"\n[reconnect]").unwrap();
+        let keepalive = config.keepalive.unwrap();
+        assert_eq!(keepalive.interval, Duration::from_secs(15));
+        assert_eq!(keepalive.timeout, Duration::from_secs(45));
+        assert_eq!(config.write_timeout, Some(Duration::from_secs(15)));
+        let reconnect = config.reconnect.unwrap();
+        assert_eq!(reconnect.backoff, Duration::from_millis(500));
+        assert_eq!(reconnect.max_backoff, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn explicit_settings_override_the_profile() {
+        let config = client(
+            "profile = \"mobile\"\nkeepalive_interval_secs = 30\nwrite_timeout_ms = 2000\n\
+             [reconnect]\nbackoff_ms = 40000",
+        )
+        .unwrap();
+        let keepalive = config.keepalive.unwrap();
+        assert_eq!(keepalive.interval, Duration::from_secs(30));
+        assert_eq!(keepalive.timeout, Duration::from_secs(90));
+        assert_eq!(config.write_timeout, Some(Duration::from_secs(2)));
+        let reconnect = config.reconnect.unwrap();
+        assert_eq!(reconnect.backoff, Duration::from_secs(40));
+        assert_eq!(reconnect.max_backoff, Duration::from_secs(40));
+    }
+
+    #[test]
+    fn custom_profile_adds_nothing() {
+        let config = client("profile = \"custom\"").unwrap();
+        assert!(config.keepalive.is_none());
+        assert_eq!(config.write_timeout, None);
+        assert!(config.reconnect.is_none());
+    }
+
+    #[test]
+    fn cover_request_is_built_or_taken_verbatim() {
+        let config = client(r#"cover_host = "cdn.example.com""#).unwrap();
+        assert_eq!(
+            config.cover_request,
+            Some(cover::default_request("cdn.example.com"))
+        );
+        let config =
+            client(r#"cover_request = "GET /app.js HTTP/1.1\r\nHost: cdn.example.com\r\n\r\n""#)
+                .unwrap();
+        assert_eq!(
+            config.cover_request.as_deref(),
+            Some(&b"GET /app.js HTTP/1.1\r\nHost: cdn.example.com\r\n\r\n"[..])
+        );
+        assert!(client(r#"cover_request = "GET /app.js HTTP/1.1\r\n""#).is_err());
+        assert!(client(
+            "cover_host = \"cdn.example.com\"\ncover_request = \"GET / HTTP/1.1\\r\\n\\r\\n\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn cover_response_requires_expect_cover() {
+        let response = r#"cover_response = "HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n""#;
+        assert!(server(response).is_err());
+        let config = server(&format!("expect_cover = true\n{response}")).unwrap();
+        assert_eq!(
+            config.cover_response,
+            b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n"
+        );
+        assert_eq!(server("").unwrap().cover_response, cover::DEFAULT_RESPONSE);
+    }
+
+    #[test]
+    fn close_linger_has_a_lower_bound() {
+        assert!(client("close_linger_ms = 0").is_err());
+        assert!(server("close_linger_ms = 10").is_err());
+        assert_eq!(
+            client("close_linger_ms = 500").unwrap().close_linger,
+            Duration::from_millis(500)
+        );
+        assert_eq!(server("").unwrap().close_linger, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn gzip_config_parses_like_plaintext() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(PLAIN_CONFIG.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.starts_with(GZIP_MAGIC));
+        assert_eq!(parse(compressed), parse(PLAIN_CONFIG.into()));
+    }
+
+    #[test]
+    fn zstd_config_parses_like_plaintext() {
+        let compressed = zstd::encode_all(PLAIN_CONFIG.as_bytes(), 0).unwrap();
+        assert!(compressed.starts_with(ZSTD_MAGIC));
+        assert_eq!(parse(compressed), parse(PLAIN_CONFIG.into()));
+    }
+
+    #[test]
+    fn corrupt_gzip_config_is_rejected() {
+        assert!(decompress(vec![0x1f, 0x8b, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn static_leases_are_read() {
+        let config =
+            server("static_leases = { alice = \"10.0.0.2\", bob = \"10.0.0.3\" }").unwrap();
+        assert_eq!(config.static_leases["alice"], Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(config.static_leases["bob"], Ipv4Addr::new(10, 0, 0, 3));
+    }
+
+    #[test]
+    fn subnet_gateway_must_be_a_static_lease() {
+        let route = "[[subnet_routes]]\nsubnet = \"192.168.10.0/24\"\ngateway = \"10.0.0.50\"";
+        assert!(server(route).is_err());
+        let config = server(&format!(
+            "static_leases = {{ office = \"10.0.0.50\" }}\n{route}"
+        ))
+        .unwrap();
+        assert_eq!(config.subnet_routes[0].gateway, Ipv4Addr::new(10, 0, 0, 50));
+        assert_eq!(config.subnet_routes[0].prefix_len, 24);
+    }
+
+    #[test]
+    fn duplicate_static_leases_are_rejected() {
+        assert!(server("static_leases = { alice = \"10.0.0.2\", bob = \"10.0.0.2\" }").is_err());
+    }
+
+    #[test]
+    fn static_leases_outside_the_subnet_are_rejected() {
+        for addr in ["10.0.1.2", "10.0.0.1", "10.0.0.0", "10.0.0.255"] {
+            let raw = format!("static_leases = {{ alice = \"{addr}\" }}");
+            assert!(server(&raw).is_err(), "{addr} was accepted");
+        }
+    }
+
+    #[test]
+    fn search_domain_is_validated() {
+        let config = server(r#"search_domain = "corp.internal""#).unwrap();
+        assert_eq!(config.search_domain.as_deref(), Some("corp.internal"));
+        assert_eq!(server(r#"search_domain = """#).unwrap().search_domain, None);
+        assert_eq!(server("").unwrap().search_domain, None);
+        for invalid in [
+            "corp..internal",
+            "-corp.internal",
+            "corp.internal.",
+            "corp internal",
+        ] {
+            assert!(server(&format!("search_domain = \"{invalid}\"")).is_err());
+        }
+        assert!(server(&format!("search_domain = \"{}.internal\"", "a".repeat(64))).is_err());
+    }
+
+    fn substituted_server(raw: &str) -> anyhow::Result<ServerConfig> {
+        let lookup = |name: &str| match name {
+            "PORT" => Some("8443".to_owned()),
+            "ADDRESS" => Some("10.1.0.1".to_owned()),
+            "INJECTED" => Some("8443\"\nsubnet_mask = \"255.0.0.0".to_owned()),
+            _ => None,
+        };
+        let mut value: toml::Value = toml::from_str(raw)?;
+        substitute_env(&mut value, &lookup)?;
+        read_server(value.try_into()?)
+    }
+
+    #[test]
+    fn environment_variables_fill_port_and_address() {
+        let config = substituted_server(
+            r#"
+            # the port comes from ${UNSET} in production
+            port = "${PORT}"
+            virtual_address = "${ADDRESS}"
+            subnet_mask = "255.255.255.0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 8443);
+        assert_eq!(config.virtual_address, Ipv4Addr::new(10, 1, 0, 1));
+    }
+
+    #[test]
+    fn missing_environment_variable_is_reported() {
+        let err = substituted_server(r#"port = "${UNSET}""#).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "environment variable UNSET used in config is not set"
+        );
+    }
+
+    #[test]
+    fn escaped_reference_is_kept_literally() {
+        let lookup = |_: &str| Some("8443".to_owned());
+        assert_eq!(
+            expand_env("$${PORT} ${PORT} $$${PORT}", &lookup).unwrap(),
+            "${PORT} 8443 $${PORT}"
+        );
+    }
+
+    #[test]
+    fn substituted_values_cannot_add_settings() {
+        assert!(substituted_server(
+            r#"
+            port = "${INJECTED}"
+            virtual_address = "10.0.0.1"
+            subnet_mask = "255.255.255.0"
+            "#,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use anyhow::{ensure, Context};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+const MAX_HEAD_SIZE: usize = 4096;
+const HEAD_END: &[u8] = b"\r\n\r\n";
+// a real browser sends its request right after connecting
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+const USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0";
+pub const DEFAULT_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
+    Content-Type: application/octet-stream\r\n\
+    Cache-Control: no-store\r\n\
+    Connection: keep-alive\r\n\r\n";
+
+pub fn default_request(host: &str) -> Vec<u8> {
+    format!(
+        "GET / HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         User-Agent: {USER_AGENT}\r\n\
+         Accept: */*\r\n\
+         Connection: keep-alive\r\n\r\n"
+    )
+    .into_bytes()
+}
+
+// configured heads have to pass for HTTP/1.1 on both ends and fit the head limit
+pub fn validate_request(head: &[u8]) -> anyhow::Result<()> {
+    validate_head(head)?;
+    ensure!(
+        is_request(head),
+        "cover request must start with an HTTP/1.1 request line"
+    );
+    Ok(())
+}
+
+pub fn validate_response(head: &[u8]) -> anyhow::Result<()> {
+    validate_head(head)?;
+    ensure!(
+        head.starts_with(b"HTTP/1.1 200 "),
+        "cover response must start with an HTTP/1.1 200 status line"
+    );
+    Ok(())
+}
+
+fn validate_head(head: &[u8]) -> anyhow::Result<()> {
+    ensure!(
+        head.len() <= MAX_HEAD_SIZE,
+        "cover head must be at most {MAX_HEAD_SIZE} bytes"
+    );
+    let end = head
+        .windows(HEAD_END.len())
+        .position(|window| window == HEAD_END);
+    ensure!(
+        end.is_some_and(|end| end + HEAD_END.len() == head.len()),
+        "cover head must end with its only empty line"
+    );
+    Ok(())
+}
+
+fn is_request(head: &[u8]) -> bool {
+    let line = head.split(|&byte| byte == b'\n').next().unwrap_or_default();
+    line.ends_with(b" HTTP/1.1\r") && line.split(|&byte| byte == b' ').count() == 3
+}
+
+pub async fn request<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    request: &[u8],
+) -> anyhow::Result<()> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let head = read_head(stream).await?;
+    ensure!(
+        head.starts_with(b"HTTP/1.1 200 "),
+        "server did not answer the cover request"
+    );
+    Ok(())
+}
+
+pub async fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    response: &[u8],
+) -> anyhow::Result<()> {
+    let head = time::timeout(ACCEPT_TIMEOUT, read_head(stream))
+        .await
+        .context("cover request timed out")??;
+    ensure!(
+        is_request(&head),
+        "connection did not start with a cover request"
+    );
+    stream.write_all(response).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// reads byte by byte so that nothing past the head is consumed from the stream,
+// the proxy handshake reads its CONNECT response with it too
+pub async fn read_head<IO: AsyncRead + Unpin>(stream: &mut IO) -> anyhow::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    while !head.ends_with(HEAD_END) {
+        ensure!(head.len() < MAX_HEAD_SIZE, "HTTP head is too long");
+        let mut byte = [0u8];
+        stream
+            .read_exact(&mut byte)
+            .await
+            .context("connection closed before the end of the HTTP head")?;
+        head.push(byte[0]);
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use super::*;
+
+    async fn connected() -> (Compat<TcpStream>, Compat<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (accepted, connected) = tokio::join!(listener.accept(), TcpStream::connect(address));
+        (connected.unwrap().compat(), accepted.unwrap().0.compat())
+    }
+
+    #[tokio::test]
+    async fn protocol_continues_after_the_decoy_exchange() {
+        let (mut client, mut server) = connected().await;
+        let request = b"POST /upload HTTP/1.1\r\nHost: cdn.example.com\r\n\r\n";
+        let response = b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n";
+        let client_side = async {
+            super::request(&mut client, request).await.unwrap();
+            client.write_all(b"\x16\x03\x01").await.unwrap();
+        };
+        let server_side = async {
+            accept(&mut server, response).await.unwrap();
+            let mut hello = [0u8; 3];
+            server.read_exact(&mut hello).await.unwrap();
+            hello
+        };
+        let ((), hello) = tokio::join!(client_side, server_side);
+        assert_eq!(hello, *b"\x16\x03\x01");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_times_out() {
+        let (_client, mut server) = connected().await;
+        let err = accept(&mut server, DEFAULT_RESPONSE).await.unwrap_err();
+        assert_eq!(err.to_string(), "cover request timed out");
+    }
+
+    #[test]
+    fn configured_heads_are_validated() {
+        assert!(validate_request(&default_request("example.com")).is_ok());
+        assert!(validate_request(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(validate_request(b"hello\r\n\r\n").is_err());
+        assert!(validate_request(b"GET / HTTP/1.1\r\n\r\n\x16\x03\x01\r\n\r\n").is_err());
+        assert!(validate_response(DEFAULT_RESPONSE).is_ok());
+        assert!(validate_response(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+    }
+}
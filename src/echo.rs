@@ -0,0 +1,86 @@
+use std::net::Ipv4Addr;
+
+use etherparse::{Icmpv4Header, Icmpv4Slice, Icmpv4Type, IpNumber, Ipv4Header};
+
+pub fn reflect(packet: &[u8], expected_source: Ipv4Addr) -> Option<Box<[u8]>> {
+    let (mut header, rest) = Ipv4Header::from_slice(packet).ok()?;
+    if Ipv4Addr::from(header.source) != expected_source {
+        return None;
+    }
+    let payload = rest.get(..usize::from(header.payload_len().ok()?))?;
+
+    (header.source, header.destination) = (header.destination, header.source);
+    header.header_checksum = header.calc_header_checksum();
+
+    let mut reply = header.to_bytes().to_vec();
+    match echo_reply(&header, payload) {
+        Some(icmp_header) => {
+            reply.extend_from_slice(&icmp_header.to_bytes());
+            reply.extend_from_slice(&payload[icmp_header.header_len()..]);
+        }
+        // swapping the addresses keeps TCP and UDP pseudo-header checksums valid
+        None => reply.extend_from_slice(payload),
+    }
+    Some(reply.into_boxed_slice())
+}
+
+fn echo_reply(header: &Ipv4Header, payload: &[u8]) -> Option<Icmpv4Header> {
+    // only the first fragment carries the ICMP header, and its checksum covers all of them
+    if header.protocol != IpNumber::ICMP || header.is_fragmenting_payload() {
+        return None;
+    }
+    let icmp = Icmpv4Slice::from_slice(payload).ok()?;
+    let Icmpv4Type::EchoRequest(echo) = icmp.icmp_type() else {
+        return None;
+    };
+    Some(Icmpv4Header::with_checksum(
+        Icmpv4Type::EchoReply(echo),
+        icmp.payload(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::{Icmpv4Slice, Ipv4HeaderSlice, PacketBuilder};
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    fn echo_request(source: Ipv4Addr) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4(source.octets(), REMOTE.octets(), 64)
+            .icmpv4_echo_request(0x1234, 7);
+        let mut packet = Vec::new();
+        builder.write(&mut packet, b"ping payload").unwrap();
+        packet
+    }
+
+    #[test]
+    fn echo_request_is_answered_with_a_reply() {
+        let reply = reflect(&echo_request(CLIENT), CLIENT).unwrap();
+
+        let header = Ipv4HeaderSlice::from_slice(&reply).unwrap();
+        assert_eq!(header.source_addr(), REMOTE);
+        assert_eq!(header.destination_addr(), CLIENT);
+        assert_eq!(
+            header.header_checksum(),
+            header.to_header().calc_header_checksum()
+        );
+
+        let icmp = Icmpv4Slice::from_slice(&reply[header.slice().len()..]).unwrap();
+        let Icmpv4Type::EchoReply(echo) = icmp.icmp_type() else {
+            panic!("expected an echo reply, got {:?}", icmp.icmp_type());
+        };
+        assert_eq!((echo.id, echo.seq), (0x1234, 7));
+        assert_eq!(icmp.payload(), b"ping payload");
+        let expected = Icmpv4Header::with_checksum(icmp.icmp_type(), icmp.payload());
+        assert_eq!(icmp.checksum(), expected.checksum);
+    }
+
+    #[test]
+    fn packet_from_another_source_is_not_reflected() {
+        let spoofed = Ipv4Addr::new(10, 0, 0, 3);
+        assert_eq!(reflect(&echo_request(spoofed), CLIENT), None);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use serde::Serialize;
+use tokio::{
+    sync::{broadcast, watch},
+    time,
+};
+
+use crate::listener::{is_resource_exhaustion, ACCEPT_BACKOFF};
+
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        peer: String,
+    },
+    LeaseGranted {
+        peer: String,
+        common_name: String,
+        address: Ipv4Addr,
+    },
+    LeaseRevoked {
+        common_name: String,
+        address: Ipv4Addr,
+        reason: RevokeReason,
+    },
+    Error {
+        peer: String,
+        message: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevokeReason {
+    Disconnected,
+    SessionExpired,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp_ms: u128,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub struct EventSink {
+    sender: broadcast::Sender<Arc<str>>,
+}
+
+impl EventSink {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        match serde_json::to_string(&Record {
+            timestamp_ms,
+            event: &event,
+        }) {
+            Ok(mut line) => {
+                line.push('\n');
+                _ = self.sender.send(line.into());
+            }
+            Err(e) => warn!("could not serialize event: {e}"),
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn serve(
+        &self,
+        listener: crate::listener::UnixSocketListener,
+        mut stop_token: watch::Receiver<bool>,
+    ) {
+        loop {
+            let accept_res = tokio::select! {
+                accept_res = listener.accept() => accept_res,
+                _ = stop_token.wait_for(|&stop| stop) => return,
+            };
+            let socket = match accept_res {
+                Ok(socket) => socket,
+                Err(e) if is_resource_exhaustion(&e) => {
+                    error!("could not accept event consumer, backing off: {e}");
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("could not accept event consumer: {e}");
+                    continue;
+                }
+            };
+            tokio::spawn(stream_events(
+                socket,
+                self.sender.subscribe(),
+                stop_token.clone(),
+            ));
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn stream_events(
+    socket: tokio::net::UnixStream,
+    mut events: broadcast::Receiver<Arc<str>>,
+    mut stop_token: watch::Receiver<bool>,
+) {
+    use futures::AsyncWriteExt;
+    use tokio_util::compat::TokioAsyncWriteCompatExt;
+
+    let mut socket = socket.compat_write();
+    loop {
+        let line = tokio::select! {
+            event = events.recv() => match event {
+                Ok(line) => line,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("event consumer fell behind, skipped {skipped} events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = stop_token.wait_for(|&stop| stop) => return,
+        };
+        if socket.write_all(line.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use futures::AsyncReadExt;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+    use crate::listener::UnixSocketListener;
+
+    #[test]
+    fn revoked_lease_carries_its_reason() {
+        let record = serde_json::to_value(Event::LeaseRevoked {
+            common_name: "alice".into(),
+            address: Ipv4Addr::new(10, 0, 0, 2),
+            reason: RevokeReason::SessionExpired,
+        })
+        .unwrap();
+        assert_eq!(record["event"], "lease_revoked");
+        assert_eq!(record["reason"], "session_expired");
+    }
+
+    #[tokio::test]
+    async fn connected_client_is_streamed_as_json() {
+        let path =
+            std::env::temp_dir().join(format!("opaque-vpn-{}-events.sock", std::process::id()));
+        let listener = UnixSocketListener::bind(&path).unwrap();
+        let sink = Arc::new(EventSink::new());
+        let (_stop_sender, stop_receiver) = watch::channel(false);
+        let server = tokio::spawn({
+            let sink = sink.clone();
+            async move { sink.serve(listener, stop_receiver).await }
+        });
+
+        let mut consumer = tokio::net::UnixStream::connect(&path)
+            .await
+            .unwrap()
+            .compat();
+        // the consumer is subscribed once the serve task has accepted it
+        while sink.sender.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        sink.emit(Event::Connected {
+            peer: "192.0.2.1:50000".into(),
+        });
+
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while line.last() != Some(&b'\n') {
+            consumer.read_exact(&mut byte).await.unwrap();
+            line.push(byte[0]);
+        }
+        let record: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(record["event"], "connected");
+        assert_eq!(record["peer"], "192.0.2.1:50000");
+        assert!(record["timestamp_ms"].as_u64().is_some_and(|ms| ms > 0));
+        server.abort();
+    }
+}
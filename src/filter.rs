@@ -0,0 +1,48 @@
+use std::{collections::HashSet, net::Ipv4Addr};
+
+use etherparse::Ipv4HeaderSlice;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    FromClient,
+    FromTun,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FilterVerdict {
+    Allow,
+    Drop,
+}
+
+pub trait PacketFilter: Send + Sync {
+    fn allow(&self, packet: &[u8], direction: Direction) -> FilterVerdict;
+}
+
+pub struct AllowAll;
+
+impl PacketFilter for AllowAll {
+    fn allow(&self, _packet: &[u8], _direction: Direction) -> FilterVerdict {
+        FilterVerdict::Allow
+    }
+}
+
+pub struct DestinationBlocklist {
+    blocked: HashSet<Ipv4Addr>,
+}
+
+impl DestinationBlocklist {
+    pub fn new(blocked: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        Self {
+            blocked: blocked.into_iter().collect(),
+        }
+    }
+}
+
+impl PacketFilter for DestinationBlocklist {
+    fn allow(&self, packet: &[u8], _direction: Direction) -> FilterVerdict {
+        match Ipv4HeaderSlice::from_slice(packet) {
+            Ok(header) if self.blocked.contains(&header.destination_addr()) => FilterVerdict::Drop,
+            _ => FilterVerdict::Allow,
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::{collections::BTreeSet, net::Ipv4Addr};
 
 pub struct IpManager {
     blocked: BTreeSet<u32>,
+    reserved: BTreeSet<u32>,
     subnet: u32,
     netmask: u32,
     min_free: u32,
@@ -9,17 +10,35 @@ pub struct IpManager {
 }
 
 impl IpManager {
-    pub fn new(subnet: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+    pub fn new(gateway: Ipv4Addr, netmask: Ipv4Addr) -> Self {
         let netmask_bits = netmask.to_bits();
         let subnet_size = 1u32 << netmask_bits.count_zeros();
-        let subnet_bits = subnet.to_bits() & netmask_bits;
-        Self {
+        let subnet_bits = gateway.to_bits() & netmask_bits;
+        let mut manager = Self {
             blocked: BTreeSet::new(),
+            reserved: BTreeSet::new(),
             subnet: subnet_bits,
             netmask: netmask_bits,
             min_free: 0,
             subnet_size,
+        };
+
+        let mut reserved = vec![manager.compress_address(gateway.to_bits())];
+        // /31 and /32 have no network or broadcast address
+        if subnet_size >= 4 {
+            reserved.extend([0, subnet_size - 1]);
+        }
+        for index in reserved {
+            manager.reserved.insert(index);
+            manager.block(manager.expand_bits(index));
         }
+        manager
+    }
+
+    pub fn is_assignable(&self, addr: Ipv4Addr) -> bool {
+        let addr_bits = addr.to_bits();
+        (addr_bits & self.netmask) == self.subnet
+            && !self.reserved.contains(&self.compress_address(addr_bits))
     }
 
     pub fn block(&mut self, addr: Ipv4Addr) {
@@ -42,6 +61,9 @@ impl IpManager {
         }
 
         let to_unblock = self.compress_address(addr_bits);
+        if self.reserved.contains(&to_unblock) {
+            return;
+        }
         if self.blocked.remove(&to_unblock) && to_unblock < self.min_free {
             self.min_free = to_unblock;
         }
@@ -55,6 +77,36 @@ impl IpManager {
         }
     }
 
+    pub fn get_free_near(&self, hint: u64) -> Option<Ipv4Addr> {
+        let start = (hint % u64::from(self.subnet_size)) as u32;
+        self.next_free_from(start)
+            .or_else(|| self.next_free_from(0))
+            .map(|index| self.expand_bits(index))
+    }
+
+    pub fn get_free_highest(&self) -> Option<Ipv4Addr> {
+        let mut candidate = self.subnet_size.checked_sub(1)?;
+        for &blocked in self.blocked.iter().rev() {
+            if blocked != candidate {
+                break;
+            }
+            candidate = candidate.checked_sub(1)?;
+        }
+        Some(self.expand_bits(candidate))
+    }
+
+    // skips the run of blocked indexes starting at start without touching the free ones
+    fn next_free_from(&self, start: u32) -> Option<u32> {
+        let mut candidate = start;
+        for &blocked in self.blocked.range(start..) {
+            if blocked != candidate {
+                break;
+            }
+            candidate += 1;
+        }
+        (candidate < self.subnet_size).then_some(candidate)
+    }
+
     fn compress_address(&self, addr_bits: u32) -> u32 {
         let mut mask = !self.subnet;
         let mut offset = 1u32;
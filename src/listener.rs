@@ -0,0 +1,210 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+use anyhow::bail;
+use anyhow::Context;
+use futures::future::Future;
+#[cfg(unix)]
+use log::warn;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+// how long accept loops pause when the process runs out of descriptors or memory
+pub const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+pub enum Peer {
+    Tcp(SocketAddr),
+    Local(String),
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Local(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Local(description) => f.write_str(description),
+        }
+    }
+}
+
+pub trait Listener: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, Peer)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, Peer)> {
+        let (socket, addr) = TcpListener::accept(self).await?;
+        Ok((socket, Peer::Tcp(addr)))
+    }
+}
+
+// the socket file is removed again when the listener is dropped
+#[cfg(unix)]
+pub struct UnixSocketListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    pub fn bind(path: &Path) -> anyhow::Result<Self> {
+        // a stale socket left by a crashed server is replaced, anything else is left alone
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)
+                .with_context(|| format!("could not remove {}", path.display()))?,
+            Ok(_) => bail!("{} exists and is not a socket", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("could not stat {}", path.display())),
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("could not bind unix socket {}", path.display()))?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<tokio::net::UnixStream> {
+        let (socket, _) = self.listener.accept().await?;
+        Ok(socket)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let is_socket =
+            fs::symlink_metadata(&self.path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if is_socket {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("could not remove {}: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocketListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> io::Result<(tokio::net::UnixStream, Peer)> {
+        let socket = UnixSocketListener::accept(self).await?;
+        let path = self.path.display();
+        Ok((socket, Peer::Local(format!("unix socket {path}"))))
+    }
+}
+
+// retrying these at once only spins until some other connection is closed
+#[cfg(unix)]
+pub fn is_resource_exhaustion(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+#[cfg(not(unix))]
+pub fn is_resource_exhaustion(_err: &io::Error) -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub fn systemd_listener() -> anyhow::Result<Option<TcpListener>> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let Ok(listen_pid) = std::env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    if listen_pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let listen_fds: u32 = std::env::var("LISTEN_FDS")
+        .context("LISTEN_PID is set without LISTEN_FDS")?
+        .parse()
+        .context("could not parse LISTEN_FDS")?;
+    if listen_fds == 0 {
+        return Ok(None);
+    }
+
+    // SAFETY: systemd hands the process ownership of the sockets starting at SD_LISTEN_FDS_START
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener
+        .set_nonblocking(true)
+        .context("could not configure inherited socket")?;
+    TcpListener::from_std(listener)
+        .map(Some)
+        .context("inherited socket is not a TCP listener")
+}
+
+#[cfg(not(unix))]
+pub fn systemd_listener() -> anyhow::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("opaque-vpn-{}-{name}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn socket_file_lives_as_long_as_the_listener() {
+        let path = temp_path("lifetime");
+        let listener = UnixSocketListener::bind(&path).unwrap();
+        let (accepted, connected) = tokio::join!(
+            Listener::accept(&listener),
+            tokio::net::UnixStream::connect(&path)
+        );
+        connected.unwrap();
+        let (_, peer) = accepted.unwrap();
+        assert_eq!(peer.to_string(), format!("unix socket {}", path.display()));
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stale_socket_is_replaced() {
+        let path = temp_path("stale");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let _listener = UnixSocketListener::bind(&path).unwrap();
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn regular_file_is_not_removed() {
+        let path = temp_path("regular");
+        fs::write(&path, "keep me\n").unwrap();
+        assert!(UnixSocketListener::bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me\n");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use anyhow::Context;
+use log::{Log, Metadata, Record};
+
+use crate::config::LogConfig;
+
+// the log settings come from the config, but loading it should already be logged,
+// so the process starts with a default logger that is replaced once the config is read
+pub struct ReplaceableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+impl ReplaceableLogger {
+    pub fn install(logger: env_logger::Logger) -> anyhow::Result<&'static Self> {
+        let logger: &'static Self = Box::leak(Box::new(Self::new(logger)));
+        log::set_logger(logger).context("could not install logger")?;
+        Ok(logger)
+    }
+
+    fn new(logger: env_logger::Logger) -> Self {
+        log::set_max_level(logger.filter());
+        Self {
+            inner: logger.into(),
+        }
+    }
+
+    pub fn replace(&self, logger: env_logger::Logger) {
+        log::set_max_level(logger.filter());
+        *self.inner.write().unwrap() = logger;
+    }
+}
+
+impl Log for ReplaceableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush();
+    }
+}
+
+pub struct RotatingLogFile {
+    config: LogConfig,
+    file: File,
+    size: u64,
+    reopen: Arc<AtomicBool>,
+}
+
+impl RotatingLogFile {
+    pub fn open(config: LogConfig) -> anyhow::Result<Self> {
+        let file = open_append(&config.file)
+            .with_context(|| format!("could not open log file {}", config.file.display()))?;
+        let size = file.metadata()?.len();
+        let reopen = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, reopen.clone())
+            .context("could not register SIGHUP handler")?;
+
+        Ok(Self {
+            config,
+            file,
+            size,
+            reopen,
+        })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_append(&self.config.file)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.config.keep == 0 {
+            fs::remove_file(&self.config.file)?;
+        } else {
+            for index in (1..self.config.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.config.file, self.rotated_path(1))?;
+        }
+        self.reopen()
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.config.file.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            self.reopen()?;
+        }
+        if let Some(max_size) = self.config.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use env_logger::Target;
+    use log::Level;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        fn logger(&self) -> env_logger::Logger {
+            env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Info)
+                .format(|f, record| writeln!(f, "{}", record.args()))
+                .target(Target::Pipe(Box::new(self.clone())))
+                .build()
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn log(logger: &ReplaceableLogger, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn records_go_to_the_current_logger() {
+        let (early, late) = (Capture::default(), Capture::default());
+        let logger = ReplaceableLogger::new(early.logger());
+        log(&logger, "while loading the config");
+        logger.replace(late.logger());
+        log(&logger, "after loading the config");
+        assert_eq!(early.text(), "while loading the config\n");
+        assert_eq!(late.text(), "after loading the config\n");
+    }
+
+    #[test]
+    fn log_file_rotates_at_max_size() {
+        let dir = std::env::temp_dir().join(format!("opaque-vpn-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("vpn.log");
+        let mut log_file = RotatingLogFile::open(LogConfig {
+            file: file.clone(),
+            max_size: Some(10),
+            keep: 2,
+        })
+        .unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log_file.write_all(line.as_bytes()).unwrap();
+        }
+        log_file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(log_file.rotated_path(1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(log_file.rotated_path(2)).unwrap(),
+            "second\n"
+        );
+        assert!(!log_file.rotated_path(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,49 +1,140 @@
-#![feature(ip_from)]
-
+mod admission;
+mod affinity;
 mod client;
+mod clock_skew;
 mod common;
 mod config;
+mod cover;
+mod echo;
+mod events;
+mod filter;
 mod ip_manager;
+mod listener;
+mod log_file;
 mod packet_stream;
+mod pid_file;
+mod prefix_table;
 mod protocol;
+mod proxy;
+mod rate_limit;
+mod resolver;
 mod routing;
+mod sealed;
 mod server;
+mod system_dns;
+mod system_route;
+mod undo_script;
+mod watchdog;
+
+use std::io::Read;
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use env_logger::Target;
 use log::error;
-use tokio::runtime::Builder;
+use tokio::{runtime::Builder, sync::watch};
 
 use crate::{
     client::Client,
-    config::{load_config, Mode},
+    config::{load_config, LogConfig, Mode},
+    log_file::{ReplaceableLogger, RotatingLogFile},
+    pid_file::PidFile,
     server::Server,
 };
 
+enum Command {
+    Run(String),
+    TestConnect(String),
+    Seal,
+}
+
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    let command = parse_args()?;
+    let config_path = match &command {
+        Command::Run(path) | Command::TestConnect(path) => path,
+        Command::Seal => return seal_stdin(),
+    };
+    let logger = ReplaceableLogger::install(env_logger::Builder::from_default_env().build())?;
+    let config = load_config(config_path)?;
+    if let Some(log_config) = config.log {
+        logger.replace(file_logger(log_config)?);
+    }
 
-    let config = load_config(std::env::args().nth(1).context("no config file provided")?)?;
     let runtime = Builder::new_current_thread()
         .enable_io()
+        .enable_time()
         .build()
         .context("could not create runtime")?;
+    // the runtime is single-threaded, so pinning this thread pins all packet processing
+    if !config.cpu_affinity.is_empty() {
+        affinity::pin_current_thread(&config.cpu_affinity)?;
+    }
+
+    if let Command::TestConnect(_) = command {
+        let client_config = match config.mode {
+            Mode::Client(client_config) | Mode::Loopback(_, client_config) => client_config,
+            Mode::Server(_) => bail!("test-connect requires a config with a 'client' section"),
+        };
+        return runtime.block_on(Client::try_new(client_config, config.tls)?.test_connect());
+    }
+
+    let _pid_file = config.pid_file.map(PidFile::create).transpose()?;
 
     match config.mode {
         Mode::Client(client_config) => {
             let client = Client::try_new(client_config, config.tls)?;
-            let stop_sender = client.stop_sender();
-            ctrlc::set_handler(move || {
-                if let Err(err) = stop_sender.send(true) {
-                    error!("could not stop: {err}");
-                }
-            })
-            .context("could not set Ctrl-C handler")?;
+            set_stop_handler(vec![client.stop_sender()])?;
             runtime.block_on(client.run())
         }
         Mode::Server(server_config) => runtime.block_on(async move {
-            Server::try_new(server_config, config.tls)
-                .map(|server| server.run())?
-                .await
+            let server = Server::try_new(*server_config, config.tls).await?;
+            set_stop_handler(vec![server.stop_sender()])?;
+            server.run().await
+        }),
+        Mode::Loopback(server_config, client_config) => runtime.block_on(async move {
+            let server = Server::try_new(*server_config, config.tls.clone()).await?;
+            let client = Client::try_new(client_config, config.tls)?;
+            set_stop_handler(vec![server.stop_sender(), client.stop_sender()])?;
+            tokio::try_join!(server.run(), client.run())?;
+            Ok(())
         }),
     }
 }
+
+fn parse_args() -> anyhow::Result<Command> {
+    let mut args = std::env::args().skip(1);
+    let first = args.next().context("no config file provided")?;
+    if first == "test-connect" {
+        let path = args.next().context("test-connect requires a config file")?;
+        Ok(Command::TestConnect(path))
+    } else if first == "seal" {
+        Ok(Command::Seal)
+    } else {
+        Ok(Command::Run(first))
+    }
+}
+
+fn seal_stdin() -> anyhow::Result<()> {
+    let mut plaintext = String::new();
+    std::io::stdin()
+        .read_to_string(&mut plaintext)
+        .context("could not read value to seal from stdin")?;
+    println!("{}", sealed::seal(plaintext.as_bytes())?);
+    Ok(())
+}
+
+fn file_logger(log_config: LogConfig) -> anyhow::Result<env_logger::Logger> {
+    Ok(env_logger::Builder::from_default_env()
+        .target(Target::Pipe(Box::new(RotatingLogFile::open(log_config)?)))
+        .build())
+}
+
+fn set_stop_handler(stop_senders: Vec<watch::Sender<bool>>) -> anyhow::Result<()> {
+    ctrlc::set_handler(move || {
+        for stop_sender in &stop_senders {
+            if let Err(err) = stop_sender.send(true) {
+                error!("could not stop: {err}");
+            }
+        }
+    })
+    .context("could not set Ctrl-C handler")
+}
@@ -0,0 +1,106 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::io;
+use log::debug;
+use tokio::{sync::Mutex, task::AbortHandle, time};
+
+use crate::packet_stream::{PacketReceiver, PacketSender};
+
+// zero-length frames never carry a packet, so they serve as keepalives
+const KEEPALIVE: &[u8] = &[];
+
+pub struct KeepaliveSender<S: PacketSender> {
+    inner: Arc<Mutex<S>>,
+    active: Arc<AtomicBool>,
+    task: Option<AbortHandle>,
+}
+
+impl<S: PacketSender + 'static> KeepaliveSender<S> {
+    pub fn new(inner: S, interval: Option<Duration>) -> Self {
+        let inner = Arc::new(Mutex::new(inner));
+        let active = Arc::new(AtomicBool::new(false));
+        let task = interval.map(|interval| {
+            tokio::spawn(send_keepalives(inner.clone(), active.clone(), interval)).abort_handle()
+        });
+        Self {
+            inner,
+            active,
+            task,
+        }
+    }
+}
+
+impl<S: PacketSender> PacketSender for KeepaliveSender<S> {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.active.store(true, Ordering::Relaxed);
+        self.inner.lock().await.send(packet).await
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.inner.lock().await.close().await
+    }
+}
+
+impl<S: PacketSender> Drop for KeepaliveSender<S> {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+// a keepalive goes out once a whole interval passed without a packet
+async fn send_keepalives<S: PacketSender>(
+    sender: Arc<Mutex<S>>,
+    active: Arc<AtomicBool>,
+    interval: Duration,
+) {
+    let mut interval = time::interval_at(time::Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        if active.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        // a failed write surfaces on the next packet, or as silence on the peer's side
+        if let Err(e) = sender.lock().await.send(KEEPALIVE).await {
+            debug!("could not send keepalive: {e}");
+            return;
+        }
+    }
+}
+
+pub struct KeepaliveReceiver<R: PacketReceiver> {
+    inner: R,
+    timeout: Option<Duration>,
+}
+
+impl<R: PacketReceiver> KeepaliveReceiver<R> {
+    pub fn new(inner: R, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<R: PacketReceiver> PacketReceiver for KeepaliveReceiver<R> {
+    async fn receive(&mut self) -> io::Result<Box<[u8]>> {
+        let Some(timeout) = self.timeout else {
+            return self.inner.receive().await;
+        };
+        time::timeout(timeout, self.inner.receive())
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "peer sent nothing within the keepalive timeout",
+                ))
+            })
+    }
+}
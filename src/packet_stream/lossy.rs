@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use futures::io;
+use tokio::time;
+
+use crate::{
+    common::SplitMix64,
+    packet_stream::{PacketReceiver, PacketSender},
+};
+
+// simulates a bad link with a seeded generator, so that every run loses the same packets
+#[derive(Clone, Copy)]
+pub struct Loss {
+    pub seed: u64,
+    pub drop_rate: f64,
+    // a reordered packet is held back until the next one has been sent
+    pub reorder_rate: f64,
+    pub delay: Duration,
+}
+
+pub struct LossySender<S: PacketSender> {
+    inner: S,
+    loss: Loss,
+    rng: SplitMix64,
+    held: Option<Box<[u8]>>,
+}
+
+impl<S: PacketSender> LossySender<S> {
+    pub fn new(inner: S, loss: Loss) -> Self {
+        Self {
+            inner,
+            loss,
+            rng: SplitMix64::new(loss.seed),
+            held: None,
+        }
+    }
+}
+
+impl<S: PacketSender> PacketSender for LossySender<S> {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.rng.next_f64() < self.loss.drop_rate {
+            return Ok(());
+        }
+        if self.held.is_none() && self.rng.next_f64() < self.loss.reorder_rate {
+            self.held = Some(packet.into());
+            return Ok(());
+        }
+        time::sleep(self.loss.delay).await;
+        self.inner.send(packet).await?;
+        if let Some(held) = self.held.take() {
+            self.inner.send(&held).await?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+pub struct LossyReceiver<R: PacketReceiver> {
+    inner: R,
+    loss: Loss,
+    rng: SplitMix64,
+}
+
+impl<R: PacketReceiver> LossyReceiver<R> {
+    pub fn new(inner: R, loss: Loss) -> Self {
+        Self {
+            inner,
+            loss,
+            rng: SplitMix64::new(loss.seed),
+        }
+    }
+}
+
+impl<R: PacketReceiver> PacketReceiver for LossyReceiver<R> {
+    async fn receive(&mut self) -> io::Result<Box<[u8]>> {
+        loop {
+            let packet = self.inner.receive().await?;
+            if self.rng.next_f64() >= self.loss.drop_rate {
+                time::sleep(self.loss.delay).await;
+                return Ok(packet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_stream::{mock, KeepaliveReceiver, KeepaliveSender};
+
+    const INTERVAL: Duration = Duration::from_secs(10);
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn loss(drop_rate: f64, reorder_rate: f64) -> Loss {
+        Loss {
+            seed: 7,
+            drop_rate,
+            reorder_rate,
+            delay: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heavy_loss_is_detected_as_a_dead_peer() {
+        let (sender, receiver) = mock::channel();
+        let _keepalives =
+            KeepaliveSender::new(LossySender::new(sender, loss(0.9, 0.0)), Some(INTERVAL));
+        let mut receiver = KeepaliveReceiver::new(receiver, Some(TIMEOUT));
+        let err = loop {
+            if let Err(e) = receiver.receive().await {
+                break e;
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalives_hold_a_lossless_link_open() {
+        let (sender, receiver) = mock::channel();
+        let _keepalives =
+            KeepaliveSender::new(LossySender::new(sender, loss(0.0, 0.0)), Some(INTERVAL));
+        let mut receiver = KeepaliveReceiver::new(receiver, Some(TIMEOUT));
+        for _ in 0..20 {
+            assert!(receiver.receive().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn same_seed_loses_the_same_packets() {
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let (sender, mut receiver) = mock::channel();
+            let mut sender = LossySender::new(sender, loss(0.3, 0.2));
+            for packet in 0..50u8 {
+                sender.send(&[packet]).await.unwrap();
+            }
+            runs.push(std::iter::from_fn(|| receiver.try_receive()).collect::<Vec<_>>());
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].len() < 50);
+        assert!(runs[0].windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[tokio::test]
+    async fn receiver_drops_at_the_configured_rate() {
+        let (mut sender, receiver) = mock::channel();
+        for packet in 0..100u8 {
+            sender.send(&[packet]).await.unwrap();
+        }
+        sender.close().await.unwrap();
+        let mut receiver = LossyReceiver::new(receiver, loss(0.5, 0.0));
+        let mut received = 0;
+        while receiver.receive().await.is_ok() {
+            received += 1;
+        }
+        assert!((30..70).contains(&received));
+    }
+}
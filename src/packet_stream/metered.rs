@@ -0,0 +1,76 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use futures::io;
+
+use crate::packet_stream::{PacketReceiver, PacketSender};
+
+#[derive(Default)]
+pub struct TrafficCounter {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct TrafficSnapshot {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl TrafficCounter {
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record(&self, packet_size: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(packet_size as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct MeteredSender<S: PacketSender> {
+    inner: S,
+    counter: Arc<TrafficCounter>,
+}
+
+impl<S: PacketSender> MeteredSender<S> {
+    pub fn new(inner: S, counter: Arc<TrafficCounter>) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl<S: PacketSender> PacketSender for MeteredSender<S> {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.inner.send(packet).await?;
+        self.counter.record(packet.len());
+        Ok(())
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.inner.close().await
+    }
+}
+
+pub struct MeteredReceiver<R: PacketReceiver> {
+    inner: R,
+    counter: Arc<TrafficCounter>,
+}
+
+impl<R: PacketReceiver> MeteredReceiver<R> {
+    pub fn new(inner: R, counter: Arc<TrafficCounter>) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl<R: PacketReceiver> PacketReceiver for MeteredReceiver<R> {
+    async fn receive(&mut self) -> io::Result<Box<[u8]>> {
+        let packet = self.inner.receive().await?;
+        self.counter.record(packet.len());
+        Ok(packet)
+    }
+}
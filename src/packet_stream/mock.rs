@@ -0,0 +1,65 @@
+use futures::{future, io};
+use tokio::sync::mpsc;
+
+use crate::packet_stream::{PacketReceiver, PacketSender};
+
+pub fn channel() -> (MockSender, MockReceiver) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (
+        MockSender {
+            packets: Some(sender),
+        },
+        MockReceiver { packets: receiver },
+    )
+}
+
+// closing the sender reads as the end of the stream on the receiver
+pub struct MockSender {
+    packets: Option<mpsc::UnboundedSender<Box<[u8]>>>,
+}
+
+impl PacketSender for MockSender {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.packets
+            .as_ref()
+            .and_then(|packets| packets.send(packet.into()).ok())
+            .ok_or(io::ErrorKind::BrokenPipe.into())
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.packets = None;
+        Ok(())
+    }
+}
+
+pub struct MockReceiver {
+    packets: mpsc::UnboundedReceiver<Box<[u8]>>,
+}
+
+impl MockReceiver {
+    pub fn try_receive(&mut self) -> Option<Box<[u8]>> {
+        self.packets.try_recv().ok()
+    }
+}
+
+impl PacketReceiver for MockReceiver {
+    async fn receive(&mut self) -> io::Result<Box<[u8]>> {
+        self.packets
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+// a peer that stopped reading, every send blocks forever
+pub struct StalledSender;
+
+impl PacketSender for StalledSender {
+    async fn send(&mut self, _packet: &[u8]) -> io::Result<()> {
+        future::pending().await
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        future::pending().await
+    }
+}
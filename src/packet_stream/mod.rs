@@ -1,10 +1,26 @@
 mod dyn_compat;
+mod keepalive;
+#[cfg(test)]
+pub mod lossy;
+mod metered;
+#[cfg(test)]
+pub mod mock;
+mod replay;
 mod tagged;
+mod timeout;
 mod traits;
 mod tun;
+mod udp;
 mod util;
 
 pub use dyn_compat::DynPacketSender;
-pub use tagged::{TaggedPacketReceiver, TaggedPacketSender};
+pub use keepalive::{KeepaliveReceiver, KeepaliveSender};
+pub use metered::{MeteredReceiver, MeteredSender, TrafficCounter, TrafficSnapshot};
+pub use tagged::{TaggedPacketReceiver, TaggedPacketSender, LENGTH_PREFIX_SIZE};
+pub use timeout::TimeoutSender;
 pub use traits::{PacketReceiver, PacketSender};
 pub use tun::{TunReceiver, TunSender};
+pub use udp::{
+    DatagramKeys, UdpEndpoint, UdpPacketReceiver, UdpPacketSender, DATAGRAM_IDLE_TIMEOUT,
+    DATAGRAM_KEEPALIVE, DATAGRAM_OVERHEAD,
+};
@@ -0,0 +1,105 @@
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+// counters start at 1, so marking 0 as seen from the start rejects it
+pub struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self {
+            highest: 0,
+            seen: 1,
+        }
+    }
+}
+
+impl ReplayWindow {
+    pub fn is_fresh(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            return true;
+        }
+        let offset = self.highest - counter;
+        offset < REPLAY_WINDOW_SIZE && self.seen & (1 << offset) == 0
+    }
+
+    pub fn mark(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift < REPLAY_WINDOW_SIZE {
+                self.seen << shift
+            } else {
+                0
+            };
+            self.highest = counter;
+            self.seen |= 1;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(window: &mut ReplayWindow, counter: u64) -> bool {
+        let fresh = window.is_fresh(counter);
+        if fresh {
+            window.mark(counter);
+        }
+        fresh
+    }
+
+    #[test]
+    fn in_order_counters_pass() {
+        let mut window = ReplayWindow::default();
+        assert!((1..=200).all(|counter| accept(&mut window, counter)));
+    }
+
+    #[test]
+    fn zero_is_rejected() {
+        let mut window = ReplayWindow::default();
+        assert!(!accept(&mut window, 0));
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 1));
+        assert!(accept(&mut window, 2));
+        assert!(!accept(&mut window, 2));
+        assert!(!accept(&mut window, 1));
+        assert!(accept(&mut window, 3));
+    }
+
+    #[test]
+    fn reordered_counters_inside_the_window_pass_once() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 10));
+        assert!(accept(&mut window, 7));
+        assert!(accept(&mut window, 9));
+        assert!(!accept(&mut window, 7));
+        assert!(accept(&mut window, 8));
+        assert!(!accept(&mut window, 10));
+    }
+
+    #[test]
+    fn counters_behind_the_window_are_rejected() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 100));
+        assert!(!accept(&mut window, 100 - REPLAY_WINDOW_SIZE));
+        assert!(accept(&mut window, 100 - REPLAY_WINDOW_SIZE + 1));
+    }
+
+    #[test]
+    fn large_jump_clears_the_window() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 5));
+        assert!(accept(&mut window, 5 + 2 * REPLAY_WINDOW_SIZE));
+        assert!(!accept(&mut window, 5));
+        assert!(!accept(&mut window, 5 + 2 * REPLAY_WINDOW_SIZE));
+        assert!(accept(&mut window, 4 + 2 * REPLAY_WINDOW_SIZE));
+    }
+}
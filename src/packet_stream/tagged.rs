@@ -5,6 +5,8 @@ use crate::packet_stream::{
     PacketReceiver, PacketSender,
 };
 
+pub const LENGTH_PREFIX_SIZE: u16 = size_of::<u16>() as u16;
+
 pub struct TaggedPacketReceiver<IO: Send> {
     stream: IO,
 }
@@ -63,6 +65,31 @@ impl<IO: AsyncWrite + Unpin + Send> PacketSender for TaggedPacketSender<IO> {
     }
 
     async fn close(&mut self) -> io::Result<()> {
+        self.stream.flush().await?;
         self.stream.close().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn zero_length_frame_round_trips_as_empty_packet() {
+        let mut sender = TaggedPacketSender::new(Cursor::new(Vec::new()));
+        sender.send(&[1, 2, 3]).await.unwrap();
+        sender.send(&[]).await.unwrap();
+        sender.send(&[4]).await.unwrap();
+        let bytes = sender.stream.into_inner();
+        assert_eq!(bytes, [3, 0, 1, 2, 3, 0, 0, 1, 0, 4]);
+
+        let mut receiver = TaggedPacketReceiver::new(Cursor::new(bytes));
+        assert_eq!(*receiver.receive().await.unwrap(), [1, 2, 3]);
+        assert!(receiver.receive().await.unwrap().is_empty());
+        assert_eq!(*receiver.receive().await.unwrap(), [4]);
+        let eof = receiver.receive().await.unwrap_err();
+        assert_eq!(eof.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
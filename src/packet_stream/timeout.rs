@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use futures::io;
+use tokio::time;
+
+use crate::packet_stream::PacketSender;
+
+// a timed out send may have written part of a frame, so the sender is unusable afterwards
+pub struct TimeoutSender<S: PacketSender> {
+    inner: S,
+    timeout: Option<Duration>,
+    close_linger: Duration,
+    timed_out: bool,
+}
+
+impl<S: PacketSender> TimeoutSender<S> {
+    pub fn new(inner: S, timeout: Option<Duration>, close_linger: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            close_linger,
+            timed_out: false,
+        }
+    }
+}
+
+impl<S: PacketSender> PacketSender for TimeoutSender<S> {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.timed_out {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "an earlier send timed out",
+            ));
+        }
+        let Some(timeout) = self.timeout else {
+            return self.inner.send(packet).await;
+        };
+        time::timeout(timeout, self.inner.send(packet))
+            .await
+            .unwrap_or_else(|_| {
+                self.timed_out = true;
+                Err(io::ErrorKind::TimedOut.into())
+            })
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        // the stream may end in a partial frame, there is nothing sensible left to flush
+        if self.timed_out {
+            return Ok(());
+        }
+        // give buffered data a chance to drain, but never hold a dead peer open
+        time::timeout(self.close_linger, self.inner.close())
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_stream::mock::{self, StalledSender};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const LINGER: Duration = Duration::from_secs(2);
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_send_times_out() {
+        let mut sender = TimeoutSender::new(StalledSender, Some(TIMEOUT), LINGER);
+        let started = time::Instant::now();
+        let err = sender.send(&[1, 2, 3]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_is_unusable_after_a_timeout() {
+        let mut sender = TimeoutSender::new(StalledSender, Some(TIMEOUT), LINGER);
+        _ = sender.send(&[1]).await;
+        let started = time::Instant::now();
+        let err = sender.send(&[2]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        sender.close().await.unwrap();
+        assert!(started.elapsed().is_zero());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_waits_at_most_the_linger() {
+        let mut sender = TimeoutSender::new(StalledSender, None, LINGER);
+        let started = time::Instant::now();
+        let err = sender.close().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), LINGER);
+    }
+
+    // holds every packet until closed, like a buffered writer in front of a slow socket
+    struct BufferedSender {
+        buffered: Vec<Box<[u8]>>,
+        output: mock::MockSender,
+    }
+
+    impl PacketSender for BufferedSender {
+        async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+            self.buffered.push(packet.into());
+            Ok(())
+        }
+
+        async fn close(&mut self) -> io::Result<()> {
+            time::sleep(LINGER / 2).await;
+            for packet in self.buffered.drain(..) {
+                self.output.send(&packet).await?;
+            }
+            self.output.close().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_flushes_buffered_packets_within_the_linger() {
+        let (output, mut receiver) = mock::channel();
+        let inner = BufferedSender {
+            buffered: Vec::new(),
+            output,
+        };
+        let mut sender = TimeoutSender::new(inner, Some(TIMEOUT), LINGER);
+        sender.send(&[1]).await.unwrap();
+        sender.send(&[2]).await.unwrap();
+        assert_eq!(receiver.try_receive(), None);
+        let started = time::Instant::now();
+        sender.close().await.unwrap();
+        assert_eq!(started.elapsed(), LINGER / 2);
+        assert_eq!(receiver.try_receive(), Some([1].into()));
+        assert_eq!(receiver.try_receive(), Some([2].into()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prompt_sends_pass_through() {
+        let (inner, mut receiver) = mock::channel();
+        let mut sender = TimeoutSender::new(inner, Some(TIMEOUT), LINGER);
+        sender.send(&[1, 2, 3]).await.unwrap();
+        sender.close().await.unwrap();
+        assert_eq!(receiver.try_receive(), Some([1, 2, 3].into()));
+        assert_eq!(receiver.try_receive(), None);
+    }
+}
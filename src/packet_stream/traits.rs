@@ -9,3 +9,19 @@ pub trait PacketSender: Send {
 
     fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send;
 }
+
+impl<R: PacketReceiver> PacketReceiver for &mut R {
+    fn receive(&mut self) -> impl Future<Output = io::Result<Box<[u8]>>> + Send {
+        (**self).receive()
+    }
+}
+
+impl<S: PacketSender> PacketSender for &mut S {
+    fn send(&mut self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send {
+        (**self).send(packet)
+    }
+
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        (**self).close()
+    }
+}
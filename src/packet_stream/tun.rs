@@ -47,6 +47,7 @@ impl PacketSender for TunSender {
     }
 
     async fn close(&mut self) -> io::Result<()> {
+        self.wrapped.flush().await?;
         self.wrapped.close().await
     }
 }
@@ -0,0 +1,455 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use futures::io;
+use log::{debug, info, warn};
+use tokio::{net::UdpSocket, sync::mpsc, task::AbortHandle};
+use tokio_rustls::rustls::ConnectionCommon;
+
+use crate::packet_stream::{replay::ReplayWindow, PacketReceiver, PacketSender};
+
+const KEY_LABEL: &[u8] = b"EXPORTER-opaque-vpn-udp";
+const KEY_SIZE: usize = 32;
+const KEY_MATERIAL_SIZE: usize = 8 + 2 * KEY_SIZE;
+// session id and packet counter, both sent in the clear and authenticated as associated data
+const HEADER_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+pub const DATAGRAM_OVERHEAD: u16 = (HEADER_SIZE + TAG_SIZE) as u16;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const SESSION_QUEUE_DEPTH: usize = 256;
+// well below the shortest UDP mapping timeouts NATs commonly use
+pub const DATAGRAM_KEEPALIVE: Duration = Duration::from_secs(15);
+// a session that lost its control connection lives on as long as datagrams keep arriving
+pub const DATAGRAM_IDLE_TIMEOUT: Duration = DATAGRAM_KEEPALIVE.saturating_mul(3);
+
+type Datagram = (Box<[u8]>, SocketAddr);
+type SessionMap = Arc<Mutex<HashMap<u64, mpsc::Sender<Datagram>>>>;
+type PeerAddress = Arc<Mutex<Option<SocketAddr>>>;
+
+pub struct DatagramKeys {
+    session_id: u64,
+    send: LessSafeKey,
+    receive: LessSafeKey,
+}
+
+impl DatagramKeys {
+    // both peers export the same material from the TLS session and pick opposite directions
+    pub fn export<Data>(tls: &ConnectionCommon<Data>, is_server: bool) -> anyhow::Result<Self> {
+        let material = tls
+            .export_keying_material([0u8; KEY_MATERIAL_SIZE], KEY_LABEL, None)
+            .context("could not export datagram keys")?;
+        Self::from_material(&material, is_server)
+    }
+
+    // the session id doubles as the connection id that lets a session follow its client
+    // to a new address, it is bound to the TLS session and so to the client's identity
+    fn from_material(material: &[u8; KEY_MATERIAL_SIZE], is_server: bool) -> anyhow::Result<Self> {
+        let (session_id, keys) = material.split_at(8);
+        let (client_key, server_key) = keys.split_at(KEY_SIZE);
+        let (send, receive) = if is_server {
+            (server_key, client_key)
+        } else {
+            (client_key, server_key)
+        };
+        let key = |bytes| UnboundKey::new(&CHACHA20_POLY1305, bytes).map(LessSafeKey::new);
+        Ok(Self {
+            session_id: u64::from_be_bytes(session_id.try_into().unwrap()),
+            send: key(send).map_err(|_| anyhow::anyhow!("invalid datagram key"))?,
+            receive: key(receive).map_err(|_| anyhow::anyhow!("invalid datagram key"))?,
+        })
+    }
+}
+
+pub struct UdpEndpoint {
+    socket: Arc<UdpSocket>,
+    sessions: SessionMap,
+    dispatcher: AbortHandle,
+}
+
+impl UdpEndpoint {
+    pub fn new(socket: UdpSocket) -> Self {
+        let socket = Arc::new(socket);
+        let sessions = SessionMap::default();
+        let dispatcher = tokio::spawn(dispatch(socket.clone(), sessions.clone())).abort_handle();
+        Self {
+            socket,
+            sessions,
+            dispatcher,
+        }
+    }
+
+    pub fn open(
+        &self,
+        keys: DatagramKeys,
+        peer: Option<SocketAddr>,
+        max_packet: usize,
+    ) -> (UdpPacketSender, UdpPacketReceiver) {
+        let (datagram_sender, datagrams) = mpsc::channel(SESSION_QUEUE_DEPTH);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(keys.session_id, datagram_sender);
+        let peer = Arc::new(Mutex::new(peer));
+        let sender = UdpPacketSender {
+            state: Arc::new(SendState {
+                socket: self.socket.clone(),
+                peer: peer.clone(),
+                key: keys.send,
+                session_id: keys.session_id,
+                counter: AtomicU64::new(1),
+                max_packet,
+                oversize: AtomicU64::new(0),
+            }),
+        };
+        let receiver = UdpPacketReceiver {
+            datagrams,
+            key: keys.receive,
+            session_id: keys.session_id,
+            window: ReplayWindow::default(),
+            peer,
+            sessions: self.sessions.clone(),
+        };
+        (sender, receiver)
+    }
+}
+
+impl Drop for UdpEndpoint {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+async fn dispatch(socket: Arc<UdpSocket>, sessions: SessionMap) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (size, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("could not receive datagram: {e}");
+                continue;
+            }
+        };
+        let Some(session_id) = buffer[..size]
+            .first_chunk::<8>()
+            .filter(|_| size >= HEADER_SIZE + TAG_SIZE)
+            .map(|bytes| u64::from_be_bytes(*bytes))
+        else {
+            continue;
+        };
+        let session = sessions.lock().unwrap().get(&session_id).cloned();
+        // a full queue means the session is not keeping up, so the datagram is lost like on the wire
+        if let Some(session) = session {
+            _ = session.try_send((buffer[..size].into(), from));
+        }
+    }
+}
+
+struct SendState {
+    socket: Arc<UdpSocket>,
+    peer: PeerAddress,
+    key: LessSafeKey,
+    session_id: u64,
+    counter: AtomicU64,
+    max_packet: usize,
+    oversize: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct UdpPacketSender {
+    state: Arc<SendState>,
+}
+
+impl PacketSender for UdpPacketSender {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        let state = &self.state;
+        if packet.len() > state.max_packet {
+            state.oversize.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        // the peer address is only learned from its first authenticated datagram
+        let Some(peer) = *state.peer.lock().unwrap() else {
+            return Ok(());
+        };
+
+        let counter = state.counter.fetch_add(1, Ordering::Relaxed);
+        let datagram = seal(&state.key, state.session_id, counter, packet)?;
+
+        // UDP promises no delivery, the inner protocol retransmits whatever is lost here
+        if let Err(e) = state.socket.send_to(&datagram, peer).await {
+            debug!("could not send datagram to {peer}: {e}");
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        let oversize = self.state.oversize.swap(0, Ordering::Relaxed);
+        if oversize > 0 {
+            warn!("dropped {oversize} packets larger than the tunnel MTU");
+        }
+        Ok(())
+    }
+}
+
+pub struct UdpPacketReceiver {
+    datagrams: mpsc::Receiver<Datagram>,
+    key: LessSafeKey,
+    session_id: u64,
+    window: ReplayWindow,
+    peer: PeerAddress,
+    sessions: SessionMap,
+}
+
+impl UdpPacketReceiver {
+    fn open(&mut self, datagram: &mut [u8]) -> Option<Box<[u8]>> {
+        let (header, sealed) = datagram.split_at_mut(HEADER_SIZE);
+        let counter = u64::from_be_bytes(header[8..].try_into().unwrap());
+        if !self.window.is_fresh(counter) {
+            return None;
+        }
+        let packet = self
+            .key
+            .open_in_place(nonce(counter), Aad::from(&header[..]), sealed)
+            .ok()?;
+        self.window.mark(counter);
+        Some(Box::from(&packet[..]))
+    }
+}
+
+impl PacketReceiver for UdpPacketReceiver {
+    async fn receive(&mut self) -> io::Result<Box<[u8]>> {
+        loop {
+            let (mut datagram, from) = self
+                .datagrams
+                .recv()
+                .await
+                .ok_or(io::ErrorKind::BrokenPipe)?;
+            if let Some(packet) = self.open(&mut datagram) {
+                // follow the peer across address changes, but only on authenticated datagrams
+                let previous = self.peer.lock().unwrap().replace(from);
+                if previous.is_some_and(|previous| previous != from) {
+                    info!("datagram peer moved from {} to {from}", previous.unwrap());
+                }
+                return Ok(packet);
+            }
+        }
+    }
+}
+
+impl Drop for UdpPacketReceiver {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.session_id);
+    }
+}
+
+fn seal(key: &LessSafeKey, session_id: u64, counter: u64, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let mut datagram = Vec::with_capacity(HEADER_SIZE + packet.len() + TAG_SIZE);
+    datagram.extend_from_slice(&session_id.to_be_bytes());
+    datagram.extend_from_slice(&counter.to_be_bytes());
+    let mut sealed = packet.to_vec();
+    key.seal_in_place_append_tag(nonce(counter), Aad::from(&datagram[..]), &mut sealed)
+        .map_err(|_| io::Error::other("could not seal datagram"))?;
+    datagram.extend_from_slice(&sealed);
+    Ok(datagram)
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use etherparse::PacketBuilder;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    use super::*;
+    use crate::{
+        echo,
+        packet_stream::{TaggedPacketReceiver, TaggedPacketSender},
+    };
+
+    // distinct bytes, so that the client and server halves are different keys
+    fn material() -> [u8; KEY_MATERIAL_SIZE] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    async fn endpoint() -> (UdpEndpoint, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        (UdpEndpoint::new(socket), address)
+    }
+
+    // a client socket that seals datagrams with the client's half of the keys
+    struct Client {
+        socket: UdpSocket,
+        keys: DatagramKeys,
+    }
+
+    impl Client {
+        async fn bind() -> Self {
+            Self {
+                socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                keys: DatagramKeys::from_material(&material(), false).unwrap(),
+            }
+        }
+
+        async fn send(&self, to: SocketAddr, counter: u64, packet: &[u8]) {
+            let datagram = seal(&self.keys.send, self.keys.session_id, counter, packet).unwrap();
+            self.socket.send_to(&datagram, to).await.unwrap();
+        }
+
+        async fn receive(&self) -> Box<[u8]> {
+            let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+            let size = self.socket.recv(&mut datagram).await.unwrap();
+            let (header, sealed) = datagram[..size].split_at_mut(HEADER_SIZE);
+            let counter = u64::from_be_bytes(header[8..].try_into().unwrap());
+            let packet = self
+                .keys
+                .receive
+                .open_in_place(nonce(counter), Aad::from(&header[..]), sealed)
+                .unwrap();
+            packet.into()
+        }
+    }
+
+    #[tokio::test]
+    async fn session_follows_the_client_to_a_new_address() {
+        let (server, server_address) = endpoint().await;
+        let keys = DatagramKeys::from_material(&material(), true).unwrap();
+        let (mut sender, mut receiver) = server.open(keys, None, 1500);
+
+        let before = Client::bind().await;
+        before.send(server_address, 1, b"before").await;
+        assert_eq!(*receiver.receive().await.unwrap(), *b"before");
+        sender.send(b"to before").await.unwrap();
+        assert_eq!(*before.receive().await, *b"to before");
+
+        // the same session continues from another source address
+        let after = Client::bind().await;
+        after.send(server_address, 2, b"after").await;
+        assert_eq!(*receiver.receive().await.unwrap(), *b"after");
+        sender.send(b"to after").await.unwrap();
+        assert_eq!(*after.receive().await, *b"to after");
+    }
+
+    #[tokio::test]
+    async fn forged_datagram_does_not_move_the_session() {
+        let (server, server_address) = endpoint().await;
+        let keys = DatagramKeys::from_material(&material(), true).unwrap();
+        let (mut sender, mut receiver) = server.open(keys, None, 1500);
+        let client = Client::bind().await;
+        client.send(server_address, 1, b"hello").await;
+        assert_eq!(*receiver.receive().await.unwrap(), *b"hello");
+
+        // right session id, but sealed with the wrong key
+        let attacker = Client {
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            keys: DatagramKeys::from_material(&material(), true).unwrap(),
+        };
+        attacker.send(server_address, 2, b"hijack").await;
+        client.send(server_address, 3, b"still here").await;
+        assert_eq!(*receiver.receive().await.unwrap(), *b"still here");
+        sender.send(b"reply").await.unwrap();
+        assert_eq!(*client.receive().await, *b"reply");
+    }
+
+    #[tokio::test]
+    async fn oversize_packets_are_dropped() {
+        let (server, server_address) = endpoint().await;
+        let keys = DatagramKeys::from_material(&material(), true).unwrap();
+        let (mut sender, mut receiver) = server.open(keys, None, 4);
+        let client = Client::bind().await;
+        client.send(server_address, 1, b"hello").await;
+        receiver.receive().await.unwrap();
+        sender.send(b"too large").await.unwrap();
+        sender.send(b"fits").await.unwrap();
+        assert_eq!(*client.receive().await, *b"fits");
+        assert_eq!(sender.state.oversize.load(Ordering::Relaxed), 1);
+    }
+
+    const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn udp_packet(source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Box<[u8]> {
+        let builder = PacketBuilder::ipv4(source.octets(), destination.octets(), 64).udp(7, 7);
+        let mut packet = Vec::new();
+        builder.write(&mut packet, payload).unwrap();
+        packet.into()
+    }
+
+    // the server side answers every packet like the echo service would
+    async fn echo_through(
+        mut client_sender: impl PacketSender,
+        mut client_receiver: impl PacketReceiver,
+        mut server_sender: impl PacketSender,
+        mut server_receiver: impl PacketReceiver,
+    ) {
+        let server = async {
+            for _ in 0..3 {
+                let packet = server_receiver.receive().await.unwrap();
+                let reply = echo::reflect(&packet, CLIENT_IP).unwrap();
+                server_sender.send(&reply).await.unwrap();
+            }
+        };
+        let client = async {
+            for payload in [&b"ping0"[..], b"ping1", b"ping2"] {
+                client_sender
+                    .send(&udp_packet(CLIENT_IP, SERVER_IP, payload))
+                    .await
+                    .unwrap();
+                let reply = client_receiver.receive().await.unwrap();
+                assert_eq!(reply, udp_packet(SERVER_IP, CLIENT_IP, payload));
+            }
+        };
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn udp_echo_is_tunneled_over_both_transports() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (accepted, connected) = tokio::join!(listener.accept(), TcpStream::connect(address));
+        let (client_reader, client_writer) = tokio::io::split(connected.unwrap());
+        let (server_reader, server_writer) = tokio::io::split(accepted.unwrap().0);
+        echo_through(
+            TaggedPacketSender::new(client_writer.compat_write()),
+            TaggedPacketReceiver::new(client_reader.compat()),
+            TaggedPacketSender::new(server_writer.compat_write()),
+            TaggedPacketReceiver::new(server_reader.compat()),
+        )
+        .await;
+
+        let (server, server_address) = endpoint().await;
+        let (client, _) = endpoint().await;
+        let (server_sender, server_receiver) = server.open(
+            DatagramKeys::from_material(&material(), true).unwrap(),
+            None,
+            1500,
+        );
+        let (client_sender, client_receiver) = client.open(
+            DatagramKeys::from_material(&material(), false).unwrap(),
+            Some(server_address),
+            1500,
+        );
+        echo_through(
+            client_sender,
+            client_receiver,
+            server_sender,
+            server_receiver,
+        )
+        .await;
+    }
+}
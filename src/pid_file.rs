@@ -0,0 +1,88 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use log::warn;
+
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        if let Ok(contents) = fs::read_to_string(&path) {
+            match contents.trim().parse::<u32>() {
+                Ok(pid) if pid != std::process::id() && process_exists(pid) => {
+                    bail!(
+                        "pid file {} belongs to running process {pid}",
+                        path.display()
+                    )
+                }
+                _ => warn!("replacing stale pid file {}", path.display()),
+            }
+        }
+
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("could not write pid file {}", path.display()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("could not remove pid file {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_exists(_pid: u32) -> bool {
+    // without a portable liveness check every leftover pid file is treated as stale
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("opaque-vpn-{}-{name}.pid", std::process::id()))
+    }
+
+    #[test]
+    fn pid_file_lives_as_long_as_the_guard() {
+        let path = temp_path("lifetime");
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.trim(), std::process::id().to_string());
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_pid_file_is_replaced() {
+        let path = temp_path("stale");
+        fs::write(&path, "not a pid\n").unwrap();
+        let _pid_file = PidFile::create(path.clone()).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.trim(), std::process::id().to_string());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pid_file_of_a_running_process_is_kept() {
+        let path = temp_path("running");
+        fs::write(&path, "1\n").unwrap();
+        assert!(PidFile::create(path.clone()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n");
+        fs::remove_file(path).unwrap();
+    }
+}
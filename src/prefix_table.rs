@@ -0,0 +1,129 @@
+use std::net::Ipv4Addr;
+
+pub struct PrefixTable<T> {
+    nodes: Vec<Node<T>>,
+}
+
+struct Node<T> {
+    children: [Option<usize>; 2],
+    value: Option<T>,
+}
+
+impl<T> Default for PrefixTable<T> {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+impl<T> PrefixTable<T> {
+    pub fn insert(&mut self, prefix: Ipv4Addr, len: u8, value: T) -> Option<T> {
+        let mut node = 0;
+        for bit in bits(prefix, len) {
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].value.replace(value)
+    }
+
+    pub fn get(&self, prefix: Ipv4Addr, len: u8) -> Option<&T> {
+        let node = self.find(prefix, len)?;
+        self.nodes[node].value.as_ref()
+    }
+
+    // emptied nodes stay allocated, tables only ever change by a handful of prefixes
+    pub fn remove(&mut self, prefix: Ipv4Addr, len: u8) -> Option<T> {
+        let node = self.find(prefix, len)?;
+        self.nodes[node].value.take()
+    }
+
+    pub fn longest_match(&self, addr: Ipv4Addr) -> Option<&T> {
+        let mut node = 0;
+        let mut best = self.nodes[0].value.as_ref();
+        for bit in bits(addr, 32) {
+            let Some(child) = self.nodes[node].children[bit] else {
+                break;
+            };
+            node = child;
+            best = self.nodes[node].value.as_ref().or(best);
+        }
+        best
+    }
+
+    fn find(&self, prefix: Ipv4Addr, len: u8) -> Option<usize> {
+        bits(prefix, len).try_fold(0, |node, bit| self.nodes[node].children[bit])
+    }
+}
+
+pub fn covers((outer, outer_len): (Ipv4Addr, u8), (inner, inner_len): (Ipv4Addr, u8)) -> bool {
+    outer_len <= inner_len && bits(outer, outer_len).eq(bits(inner, outer_len))
+}
+
+fn bits(addr: Ipv4Addr, len: u8) -> impl Iterator<Item = usize> {
+    let addr = addr.to_bits();
+    (0..u32::from(len)).map(move |i| (addr >> (31 - i) & 1) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> Ipv4Addr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut table = PrefixTable::default();
+        table.insert(addr("10.1.0.0"), 16, "site");
+        table.insert(addr("10.1.2.0"), 24, "lab");
+        table.insert(addr("10.1.2.128"), 25, "rack");
+        assert_eq!(table.longest_match(addr("10.1.3.4")), Some(&"site"));
+        assert_eq!(table.longest_match(addr("10.1.2.4")), Some(&"lab"));
+        assert_eq!(table.longest_match(addr("10.1.2.200")), Some(&"rack"));
+        assert_eq!(table.longest_match(addr("10.2.0.1")), None);
+    }
+
+    #[test]
+    fn removed_prefix_falls_back_to_the_shorter_one() {
+        let mut table = PrefixTable::default();
+        table.insert(addr("10.1.0.0"), 16, "site");
+        table.insert(addr("10.1.2.0"), 24, "lab");
+        assert_eq!(table.remove(addr("10.1.2.0"), 24), Some("lab"));
+        assert_eq!(table.longest_match(addr("10.1.2.4")), Some(&"site"));
+        assert_eq!(table.get(addr("10.1.2.0"), 24), None);
+        assert_eq!(table.remove(addr("10.1.2.0"), 24), None);
+    }
+
+    #[test]
+    fn default_route_matches_everything() {
+        let mut table = PrefixTable::default();
+        table.insert(addr("0.0.0.0"), 0, "default");
+        assert_eq!(table.longest_match(addr("192.0.2.1")), Some(&"default"));
+        assert_eq!(table.insert(addr("0.0.0.0"), 0, "other"), Some("default"));
+    }
+
+    #[test]
+    fn covers_compares_the_outer_prefix_bits() {
+        assert!(covers((addr("10.0.0.0"), 8), (addr("10.1.2.0"), 24)));
+        assert!(!covers((addr("10.1.2.0"), 24), (addr("10.0.0.0"), 8)));
+        assert!(!covers((addr("10.0.0.0"), 8), (addr("11.0.0.0"), 24)));
+    }
+}
@@ -1,60 +1,284 @@
-use std::net::Ipv4Addr;
+use std::{fmt, net::Ipv4Addr, time::Duration};
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use futures::io::{AsyncRead, AsyncWrite};
+use serde::Serialize;
 
-use crate::packet_stream::{
-    PacketReceiver, PacketSender, TaggedPacketReceiver, TaggedPacketSender,
+use crate::{
+    config::{validate_domain, Keepalive, Transport},
+    packet_stream::{
+        PacketReceiver, PacketSender, TaggedPacketReceiver, TaggedPacketSender, DATAGRAM_OVERHEAD,
+        LENGTH_PREFIX_SIZE,
+    },
 };
 
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct NetworkConfig {
     pub client_ip: Ipv4Addr,
     pub server_ip: Ipv4Addr,
     pub netmask: Ipv4Addr,
     pub mtu: u16,
+    pub dns: Vec<Ipv4Addr>,
+    pub search_domain: Option<String>,
 }
 
-const CONFIG_SIZE: usize = 3 * 4 + 2;
+impl fmt::Display for NetworkConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} via {}, MTU {}",
+            self.client_ip,
+            self.netmask.to_bits().count_ones(),
+            self.server_ip,
+            self.mtu
+        )?;
+        if !self.dns.is_empty() {
+            let dns = self.dns.iter().map(ToString::to_string);
+            write!(f, ", DNS {}", dns.collect::<Vec<_>>().join(", "))?;
+        }
+        if let Some(domain) = &self.search_domain {
+            write!(f, ", search domain {domain}")?;
+        }
+        Ok(())
+    }
+}
 
-impl From<NetworkConfig> for [u8; CONFIG_SIZE] {
-    fn from(value: NetworkConfig) -> Self {
-        let mut bytes = [0u8; CONFIG_SIZE];
-        bytes[0..4].copy_from_slice(&value.client_ip.octets());
-        bytes[4..8].copy_from_slice(&value.server_ip.octets());
-        bytes[8..12].copy_from_slice(&value.netmask.octets());
-        bytes[12..14].copy_from_slice(&value.mtu.to_le_bytes());
-        bytes
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnsupportedVersion(u8),
+    // the server sent its config without a version byte
+    OutdatedServer,
+    Rejected(Rejection),
+    // a rejection frame in place of a packet ended an established session
+    Disconnected(Rejection),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "server speaks protocol version {version}, this client only supports version \
+                 {PROTOCOL_VERSION}"
+            ),
+            Self::OutdatedServer => write!(
+                f,
+                "server is outdated, it predates protocol versions and this client needs version \
+                 {PROTOCOL_VERSION}"
+            ),
+            Self::Rejected(rejection) => write!(f, "server rejected the connection: {rejection}"),
+            Self::Disconnected(rejection) => write!(f, "server ended the session: {rejection}"),
+        }
     }
 }
 
-impl From<&[u8; CONFIG_SIZE]> for NetworkConfig {
-    fn from(bytes: &[u8; CONFIG_SIZE]) -> Self {
-        let client_ip = Ipv4Addr::from_octets(bytes[0..4].try_into().unwrap());
-        let server_ip = Ipv4Addr::from_octets(bytes[4..8].try_into().unwrap());
-        let netmask = Ipv4Addr::from_octets(bytes[8..12].try_into().unwrap());
-        let mtu = u16::from_le_bytes(bytes[12..14].try_into().unwrap());
-        Self {
-            client_ip,
-            server_ip,
-            netmask,
-            mtu,
+// sent in place of the network config, older clients report it as an unsupported version 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    Maintenance,
+    SessionExpired,
+    // the client's certificate already has as many sessions as the server allows
+    IdentityLimit,
+    ServerFull,
+    NoFreeAddress,
+    // the client did not negotiate the ALPN protocol the server expects
+    ProtocolMismatch,
+    // too many connection attempts from the client's address
+    Throttled,
+    Unknown(u8),
+}
+
+impl Rejection {
+    fn code(self) -> u8 {
+        match self {
+            Self::Maintenance => 1,
+            Self::SessionExpired => 2,
+            Self::IdentityLimit => 3,
+            Self::ServerFull => 4,
+            Self::NoFreeAddress => 5,
+            Self::ProtocolMismatch => 6,
+            Self::Throttled => 7,
+            Self::Unknown(code) => code,
         }
     }
 }
 
+impl From<u8> for Rejection {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => Self::Maintenance,
+            2 => Self::SessionExpired,
+            3 => Self::IdentityLimit,
+            4 => Self::ServerFull,
+            5 => Self::NoFreeAddress,
+            6 => Self::ProtocolMismatch,
+            7 => Self::Throttled,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Maintenance => f.write_str("server is in maintenance mode"),
+            Self::SessionExpired => f.write_str("session reached its maximum lifetime"),
+            Self::IdentityLimit => f.write_str("too many sessions use this certificate"),
+            Self::ServerFull => f.write_str("server is full"),
+            Self::NoFreeAddress => f.write_str("no address is free for this client"),
+            Self::ProtocolMismatch => f.write_str("client did not negotiate the expected protocol"),
+            Self::Throttled => f.write_str("too many connection attempts"),
+            Self::Unknown(code) => write!(f, "reason {code}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+// bumped on incompatible handshake changes, new trailing config fields do not need a bump.
+// Servers keep accepting older clients, which do not send the frames added after their version
+const PROTOCOL_VERSION: u8 = 4;
+const MIN_PROTOCOL_VERSION: u8 = 1;
+// first version whose clients report their path MTU
+const PATH_MTU_VERSION: u8 = 2;
+// first version whose clients choose their transport, older ones only speak TCP
+const TRANSPORT_VERSION: u8 = 3;
+// first version whose clients advertise the subnets they route
+const ROUTES_VERSION: u8 = 3;
+// first version whose peers announce their keepalive interval, older ones never send keepalives
+const KEEPALIVE_VERSION: u8 = 4;
+// first version whose clients take a rejection frame between packets as the end of the session
+const DISCONNECT_VERSION: u8 = 4;
+// a few of the peer's keepalives may be lost or delayed before it counts as dead
+const MISSED_KEEPALIVES: u32 = 3;
+// takes the place of the version in the config reply, no protocol version is 0
+const REJECTION: u8 = 0;
+const CONFIG_SIZE: usize = 3 * 4 + 2;
+const PATH_MTU_SIZE: usize = 2;
+// prefix and prefix length
+const ROUTE_SIZE: usize = 5;
+// interval in seconds, 0 when the peer sends no keepalives
+const KEEPALIVE_SIZE: usize = 4;
+
+// TLS record header, AEAD tag and the TLS 1.3 inner content type
+const TLS_RECORD_OVERHEAD: u16 = 5 + 16 + 1;
+const FRAMING_OVERHEAD: u16 = LENGTH_PREFIX_SIZE + TLS_RECORD_OVERHEAD;
+// IPv4 and TCP headers with room for options
+const TCP_OVERHEAD: u16 = 20 + 32;
+// IPv4 and UDP headers
+const UDP_OVERHEAD: u16 = 20 + 8;
+const MIN_TUNNEL_MTU: u16 = 576;
+
+// without a reported path MTU the client's path is assumed to be as large as the server's
+pub fn tunnel_mtu(device_mtu: u16, path_mtu: Option<u16>, transport: Transport) -> u16 {
+    let advertised = device_mtu.saturating_sub(FRAMING_OVERHEAD);
+    let overhead = match transport {
+        Transport::Tcp => TCP_OVERHEAD + FRAMING_OVERHEAD,
+        Transport::Udp => UDP_OVERHEAD + DATAGRAM_OVERHEAD,
+    };
+    let limited = advertised.min(path_mtu.unwrap_or(device_mtu).saturating_sub(overhead));
+    limited.max(MIN_TUNNEL_MTU.min(advertised))
+}
+
+impl From<&NetworkConfig> for Vec<u8> {
+    fn from(value: &NetworkConfig) -> Self {
+        let search_domain = value.search_domain.as_deref().unwrap_or_default();
+        let mut bytes =
+            Vec::with_capacity(CONFIG_SIZE + 1 + 4 * value.dns.len() + 1 + search_domain.len());
+        bytes.extend_from_slice(&value.client_ip.octets());
+        bytes.extend_from_slice(&value.server_ip.octets());
+        bytes.extend_from_slice(&value.netmask.octets());
+        bytes.extend_from_slice(&value.mtu.to_le_bytes());
+        // the server configuration caps the DNS list at what the count byte holds
+        bytes.push(value.dns.len() as u8);
+        for server in &value.dns {
+            bytes.extend_from_slice(&server.octets());
+        }
+        // a valid domain is at most 253 bytes, an empty one means no search domain
+        bytes.push(search_domain.len() as u8);
+        bytes.extend_from_slice(search_domain.as_bytes());
+        bytes
+    }
+}
+
 impl TryFrom<&[u8]> for NetworkConfig {
     type Error = anyhow::Error;
     fn try_from(value: &[u8]) -> anyhow::Result<Self> {
-        let bytes: &[u8; CONFIG_SIZE] = value
-            .try_into()
-            .context("invalid NetworkConfig byte size")?;
-        Ok(bytes.into())
+        // newer servers may append fields, only the known prefix is decoded
+        let (bytes, rest) = value
+            .split_first_chunk::<CONFIG_SIZE>()
+            .context("NetworkConfig is too short")?;
+        // servers that predate DNS push end the config here
+        let (dns, rest) = match rest.split_first() {
+            Some((&count, rest)) => {
+                let (servers, rest) = rest
+                    .split_at_checked(4 * usize::from(count))
+                    .context("NetworkConfig DNS list is truncated")?;
+                let (servers, _) = servers.as_chunks::<4>();
+                (servers.iter().map(|&octets| octets.into()).collect(), rest)
+            }
+            None => (Vec::new(), rest),
+        };
+        // and those that predate search domain push end it here
+        let search_domain = match rest.split_first() {
+            Some((&size, rest)) if size > 0 => {
+                let domain = rest
+                    .get(..size.into())
+                    .context("NetworkConfig search domain is truncated")?;
+                let domain = str::from_utf8(domain)
+                    .ok()
+                    .filter(|domain| validate_domain(domain).is_ok())
+                    .context("NetworkConfig search domain is invalid")?;
+                Some(domain.into())
+            }
+            _ => None,
+        };
+        Ok(Self {
+            client_ip: Ipv4Addr::from_octets(bytes[0..4].try_into().unwrap()),
+            server_ip: Ipv4Addr::from_octets(bytes[4..8].try_into().unwrap()),
+            netmask: Ipv4Addr::from_octets(bytes[8..12].try_into().unwrap()),
+            mtu: u16::from_le_bytes(bytes[12..14].try_into().unwrap()),
+            dns,
+            search_domain,
+        })
     }
 }
 
+pub fn is_heartbeat(frame: &[u8]) -> bool {
+    frame.is_empty()
+}
+
+// the first byte of an IP packet holds its version, so it is never a rejection's 0
+pub fn rejection_frame(rejection: Rejection) -> [u8; 2] {
+    [REJECTION, rejection.code()]
+}
+
+pub fn as_rejection(frame: &[u8]) -> Option<Rejection> {
+    match *frame {
+        [REJECTION, code] => Some(code.into()),
+        _ => None,
+    }
+}
+
+// keepalives only flow when both ends announced an interval: older peers may not know
+// heartbeats, and a peer that sends none must not be dropped for being idle
+pub fn negotiate_keepalive(
+    own: Option<Keepalive>,
+    peer_interval: Option<Duration>,
+) -> Option<Keepalive> {
+    let own = own?;
+    let peer_interval = peer_interval?;
+    Some(Keepalive {
+        interval: own.interval,
+        timeout: own.timeout.max(peer_interval * MISSED_KEEPALIVES),
+    })
+}
+
 pub struct Connection<Reader: Send, Writer: Send> {
     receiver: TaggedPacketReceiver<Reader>,
     sender: TaggedPacketSender<Writer>,
+    // the peer's version once a server has received it
+    version: u8,
 }
 
 impl<Reader, Writer> Connection<Reader, Writer>
@@ -66,20 +290,459 @@ where
         Self {
             receiver: TaggedPacketReceiver::new(reader),
             sender: TaggedPacketSender::new(writer),
+            version: PROTOCOL_VERSION,
         }
     }
 
-    pub async fn send_config(&mut self, config: NetworkConfig) -> std::io::Result<()> {
-        let config_bytes: [u8; CONFIG_SIZE] = config.into();
-        self.sender.send(&config_bytes).await
+    pub async fn send_version(&mut self) -> std::io::Result<()> {
+        self.sender.send(&[PROTOCOL_VERSION]).await
+    }
+
+    pub async fn receive_version(&mut self) -> anyhow::Result<()> {
+        let version = match *self.receiver.receive().await? {
+            [version] => version,
+            _ => anyhow::bail!("client did not announce a protocol version, it is likely outdated"),
+        };
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            // a version-only config frame lets the client report the mismatch
+            self.sender.send(&[PROTOCOL_VERSION]).await?;
+            anyhow::bail!(
+                "client speaks protocol version {version}, this server supports versions \
+                 {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+            );
+        }
+        self.version = version;
+        Ok(())
+    }
+
+    pub async fn send_config(&mut self, config: &NetworkConfig) -> std::io::Result<()> {
+        let config_bytes: Vec<u8> = config.into();
+        self.sender
+            .send(&[&[self.version][..], &config_bytes].concat())
+            .await
+    }
+
+    pub async fn send_rejection(&mut self, rejection: Rejection) -> std::io::Result<()> {
+        self.sender.send(&rejection_frame(rejection)).await
+    }
+
+    pub fn accepts_disconnect(&self) -> bool {
+        self.version >= DISCONNECT_VERSION
     }
 
     pub async fn receive_config(&mut self) -> anyhow::Result<NetworkConfig> {
-        let config_bytes = self.receiver.receive().await?;
-        config_bytes.as_ref().try_into()
+        let frame = self.receiver.receive().await?;
+        // a versioned config is longer, its DNS and search domain sizes are always sent
+        if frame.len() == CONFIG_SIZE {
+            return Err(ProtocolError::OutdatedServer.into());
+        }
+        let (&version, config_bytes) = frame.split_first().context("network config is empty")?;
+        if let Some(rejection) = as_rejection(&frame) {
+            return Err(ProtocolError::Rejected(rejection).into());
+        }
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version).into());
+        }
+        config_bytes.try_into()
+    }
+
+    pub async fn send_path_mtu(&mut self, path_mtu: Option<u16>) -> std::io::Result<()> {
+        self.sender.send(&path_mtu.unwrap_or(0).to_le_bytes()).await
+    }
+
+    pub async fn send_transport(&mut self, transport: Transport) -> std::io::Result<()> {
+        let code = match transport {
+            Transport::Tcp => 0u8,
+            Transport::Udp => 1,
+        };
+        self.sender.send(&[code]).await
+    }
+
+    pub async fn receive_transport(&mut self) -> anyhow::Result<Transport> {
+        if self.version < TRANSPORT_VERSION {
+            return Ok(Transport::Tcp);
+        }
+        match *self.receiver.receive().await? {
+            [0] => Ok(Transport::Tcp),
+            [1] => Ok(Transport::Udp),
+            _ => anyhow::bail!("unknown transport request"),
+        }
+    }
+
+    pub async fn send_routes(&mut self, routes: &[(Ipv4Addr, u8)]) -> std::io::Result<()> {
+        let bytes: Vec<u8> = routes
+            .iter()
+            .flat_map(|(prefix, len)| prefix.octets().into_iter().chain([*len]))
+            .collect();
+        self.sender.send(&bytes).await
+    }
+
+    pub async fn receive_routes(&mut self) -> anyhow::Result<Vec<(Ipv4Addr, u8)>> {
+        if self.version < ROUTES_VERSION {
+            return Ok(Vec::new());
+        }
+        let bytes = self.receiver.receive().await?;
+        let (routes, rest) = bytes.as_chunks::<ROUTE_SIZE>();
+        ensure!(rest.is_empty(), "invalid route advertisement size");
+        routes
+            .iter()
+            .map(|&[a, b, c, d, len]| {
+                ensure!(
+                    len <= 32,
+                    "invalid prefix length {len} in route advertisement"
+                );
+                Ok((Ipv4Addr::new(a, b, c, d), len))
+            })
+            .collect()
+    }
+
+    pub async fn send_keepalive(&mut self, interval: Option<Duration>) -> std::io::Result<()> {
+        if self.version < KEEPALIVE_VERSION {
+            return Ok(());
+        }
+        let secs = interval.map_or(0, |interval| {
+            u32::try_from(interval.as_secs()).unwrap_or(u32::MAX)
+        });
+        self.sender.send(&secs.to_le_bytes()).await
+    }
+
+    pub async fn receive_keepalive(&mut self) -> anyhow::Result<Option<Duration>> {
+        if self.version < KEEPALIVE_VERSION {
+            return Ok(None);
+        }
+        let bytes = self.receiver.receive().await?;
+        let bytes: [u8; KEEPALIVE_SIZE] = bytes
+            .as_ref()
+            .try_into()
+            .context("invalid keepalive interval byte size")?;
+        let secs = u32::from_le_bytes(bytes);
+        Ok((secs != 0).then(|| Duration::from_secs(secs.into())))
+    }
+
+    pub async fn receive_path_mtu(&mut self) -> anyhow::Result<Option<u16>> {
+        if self.version < PATH_MTU_VERSION {
+            return Ok(None);
+        }
+        let bytes = self.receiver.receive().await?;
+        let bytes: [u8; PATH_MTU_SIZE] = bytes
+            .as_ref()
+            .try_into()
+            .context("invalid path MTU byte size")?;
+        Ok(Some(u16::from_le_bytes(bytes)).filter(|&mtu| mtu != 0))
     }
 
     pub fn into_parts(self) -> (TaggedPacketSender<Writer>, TaggedPacketReceiver<Reader>) {
         (self.sender, self.receiver)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+
+    // length-prefixed frames as a client would send them
+    fn frames(frames: &[&[u8]]) -> Cursor<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            bytes.extend_from_slice(&(frame.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(frame);
+        }
+        Cursor::new(bytes)
+    }
+
+    fn network_config(dns: Vec<Ipv4Addr>, search_domain: Option<&str>) -> NetworkConfig {
+        NetworkConfig {
+            client_ip: Ipv4Addr::new(10, 0, 0, 2),
+            server_ip: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            mtu: 1400,
+            dns,
+            search_domain: search_domain.map(String::from),
+        }
+    }
+
+    fn round_trip(config: &NetworkConfig) -> NetworkConfig {
+        let bytes: Vec<u8> = config.into();
+        bytes.as_slice().try_into().unwrap()
+    }
+
+    #[test]
+    fn search_domain_round_trips() {
+        let config = network_config(vec![Ipv4Addr::new(10, 0, 0, 1)], Some("corp.internal"));
+        assert_eq!(round_trip(&config), config);
+        let config = network_config(Vec::new(), Some("corp.internal"));
+        assert_eq!(round_trip(&config), config);
+        let config = network_config(vec![Ipv4Addr::new(10, 0, 0, 1)], None);
+        assert_eq!(round_trip(&config), config);
+    }
+
+    #[test]
+    fn older_servers_push_no_search_domain() {
+        let config = network_config(vec![Ipv4Addr::new(10, 0, 0, 1)], Some("corp.internal"));
+        let bytes: Vec<u8> = (&config).into();
+        let without_domain = NetworkConfig::try_from(&bytes[..CONFIG_SIZE + 1 + 4]).unwrap();
+        assert_eq!(without_domain, network_config(config.dns, None));
+        let without_dns = NetworkConfig::try_from(&bytes[..CONFIG_SIZE]).unwrap();
+        assert_eq!(without_dns, network_config(Vec::new(), None));
+    }
+
+    #[test]
+    fn malformed_search_domain_is_rejected() {
+        let mut bytes: Vec<u8> = (&network_config(Vec::new(), None)).into();
+        bytes.pop();
+        let domain = b"corp.internal\nnameserver 192.0.2.1";
+        bytes.push(domain.len() as u8);
+        bytes.extend_from_slice(domain);
+        assert!(NetworkConfig::try_from(bytes.as_slice()).is_err());
+        assert!(NetworkConfig::try_from(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn reported_path_mtu_limits_the_tunnel_mtu() {
+        assert_eq!(tunnel_mtu(1500, Some(1500), Transport::Tcp), 1500 - 52 - 24);
+        assert_eq!(tunnel_mtu(1500, Some(1400), Transport::Tcp), 1400 - 52 - 24);
+        assert_eq!(tunnel_mtu(1500, Some(1400), Transport::Udp), 1400 - 60);
+        assert_eq!(tunnel_mtu(1500, Some(600), Transport::Tcp), MIN_TUNNEL_MTU);
+    }
+
+    #[test]
+    fn unknown_path_mtu_still_leaves_room_for_the_transport() {
+        assert_eq!(tunnel_mtu(1500, None, Transport::Tcp), 1500 - 52 - 24);
+        assert_eq!(tunnel_mtu(1500, None, Transport::Udp), 1500 - 60);
+    }
+
+    #[tokio::test]
+    async fn older_clients_skip_the_path_mtu_frame() {
+        let mut output = Vec::new();
+        let mut connection = Connection::new(frames(&[&[1]]), &mut output);
+        connection.receive_version().await.unwrap();
+        assert_eq!(connection.receive_path_mtu().await.unwrap(), None);
+        assert!(connection.receive_transport().await.unwrap() == Transport::Tcp);
+        connection
+            .send_config(&network_config(Vec::new(), None))
+            .await
+            .unwrap();
+        drop(connection);
+        // the reply carries the client's version, so the client accepts it
+        assert_eq!(output[2], 1);
+    }
+
+    #[tokio::test]
+    async fn path_mtu_is_received_from_current_clients() {
+        let mtu = 1400u16.to_le_bytes();
+        let mut output = Vec::new();
+        let mut connection = Connection::new(frames(&[&[PROTOCOL_VERSION], &mtu]), &mut output);
+        connection.receive_version().await.unwrap();
+        assert_eq!(connection.receive_path_mtu().await.unwrap(), Some(1400));
+    }
+
+    #[tokio::test]
+    async fn transport_is_only_read_from_clients_that_choose_it() {
+        let mtu = 1400u16.to_le_bytes();
+        let mut connection = Connection::new(frames(&[&[2], &mtu]), Vec::new());
+        connection.receive_version().await.unwrap();
+        assert_eq!(connection.receive_path_mtu().await.unwrap(), Some(1400));
+        assert!(connection.receive_transport().await.unwrap() == Transport::Tcp);
+
+        let mut connection =
+            Connection::new(frames(&[&[TRANSPORT_VERSION], &mtu, &[1]]), Vec::new());
+        connection.receive_version().await.unwrap();
+        connection.receive_path_mtu().await.unwrap();
+        assert!(connection.receive_transport().await.unwrap() == Transport::Udp);
+    }
+
+    #[tokio::test]
+    async fn routes_are_only_read_from_clients_that_advertise_them() {
+        let mut connection = Connection::new(frames(&[&[1]]), Vec::new());
+        connection.receive_version().await.unwrap();
+        assert!(connection.receive_routes().await.unwrap().is_empty());
+
+        let route = [192, 168, 10, 0, 24];
+        let mut connection = Connection::new(frames(&[&[ROUTES_VERSION], &route]), Vec::new());
+        connection.receive_version().await.unwrap();
+        assert_eq!(
+            connection.receive_routes().await.unwrap(),
+            [(Ipv4Addr::new(192, 168, 10, 0), 24)]
+        );
+    }
+
+    #[tokio::test]
+    async fn advertised_routes_round_trip() {
+        let routes = [
+            (Ipv4Addr::new(192, 168, 20, 0), 24),
+            (Ipv4Addr::new(10, 8, 0, 0), 16),
+        ];
+        let mut output = Vec::new();
+        let mut client = Connection::new(frames(&[]), &mut output);
+        client.send_routes(&routes).await.unwrap();
+        drop(client);
+        let mut input = frames(&[&[PROTOCOL_VERSION]]).into_inner();
+        input.extend_from_slice(&output);
+        let mut server = Connection::new(Cursor::new(input), Vec::new());
+        server.receive_version().await.unwrap();
+        assert_eq!(server.receive_routes().await.unwrap(), routes);
+    }
+
+    #[tokio::test]
+    async fn malformed_route_advertisements_are_rejected() {
+        for frame in [&[10, 0, 0, 0][..], &[10, 0, 0, 0, 33]] {
+            let mut server = Connection::new(frames(&[&[PROTOCOL_VERSION], frame]), Vec::new());
+            server.receive_version().await.unwrap();
+            assert!(server.receive_routes().await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive_interval_is_only_read_from_clients_that_announce_it() {
+        let mut output = Vec::new();
+        let mut connection = Connection::new(frames(&[&[ROUTES_VERSION]]), &mut output);
+        connection.receive_version().await.unwrap();
+        assert_eq!(connection.receive_keepalive().await.unwrap(), None);
+        // nor is the server's interval sent to them
+        connection
+            .send_keepalive(Some(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        drop(connection);
+        assert!(output.is_empty());
+
+        let interval = 15u32.to_le_bytes();
+        let mut connection =
+            Connection::new(frames(&[&[KEEPALIVE_VERSION], &interval]), Vec::new());
+        connection.receive_version().await.unwrap();
+        assert_eq!(
+            connection.receive_keepalive().await.unwrap(),
+            Some(Duration::from_secs(15))
+        );
+    }
+
+    #[tokio::test]
+    async fn keepalive_interval_round_trips() {
+        for interval in [None, Some(Duration::from_secs(45))] {
+            let mut output = Vec::new();
+            let mut client = Connection::new(frames(&[]), &mut output);
+            client.send_keepalive(interval).await.unwrap();
+            drop(client);
+            let mut server = Connection::new(Cursor::new(output), Vec::new());
+            assert_eq!(server.receive_keepalive().await.unwrap(), interval);
+        }
+    }
+
+    #[test]
+    fn keepalives_need_both_ends_to_opt_in() {
+        let own = Keepalive {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+        };
+        assert!(negotiate_keepalive(Some(own), None).is_none());
+        assert!(negotiate_keepalive(None, Some(Duration::from_secs(15))).is_none());
+        let keepalive = negotiate_keepalive(Some(own), Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(keepalive.interval, own.interval);
+        assert_eq!(keepalive.timeout, own.timeout);
+        // a peer with a slower heartbeat is given time for a few of its keepalives
+        let keepalive = negotiate_keepalive(Some(own), Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(keepalive.timeout, Duration::from_secs(180));
+    }
+
+    #[tokio::test]
+    async fn newer_clients_are_told_the_server_version() {
+        let mut output = Vec::new();
+        let mut connection = Connection::new(frames(&[&[PROTOCOL_VERSION + 1]]), &mut output);
+        assert!(connection.receive_version().await.is_err());
+        drop(connection);
+        assert_eq!(output, [1, 0, PROTOCOL_VERSION]);
+    }
+
+    #[tokio::test]
+    async fn unversioned_config_is_reported_as_an_outdated_server() {
+        let config: Vec<u8> = (&network_config(Vec::new(), None)).into();
+        let legacy = &config[..CONFIG_SIZE];
+        // the first address octet would otherwise be read as the version
+        assert_eq!(legacy[0], 10);
+        let mut client = Connection::new(frames(&[legacy]), Vec::new());
+        let err = client.receive_config().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ProtocolError::OutdatedServer)
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "server is outdated, it predates protocol versions and this client needs version \
+                 {PROTOCOL_VERSION}"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn older_servers_are_reported_by_version() {
+        let config: Vec<u8> = (&network_config(Vec::new(), None)).into();
+        let reply = [&[PROTOCOL_VERSION - 1][..], &config].concat();
+        let mut client = Connection::new(frames(&[&reply]), Vec::new());
+        let err = client.receive_config().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ProtocolError::UnsupportedVersion(version)) if *version == PROTOCOL_VERSION - 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejection_replaces_the_config() {
+        let mut output = Vec::new();
+        let mut server = Connection::new(frames(&[]), &mut output);
+        server.send_rejection(Rejection::Maintenance).await.unwrap();
+        drop(server);
+        let mut client = Connection::new(Cursor::new(output), Vec::new());
+        let err = client.receive_config().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ProtocolError::Rejected(Rejection::Maintenance))
+        ));
+    }
+
+    #[test]
+    fn rejection_codes_round_trip() {
+        let rejections = [
+            Rejection::Maintenance,
+            Rejection::SessionExpired,
+            Rejection::IdentityLimit,
+            Rejection::ServerFull,
+            Rejection::NoFreeAddress,
+            Rejection::ProtocolMismatch,
+            Rejection::Throttled,
+        ];
+        for (code, rejection) in (1..).zip(rejections) {
+            assert_eq!(rejection.code(), code);
+            assert_eq!(Rejection::from(code), rejection);
+        }
+        assert_eq!(Rejection::from(8), Rejection::Unknown(8));
+    }
+
+    #[tokio::test]
+    async fn unknown_rejections_are_reported() {
+        let mut client = Connection::new(frames(&[&[REJECTION, 9]]), Vec::new());
+        let err = client.receive_config().await.unwrap_err();
+        assert_eq!(err.to_string(), "server rejected the connection: reason 9");
+    }
+
+    #[test]
+    fn rejection_frames_are_told_apart_from_packets() {
+        let frame = rejection_frame(Rejection::SessionExpired);
+        assert_eq!(as_rejection(&frame), Some(Rejection::SessionExpired));
+        assert_eq!(as_rejection(&[]), None);
+        assert_eq!(as_rejection(&[0x45, 0]), None);
+        assert_eq!(as_rejection(&[REJECTION, 2, 0]), None);
+    }
+
+    #[tokio::test]
+    async fn only_current_clients_are_told_why_their_session_ended() {
+        let mut connection = Connection::new(frames(&[&[ROUTES_VERSION]]), Vec::new());
+        connection.receive_version().await.unwrap();
+        assert!(!connection.accepts_disconnect());
+        let mut connection = Connection::new(frames(&[&[DISCONNECT_VERSION]]), Vec::new());
+        connection.receive_version().await.unwrap();
+        assert!(connection.accepts_disconnect());
+    }
+}
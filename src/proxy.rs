@@ -0,0 +1,320 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use anyhow::{bail, ensure, Context};
+use data_encoding::BASE64;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::{
+    config::{ProxyConfig, ProxyCredentials, ProxyProtocol},
+    cover::read_head,
+    resolver::resolve,
+};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASS: u8 = 2;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_USER_PASS_VERSION: u8 = 1;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+// the proxy host goes through the system resolver, it is usually only known on the local network.
+// The server host is left to the proxy, it may not be resolvable from behind it
+pub async fn connect(proxy: &ProxyConfig, host: &str, port: u16) -> anyhow::Result<TcpStream> {
+    let proxy_address = resolve(&proxy.host, proxy.port, None).await?;
+    let mut socket = TcpStream::connect(proxy_address)
+        .await
+        .with_context(|| format!("could not connect to proxy {proxy_address}"))?;
+    let stream = &mut (&mut socket).compat();
+    let credentials = proxy.credentials.as_ref();
+    match proxy.protocol {
+        ProxyProtocol::Socks5 => socks5_connect(stream, credentials, host, port).await,
+        ProxyProtocol::Http => http_connect(stream, credentials, host, port).await,
+    }
+    .with_context(|| format!("proxy {proxy_address} could not connect to {host}:{port}"))?;
+    Ok(socket)
+}
+
+async fn socks5_connect<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    credentials: Option<&ProxyCredentials>,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()> {
+    let method = match credentials {
+        Some(_) => SOCKS_USER_PASS,
+        None => SOCKS_NO_AUTH,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    stream.flush().await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[0] == SOCKS_VERSION, "proxy does not speak SOCKS5");
+    match (reply[1], credentials) {
+        (SOCKS_NO_AUTH, _) => {}
+        (SOCKS_USER_PASS, Some(credentials)) => socks5_authenticate(stream, credentials).await?,
+        (SOCKS_NO_ACCEPTABLE_METHOD, Some(_)) => {
+            bail!("proxy does not accept username and password authentication")
+        }
+        (SOCKS_NO_ACCEPTABLE_METHOD, None) => bail!("proxy requires authentication"),
+        (method, _) => bail!("proxy chose unsupported authentication method {method}"),
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let size = u8::try_from(host.len())
+                .ok()
+                .filter(|&size| size > 0)
+                .context("server host name must be 1 to 255 bytes long")?;
+            request.extend_from_slice(&[SOCKS_DOMAIN, size]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[0] == SOCKS_VERSION, "proxy does not speak SOCKS5");
+    if reply[1] != 0 {
+        bail!("proxy refused the connection: {}", socks5_error(reply[1]));
+    }
+    // the bound address is of no use, but it has to be consumed before the tunnel data
+    let address_size = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut size = [0u8];
+            stream.read_exact(&mut size).await?;
+            size[0].into()
+        }
+        kind => bail!("proxy replied with unknown address type {kind}"),
+    };
+    let mut bound = vec![0u8; address_size + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn socks5_authenticate<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    credentials: &ProxyCredentials,
+) -> anyhow::Result<()> {
+    // the configuration ensures both fit their length byte
+    let mut request = vec![SOCKS_USER_PASS_VERSION, credentials.username.len() as u8];
+    request.extend_from_slice(credentials.username.as_bytes());
+    request.push(credentials.password.len() as u8);
+    request.extend_from_slice(credentials.password.as_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[1] == 0, "proxy rejected the username or password");
+    Ok(())
+}
+
+fn socks5_error(code: u8) -> String {
+    match code {
+        1 => "general failure".into(),
+        2 => "not allowed by ruleset".into(),
+        3 => "network unreachable".into(),
+        4 => "host unreachable".into(),
+        5 => "connection refused".into(),
+        6 => "TTL expired".into(),
+        7 => "command not supported".into(),
+        8 => "address type not supported".into(),
+        code => format!("error {code}"),
+    }
+}
+
+async fn http_connect<IO: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut IO,
+    credentials: Option<&ProxyCredentials>,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()> {
+    let authority = match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{host}]:{port}"),
+        Err(_) => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(credentials) = credentials {
+        let token = format!("{}:{}", credentials.username, credentials.password);
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64.encode(token.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = read_head(stream)
+        .await
+        .context("could not read proxy response")?;
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some("200") => Ok(()),
+        Some("407") => bail!("proxy requires authentication: {status}"),
+        _ => bail!("proxy refused the connection: {status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::DuplexStream;
+    use tokio_util::compat::Compat;
+
+    use super::*;
+
+    fn connected() -> (Compat<DuplexStream>, Compat<DuplexStream>) {
+        let (client, proxy) = tokio::io::duplex(1024);
+        (client.compat(), proxy.compat())
+    }
+
+    fn credentials() -> ProxyCredentials {
+        ProxyCredentials {
+            username: "alice".into(),
+            password: "secret".into(),
+        }
+    }
+
+    // accepts the given method and the CONNECT, returning the requested destination
+    async fn socks5_stub(proxy: &mut Compat<DuplexStream>, method: u8) -> Vec<u8> {
+        let mut greeting = [0u8; 3];
+        proxy.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [SOCKS_VERSION, 1, method]);
+        proxy.write_all(&[SOCKS_VERSION, method]).await.unwrap();
+        if method == SOCKS_USER_PASS {
+            let mut auth = [0u8; 1 + 1 + 5 + 1 + 6];
+            proxy.read_exact(&mut auth).await.unwrap();
+            assert_eq!(auth, *b"\x01\x05alice\x06secret");
+            proxy
+                .write_all(&[SOCKS_USER_PASS_VERSION, 0])
+                .await
+                .unwrap();
+        }
+        let mut request = [0u8; 4];
+        proxy.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..3], [SOCKS_VERSION, SOCKS_CONNECT, 0]);
+        let address_size = match request[3] {
+            SOCKS_IPV4 => 4,
+            SOCKS_IPV6 => 16,
+            _ => {
+                let mut size = [0u8];
+                proxy.read_exact(&mut size).await.unwrap();
+                size[0].into()
+            }
+        };
+        let mut destination = vec![0u8; address_size + 2];
+        proxy.read_exact(&mut destination).await.unwrap();
+        proxy
+            .write_all(&[SOCKS_VERSION, 0, 0, SOCKS_IPV4, 10, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+        [&request[3..], &destination].concat()
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy_is_given_the_host_name() {
+        let (mut client, mut proxy) = connected();
+        let (res, destination) = tokio::join!(
+            socks5_connect(&mut client, None, "vpn.example.com", 443),
+            socks5_stub(&mut proxy, SOCKS_NO_AUTH)
+        );
+        res.unwrap();
+        assert_eq!(destination, b"\x03vpn.example.com\x01\xbb");
+        // the tunnel starts right after the reply
+        proxy.write_all(b"\x16\x03\x01").await.unwrap();
+        let mut hello = [0u8; 3];
+        client.read_exact(&mut hello).await.unwrap();
+        assert_eq!(hello, *b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy_is_given_literal_addresses_as_such() {
+        let credentials = credentials();
+        let (mut client, mut proxy) = connected();
+        let (res, destination) = tokio::join!(
+            socks5_connect(&mut client, Some(&credentials), "192.0.2.7", 8443),
+            socks5_stub(&mut proxy, SOCKS_USER_PASS)
+        );
+        res.unwrap();
+        assert_eq!(destination, [SOCKS_IPV4, 192, 0, 2, 7, 0x20, 0xfb]);
+    }
+
+    #[tokio::test]
+    async fn socks5_failure_is_reported() {
+        let (mut client, mut proxy) = connected();
+        let stub = async {
+            let mut greeting = [0u8; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            proxy
+                .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD])
+                .await
+                .unwrap();
+        };
+        let (res, ()) = tokio::join!(
+            socks5_connect(&mut client, None, "vpn.example.com", 443),
+            stub
+        );
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "proxy requires authentication"
+        );
+    }
+
+    async fn http_stub(proxy: &mut Compat<DuplexStream>, status: &[u8]) -> String {
+        let head = read_head(proxy).await.unwrap();
+        proxy.write_all(status).await.unwrap();
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_proxy_is_given_the_host_as_the_authority() {
+        let credentials = credentials();
+        let (mut client, mut proxy) = connected();
+        let (res, head) = tokio::join!(
+            http_connect(&mut client, Some(&credentials), "vpn.example.com", 443),
+            http_stub(&mut proxy, b"HTTP/1.1 200 Connection established\r\n\r\n")
+        );
+        res.unwrap();
+        assert_eq!(
+            head,
+            "CONNECT vpn.example.com:443 HTTP/1.1\r\nHost: vpn.example.com:443\r\n\
+             Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn http_proxy_gets_bracketed_ipv6_authorities() {
+        let (mut client, mut proxy) = connected();
+        let (res, head) = tokio::join!(
+            http_connect(&mut client, None, "2001:db8::1", 443),
+            http_stub(
+                &mut proxy,
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"
+            )
+        );
+        assert!(head.starts_with("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"));
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .starts_with("proxy requires authentication"));
+    }
+}
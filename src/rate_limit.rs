@@ -0,0 +1,239 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.into(),
+            tokens: rate.into(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+pub struct PacketRateLimiter {
+    bucket: Option<TokenBucket>,
+    dropped: u64,
+}
+
+impl PacketRateLimiter {
+    pub fn new(max_pps: Option<u32>) -> Self {
+        Self {
+            bucket: max_pps.map(TokenBucket::new),
+            dropped: 0,
+        }
+    }
+
+    pub fn admit(&mut self) -> bool {
+        let admitted = self.bucket.as_mut().is_none_or(TokenBucket::try_take);
+        if !admitted {
+            self.dropped += 1;
+        }
+        admitted
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+const THROTTLE_BASE_BLOCK: Duration = Duration::from_secs(1);
+const THROTTLE_MAX_BLOCK: Duration = Duration::from_secs(60);
+const THROTTLE_IDLE_EXPIRY: Duration = Duration::from_secs(60);
+
+struct SourceState {
+    bucket: TokenBucket,
+    strikes: u32,
+    blocked_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AttemptVerdict {
+    Admit,
+    // the attempt that got its source blocked, it is told so before being closed
+    Refuse,
+    // later attempts while blocked are closed without a handshake
+    Drop,
+}
+
+pub struct ConnectionThrottle {
+    rate: u32,
+    sources: HashMap<IpAddr, SourceState>,
+    last_sweep: Instant,
+    // rejections since the last report
+    dropped: u64,
+    dropped_sources: HashSet<IpAddr>,
+}
+
+impl ConnectionThrottle {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            sources: HashMap::new(),
+            last_sweep: Instant::now(),
+            dropped: 0,
+            dropped_sources: HashSet::new(),
+        }
+    }
+
+    pub fn admit(&mut self, source: IpAddr) -> AttemptVerdict {
+        let verdict = self.check(source);
+        if verdict != AttemptVerdict::Admit {
+            self.dropped += 1;
+            self.dropped_sources.insert(source);
+        }
+        verdict
+    }
+
+    // the number of rejected attempts and of distinct sources they came from
+    pub fn take_dropped(&mut self) -> (u64, usize) {
+        let sources = self.dropped_sources.len();
+        self.dropped_sources.clear();
+        (std::mem::take(&mut self.dropped), sources)
+    }
+
+    fn check(&mut self, source: IpAddr) -> AttemptVerdict {
+        let now = Instant::now();
+        self.sweep(now);
+
+        let rate = self.rate;
+        let state = self.sources.entry(source).or_insert_with(|| SourceState {
+            bucket: TokenBucket::new(rate),
+            strikes: 0,
+            blocked_until: None,
+            last_seen: now,
+        });
+        state.last_seen = now;
+        if state.blocked_until.is_some_and(|until| now < until) {
+            return AttemptVerdict::Drop;
+        }
+        if state.bucket.try_take() {
+            return AttemptVerdict::Admit;
+        }
+
+        let block = THROTTLE_BASE_BLOCK
+            .saturating_mul(1 << state.strikes.min(6))
+            .min(THROTTLE_MAX_BLOCK);
+        state.strikes += 1;
+        state.blocked_until = Some(now + block);
+        AttemptVerdict::Refuse
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < THROTTLE_IDLE_EXPIRY {
+            return;
+        }
+        self.last_sweep = now;
+        self.sources.retain(|_, state| {
+            state.blocked_until.is_some_and(|until| now < until)
+                || now.duration_since(state.last_seen) < THROTTLE_IDLE_EXPIRY
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn burst_above_the_limit_is_dropped() {
+        let mut limiter = PacketRateLimiter::new(Some(10));
+        let admitted = (0..25).filter(|_| limiter.admit()).count();
+        assert_eq!(admitted, 10);
+        assert_eq!(limiter.dropped(), 15);
+    }
+
+    #[test]
+    fn traffic_below_the_limit_passes() {
+        let mut limiter = PacketRateLimiter::new(Some(500));
+        // start from an empty bucket so that only the refill rate counts
+        while limiter.admit() {}
+        let dropped = limiter.dropped();
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(5));
+            assert!(limiter.admit());
+        }
+        assert_eq!(limiter.dropped(), dropped);
+    }
+
+    #[test]
+    fn tokens_refill_after_a_burst() {
+        let mut limiter = PacketRateLimiter::new(Some(100));
+        while limiter.admit() {}
+        thread::sleep(Duration::from_millis(50));
+        assert!(limiter.admit());
+    }
+
+    #[test]
+    fn no_limit_admits_everything() {
+        let mut limiter = PacketRateLimiter::new(None);
+        assert!((0..10_000).all(|_| limiter.admit()));
+    }
+
+    #[test]
+    fn throttle_blocks_only_the_noisy_source() {
+        let noisy = IpAddr::from([192, 0, 2, 1]);
+        let quiet = IpAddr::from([192, 0, 2, 2]);
+        let mut throttle = ConnectionThrottle::new(5);
+        let admitted = (0..20).filter(|_| throttle.admit(noisy) == AttemptVerdict::Admit);
+        assert_eq!(admitted.count(), 5);
+        // blocked for a while even once tokens would be available again
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(throttle.admit(noisy), AttemptVerdict::Drop);
+        assert_eq!(throttle.admit(quiet), AttemptVerdict::Admit);
+    }
+
+    #[test]
+    fn only_the_attempt_that_blocks_a_source_is_refused() {
+        let source = IpAddr::from([192, 0, 2, 1]);
+        let mut throttle = ConnectionThrottle::new(2);
+        let verdicts: Vec<_> = (0..5).map(|_| throttle.admit(source)).collect();
+        assert_eq!(
+            verdicts,
+            [
+                AttemptVerdict::Admit,
+                AttemptVerdict::Admit,
+                AttemptVerdict::Refuse,
+                AttemptVerdict::Drop,
+                AttemptVerdict::Drop,
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_attempts_are_summarised_once() {
+        let mut throttle = ConnectionThrottle::new(1);
+        for source in [[192, 0, 2, 1], [192, 0, 2, 2]] {
+            for _ in 0..4 {
+                throttle.admit(source.into());
+            }
+        }
+        assert_eq!(throttle.take_dropped(), (6, 2));
+        assert_eq!(throttle.take_dropped(), (0, 0));
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Context;
+use hickory_resolver::{
+    config::{NameServerConfig, ResolverConfig},
+    net::runtime::TokioRuntimeProvider,
+    Resolver,
+};
+use tokio::{net, time::Instant};
+
+// keeps a resolved address between reconnects, it is resolved again once it is older
+// than the TTL, to follow DNS changes, or once it has been invalidated
+pub struct AddressCache {
+    ttl: Duration,
+    entry: Mutex<Option<(SocketAddr, Instant)>>,
+}
+
+impl AddressCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    pub async fn get_or_resolve(
+        &self,
+        resolve: impl Future<Output = anyhow::Result<SocketAddr>>,
+    ) -> anyhow::Result<SocketAddr> {
+        let cached = *self.entry.lock().unwrap();
+        if let Some((address, resolved)) = cached {
+            if resolved.elapsed() < self.ttl {
+                return Ok(address);
+            }
+        }
+        let address = resolve.await?;
+        *self.entry.lock().unwrap() = Some((address, Instant::now()));
+        Ok(address)
+    }
+
+    pub fn invalidate(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
+
+pub async fn resolve(
+    host: &str,
+    port: u16,
+    nameserver: Option<IpAddr>,
+) -> anyhow::Result<SocketAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    let ip = match nameserver {
+        Some(nameserver) => {
+            let config =
+                ResolverConfig::from_name_servers(vec![NameServerConfig::udp_and_tcp(nameserver)]);
+            Resolver::builder_with_config(config, TokioRuntimeProvider::default())
+                .build()
+                .context("could not create resolver")?
+                .lookup_ip(host)
+                .await
+                .with_context(|| format!("could not resolve {host} via {nameserver}"))?
+                .iter()
+                .next()
+        }
+        None => net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("could not resolve {host}"))?
+            .map(|addr| addr.ip())
+            .next(),
+    };
+    ip.map(|ip| SocketAddr::new(ip, port))
+        .with_context(|| format!("no addresses found for {host}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use tokio::time;
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(300);
+
+    async fn lookup(lookups: &AtomicU32) -> anyhow::Result<SocketAddr> {
+        let count = lookups.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok(SocketAddr::new(
+            Ipv4Addr::new(192, 0, 2, count as u8).into(),
+            443,
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn address_is_reused_until_the_ttl_expires() {
+        let cache = AddressCache::new(TTL);
+        let lookups = AtomicU32::new(0);
+        let first = cache.get_or_resolve(lookup(&lookups)).await.unwrap();
+        for _ in 0..3 {
+            time::advance(TTL / 4).await;
+            assert_eq!(cache.get_or_resolve(lookup(&lookups)).await.unwrap(), first);
+        }
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+
+        time::advance(TTL / 4).await;
+        let second = cache.get_or_resolve(lookup(&lookups)).await.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalidated_address_is_resolved_again() {
+        let cache = AddressCache::new(TTL);
+        let lookups = AtomicU32::new(0);
+        let first = cache.get_or_resolve(lookup(&lookups)).await.unwrap();
+        cache.invalidate();
+        assert_ne!(cache.get_or_resolve(lookup(&lookups)).await.unwrap(), first);
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_resolution_is_not_cached() {
+        let cache = AddressCache::new(TTL);
+        assert!(cache
+            .get_or_resolve(async { anyhow::bail!("no addresses") })
+            .await
+            .is_err());
+        let lookups = AtomicU32::new(0);
+        cache.get_or_resolve(lookup(&lookups)).await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+    }
+}
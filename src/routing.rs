@@ -1,34 +1,125 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
     net::{IpAddr, Ipv4Addr},
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::{bail, ensure, Context};
 use etherparse::IpSlice;
-use log::{error, warn};
-use tokio::sync::{Mutex, RwLock};
+use futures::io;
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, Mutex, Notify, RwLock},
+    task::AbortHandle,
+    time,
+};
+use tokio_util::task::TaskTracker;
 
 use crate::{
+    common::{random_u64, temp_path, ClientIdentity},
+    config::{AllocationStrategy, SubnetRoute},
+    filter::{Direction, FilterVerdict, PacketFilter},
     ip_manager::IpManager,
-    packet_stream::{DynPacketSender, PacketReceiver, PacketSender},
+    packet_stream::{DynPacketSender, PacketReceiver, PacketSender, TrafficSnapshot},
+    prefix_table::PrefixTable,
+    system_route::{add_interface_route, remove_interface_route},
 };
 
+const MAX_TRACKED_DESTINATIONS: usize = 1024;
+const TUN_QUEUE_DEPTH: usize = 256;
+const LEASE_EXPORT_DEBOUNCE: Duration = Duration::from_millis(500);
+
 type PacketSink = Box<dyn DynPacketSender>;
 
+struct Route {
+    sink: Mutex<PacketSink>,
+    pending: AtomicUsize,
+    high_water: AtomicUsize,
+    // a failed send can leave a partial frame behind, the session has to end
+    failed: Notify,
+}
+
+struct PendingGuard<'a>(&'a AtomicUsize);
+
+#[derive(Serialize)]
+struct LeaseRecord {
+    address: Ipv4Addr,
+    common_name: String,
+    connected_at: u64,
+}
+
+#[derive(Default)]
+struct LeaseTable {
+    records: std::sync::Mutex<BTreeMap<Ipv4Addr, LeaseRecord>>,
+    changed: Notify,
+}
+
+struct HeldAddress {
+    common_name: String,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct DestinationStats {
+    by_destination: HashMap<Ipv4Addr, TrafficSnapshot>,
+    untracked: TrafficSnapshot,
+}
+
 pub struct Router<S: PacketSender> {
     ip_manager: Mutex<IpManager>,
-    routes: RwLock<HashMap<Ipv4Addr, Mutex<PacketSink>>>,
+    routes: RwLock<HashMap<Ipv4Addr, Arc<Route>>>,
+    // whole subnets reachable through a client acting as their gateway
+    subnet_routes: std::sync::RwLock<PrefixTable<Ipv4Addr>>,
     tun_writer: Mutex<S>,
+    broadcast: Ipv4Addr,
+    forward_broadcast: bool,
+    static_leases: HashMap<String, Ipv4Addr>,
+    static_in_use: Mutex<HashSet<Ipv4Addr>>,
+    allocation: AllocationStrategy,
+    filter: Box<dyn PacketFilter>,
+    destination_stats: std::sync::Mutex<DestinationStats>,
+    lease_table: Arc<LeaseTable>,
+    leased: AtomicU32,
+    // most addresses leased at once since startup
+    peak_leased: AtomicU32,
+    reclaim_grace: Option<Duration>,
+    held: std::sync::Mutex<HashMap<Ipv4Addr, HeldAddress>>,
+    hold_generation: AtomicU64,
+    interface: Option<String>,
+    cleanup_tasks: TaskTracker,
+    // aborted on drop, the tasks only hold weak references so they cannot keep the router alive
+    background_tasks: Vec<AbortHandle>,
 }
 
 pub struct RouterConfig {
     pub address: Ipv4Addr,
     pub netmask: Ipv4Addr,
+    pub forward_broadcast: bool,
+    pub static_leases: HashMap<String, Ipv4Addr>,
+    pub allocation: AllocationStrategy,
+    pub filter: Box<dyn PacketFilter>,
+    pub lease_file: Option<PathBuf>,
+    pub reclaim_grace: Option<Duration>,
+    pub subnet_routes: Vec<SubnetRoute>,
+    // the TUN interface the kernel routes for subnet_routes and advertised subnets point to
+    pub interface: Option<String>,
 }
 
 pub struct IpLease<S: PacketSender + 'static> {
     router: Arc<Router<S>>,
     addr: Ipv4Addr,
+    common_name: String,
+    is_static: bool,
+    // subnets the client announced as reachable through itself
+    advertised: Vec<(Ipv4Addr, u8)>,
+    route: Option<Arc<Route>>,
 }
 
 enum RoutingResult {
@@ -36,7 +127,6 @@ enum RoutingResult {
     NotIP,
     NoIPv4,
     NoRoute,
-    Error(anyhow::Error),
 }
 
 impl<S: PacketSender + 'static> Router<S> {
@@ -46,77 +136,375 @@ impl<S: PacketSender + 'static> Router<S> {
         tun_receiver: R,
     ) -> Arc<Self> {
         let mut ip_manager = IpManager::new(config.address, config.netmask);
-        ip_manager.block(config.address);
+        for addr in config.static_leases.values() {
+            ip_manager.block(*addr);
+        }
 
-        let router = Arc::new(Self {
-            ip_manager: ip_manager.into(),
-            routes: HashMap::new().into(),
-            tun_writer: tun_sender.into(),
-        });
+        let mut subnet_routes = PrefixTable::default();
+        for route in config.subnet_routes {
+            subnet_routes.insert(route.prefix, route.prefix_len, route.gateway);
+            add_kernel_route(config.interface.as_deref(), route.prefix, route.prefix_len);
+        }
+        let lease_table = Arc::new(LeaseTable::default());
+        Arc::new_cyclic(|router| {
+            let (packet_sender, packet_receiver) = mpsc::channel(TUN_QUEUE_DEPTH);
+            let mut background_tasks = vec![
+                tokio::spawn(read_tun(tun_receiver, packet_sender)).abort_handle(),
+                tokio::spawn(Self::route_incoming(router.clone(), packet_receiver)).abort_handle(),
+            ];
+            if let Some(path) = config.lease_file {
+                background_tasks
+                    .push(tokio::spawn(lease_table.clone().export(path)).abort_handle());
+            }
 
-        tokio::spawn(router.clone().route_incoming(tun_receiver));
-        router
+            Self {
+                ip_manager: ip_manager.into(),
+                routes: HashMap::new().into(),
+                subnet_routes: subnet_routes.into(),
+                tun_writer: tun_sender.into(),
+                broadcast: Ipv4Addr::from_bits(
+                    config.address.to_bits() | !config.netmask.to_bits(),
+                ),
+                forward_broadcast: config.forward_broadcast,
+                static_leases: config.static_leases,
+                static_in_use: HashSet::new().into(),
+                allocation: config.allocation,
+                filter: config.filter,
+                destination_stats: Default::default(),
+                lease_table,
+                leased: AtomicU32::new(0),
+                peak_leased: AtomicU32::new(0),
+                reclaim_grace: config.reclaim_grace,
+                held: Default::default(),
+                hold_generation: AtomicU64::new(0),
+                interface: config.interface,
+                cleanup_tasks: TaskTracker::new(),
+                background_tasks,
+            }
+        })
     }
 
-    pub async fn route_packet(&self, packet: Box<[u8]>) -> anyhow::Result<()> {
-        match self.route_local(&packet).await {
-            RoutingResult::Error(err) => return Err(err),
-            RoutingResult::Ok => return Ok(()),
-            _ => {}
-        };
+    // arrived_on is the leased address of the client the packet came from
+    pub async fn route_packet(
+        &self,
+        packet: Box<[u8]>,
+        arrived_on: Ipv4Addr,
+    ) -> anyhow::Result<()> {
+        if self.filter.allow(&packet, Direction::FromClient) == FilterVerdict::Drop {
+            return Ok(());
+        }
+        if let RoutingResult::Ok = self.route_local(&packet, Some(arrived_on)).await {
+            return Ok(());
+        }
 
         let mut lock = self.tun_writer.lock().await;
         lock.send(&packet).await?;
         Ok(())
     }
 
-    pub async fn get_ip(self: Arc<Self>) -> Option<IpLease<S>> {
-        let mut lock = self.ip_manager.lock().await;
-        lock.get_free().map(|ip| {
-            lock.block(ip);
-            IpLease {
-                addr: ip,
-                router: self.clone(),
-            }
+    pub async fn get_ip(self: Arc<Self>, identity: &ClientIdentity) -> anyhow::Result<IpLease<S>> {
+        let (addr, is_static) = self.allocate(identity).await?;
+        self.lease_table.insert(LeaseRecord {
+            address: addr,
+            common_name: identity.common_name.clone(),
+            connected_at: unix_time(),
+        });
+        let leased = self.leased.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_leased.fetch_max(leased, Ordering::Relaxed);
+        Ok(IpLease {
+            addr,
+            router: self,
+            common_name: identity.common_name.clone(),
+            is_static,
+            advertised: Vec::new(),
+            route: None,
         })
     }
 
-    async fn route_incoming<R: PacketReceiver>(self: Arc<Self>, mut tun_receiver: R) {
-        loop {
-            let packet = match tun_receiver.receive().await {
-                Ok(packet) => packet,
-                Err(e) => {
-                    error!("could not read packet from tun: {e}");
-                    continue;
+    async fn allocate(&self, identity: &ClientIdentity) -> anyhow::Result<(Ipv4Addr, bool)> {
+        if let Some(&addr) = self.static_leases.get(&identity.common_name) {
+            ensure!(
+                self.ip_manager.lock().await.is_assignable(addr),
+                "static address {addr} is reserved"
+            );
+            ensure!(
+                self.static_in_use.lock().await.insert(addr),
+                "static address {addr} is already in use"
+            );
+            return Ok((addr, true));
+        }
+        if let Some(addr) = self.reclaim_held(&identity.common_name) {
+            info!("{} reclaimed held address {addr}", identity.common_name);
+            return Ok((addr, false));
+        }
+
+        let mut lock = self.ip_manager.lock().await;
+        let addr = match self.allocation {
+            AllocationStrategy::Sequential => lock.get_free(),
+            AllocationStrategy::Descending => lock.get_free_highest(),
+            AllocationStrategy::Random => lock.get_free_near(random_u64()),
+            AllocationStrategy::DeriveFromKey => lock.get_free_near(identity.key_hash),
+        }
+        .context("no free addresses left")?;
+        ensure!(
+            lock.is_assignable(addr),
+            "refusing to assign reserved address {addr}"
+        );
+        lock.block(addr);
+        Ok((addr, false))
+    }
+
+    fn reclaim_held(&self, common_name: &str) -> Option<Ipv4Addr> {
+        let mut held = self.held.lock().unwrap();
+        let addr = held
+            .iter()
+            .find(|(_, held)| held.common_name == common_name)
+            .map(|(&addr, _)| addr)?;
+        held.remove(&addr);
+        Some(addr)
+    }
+
+    // the address stays blocked in the IpManager until the grace period runs out
+    fn hold(self: &Arc<Self>, addr: Ipv4Addr, common_name: String, grace: Duration) {
+        let generation = self.hold_generation.fetch_add(1, Ordering::Relaxed);
+        self.held.lock().unwrap().insert(
+            addr,
+            HeldAddress {
+                common_name,
+                generation,
+            },
+        );
+        let router = Arc::downgrade(self);
+        tokio::spawn(async move {
+            time::sleep(grace).await;
+            let Some(router) = router.upgrade() else {
+                return;
+            };
+            let expired = {
+                let mut held = router.held.lock().unwrap();
+                let expired = held
+                    .get(&addr)
+                    .is_some_and(|held| held.generation == generation);
+                if expired {
+                    held.remove(&addr);
                 }
+                expired
             };
+            if expired {
+                router.ip_manager.lock().await.release(addr);
+            }
+        });
+    }
 
-            match self.route_local(&packet).await {
+    pub fn leased(&self) -> u32 {
+        self.leased.load(Ordering::Relaxed)
+    }
+
+    pub fn peak_leased(&self) -> u32 {
+        self.peak_leased.load(Ordering::Relaxed)
+    }
+
+    pub fn top_destinations(
+        &self,
+        count: usize,
+    ) -> (Vec<(Ipv4Addr, TrafficSnapshot)>, TrafficSnapshot) {
+        let stats = self.destination_stats.lock().unwrap();
+        let mut top: Vec<_> = stats
+            .by_destination
+            .iter()
+            .map(|(&addr, &traffic)| (addr, traffic))
+            .collect();
+        top.sort_unstable_by_key(|(_, traffic)| std::cmp::Reverse(traffic.bytes));
+        top.truncate(count);
+        (top, stats.untracked)
+    }
+
+    // never waits on the data path, a lock that is busy at every probe is taken to be stuck
+    pub fn probe(&self) -> bool {
+        self.ip_manager.try_lock().is_ok()
+            && self.routes.try_read().is_ok()
+            && self.tun_writer.try_lock().is_ok()
+    }
+
+    pub async fn shutdown(&self) {
+        self.cleanup_tasks.close();
+        self.cleanup_tasks.wait().await;
+    }
+
+    async fn route_incoming(router: Weak<Self>, mut packets: mpsc::Receiver<Box<[u8]>>) {
+        while let Some(packet) = packets.recv().await {
+            let Some(router) = router.upgrade() else {
+                return;
+            };
+            if router.filter.allow(&packet, Direction::FromTun) == FilterVerdict::Drop {
+                continue;
+            }
+
+            match router.route_local(&packet, None).await {
                 RoutingResult::Ok => {}
                 RoutingResult::NotIP => warn!("destination IP does not belong to VPN"),
                 RoutingResult::NoIPv4 => warn!("incoming packet without IPv4 destination"),
                 RoutingResult::NoRoute => warn!("no route for incoming packet"),
-                RoutingResult::Error(e) => error!("could not route incoming packet: {e}"),
             }
         }
     }
 
-    async fn route_local(&self, packet: &[u8]) -> RoutingResult {
+    async fn route_local(&self, packet: &[u8], arrived_on: Option<Ipv4Addr>) -> RoutingResult {
         let Ok(ip_slice) = IpSlice::from_slice(packet) else {
             return RoutingResult::NotIP;
         };
         let IpAddr::V4(destination) = ip_slice.destination_addr() else {
             return RoutingResult::NoIPv4;
         };
-        let routes = self.routes.read().await;
-        let Some(route) = routes.get(&destination) else {
+        if self.forward_broadcast && self.is_broadcast(destination) {
+            self.route_broadcast(packet, arrived_on).await;
+            return RoutingResult::Ok;
+        }
+        // a slow client only holds up its own route, not the table
+        let route = {
+            let routes = self.routes.read().await;
+            let route = routes.get_key_value(&destination).or_else(|| {
+                let gateway = *self
+                    .subnet_routes
+                    .read()
+                    .unwrap()
+                    .longest_match(destination)?;
+                routes.get_key_value(&gateway)
+            });
+            route.map(|(&addr, route)| (addr, route.clone()))
+        };
+        let Some((addr, route)) = route else {
             return RoutingResult::NoRoute;
         };
-        if let Err(err) = route.lock().await.send_dyn(packet).await {
-            return RoutingResult::Error(err.into());
+        if let Err(err) = route.send(packet).await {
+            self.evict(addr, &route, err).await;
+            return RoutingResult::Ok;
         }
+        self.destination_stats
+            .lock()
+            .unwrap()
+            .record(destination, packet.len());
         RoutingResult::Ok
     }
+
+    fn is_broadcast(&self, addr: Ipv4Addr) -> bool {
+        addr == self.broadcast || addr.is_broadcast() || addr.is_multicast()
+    }
+
+    // the sender already has its own broadcast, delivering it back invites loops.
+    // The source address is up to the client, so it cannot tell who the sender is
+    async fn route_broadcast(&self, packet: &[u8], arrived_on: Option<Ipv4Addr>) {
+        let routes: Vec<_> = self
+            .routes
+            .read()
+            .await
+            .iter()
+            .filter(|(&addr, _)| Some(addr) != arrived_on)
+            .map(|(&addr, route)| (addr, route.clone()))
+            .collect();
+        for (addr, route) in routes {
+            if let Err(err) = route.send(packet).await {
+                self.evict(addr, &route, err).await;
+            }
+        }
+    }
+
+    // the route may have been replaced by a new session in the meantime, which stays
+    async fn evict(&self, addr: Ipv4Addr, route: &Arc<Route>, err: io::Error) {
+        warn!("dropping route to {addr}, could not send to it: {err}");
+        let mut routes = self.routes.write().await;
+        if routes
+            .get(&addr)
+            .is_some_and(|current| Arc::ptr_eq(current, route))
+        {
+            routes.remove(&addr);
+        }
+        route.failed.notify_one();
+    }
+}
+
+impl<S: PacketSender> Drop for Router<S> {
+    fn drop(&mut self) {
+        for task in &self.background_tasks {
+            task.abort();
+        }
+    }
+}
+
+impl LeaseTable {
+    fn insert(&self, record: LeaseRecord) {
+        self.records.lock().unwrap().insert(record.address, record);
+        self.changed.notify_one();
+    }
+
+    fn remove(&self, addr: Ipv4Addr) {
+        self.records.lock().unwrap().remove(&addr);
+        self.changed.notify_one();
+    }
+
+    async fn export(self: Arc<Self>, path: PathBuf) {
+        loop {
+            if let Err(e) = self.write(&path).await {
+                warn!("could not export leases to {}: {e:#}", path.display());
+            }
+            self.changed.notified().await;
+            // coalesce the bursts of changes a reconnect storm produces
+            time::sleep(LEASE_EXPORT_DEBOUNCE).await;
+        }
+    }
+
+    async fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = {
+            let records = self.records.lock().unwrap();
+            serde_json::to_vec_pretty(&records.values().collect::<Vec<_>>())?
+        };
+        // readers must never observe a partially written table
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let temp_path = temp_path(&path);
+            fs::write(&temp_path, json).context("could not write temporary file")?;
+            fs::rename(&temp_path, &path).context("could not replace lease file")
+        })
+        .await?
+    }
+}
+
+impl DestinationStats {
+    fn record(&mut self, destination: Ipv4Addr, packet_size: usize) {
+        let tracked = self.by_destination.len() < MAX_TRACKED_DESTINATIONS
+            || self.by_destination.contains_key(&destination);
+        let traffic = if tracked {
+            self.by_destination.entry(destination).or_default()
+        } else {
+            &mut self.untracked
+        };
+        traffic.packets += 1;
+        traffic.bytes += packet_size as u64;
+    }
+}
+
+impl Route {
+    fn new(sink: PacketSink) -> Self {
+        Self {
+            sink: sink.into(),
+            pending: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            failed: Notify::new(),
+        }
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let depth = self.pending.fetch_add(1, Ordering::Relaxed) + 1;
+        let _guard = PendingGuard(&self.pending);
+        self.high_water.fetch_max(depth, Ordering::Relaxed);
+        self.sink.lock().await.send_dyn(packet).await
+    }
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl<S: PacketSender + 'static> IpLease<S> {
@@ -124,29 +512,498 @@ impl<S: PacketSender + 'static> IpLease<S> {
         self.addr
     }
 
-    pub async fn set_route<Sink: PacketSender + 'static>(&self, route: Sink) {
+    pub async fn set_route<Sink: PacketSender + 'static>(&mut self, route: Sink) {
         let sink: PacketSink = Box::new(route);
+        let route = Arc::new(Route::new(sink));
         _ = self
             .router
             .routes
             .write()
             .await
-            .insert(self.addr, sink.into());
+            .insert(self.addr, route.clone());
+        self.route = Some(route);
+    }
+
+    // for frames outside the packet flow, such as the one ending the session
+    pub async fn send(&self, frame: &[u8]) -> io::Result<()> {
+        match &self.route {
+            Some(route) => route.send(frame).await,
+            None => Ok(()),
+        }
+    }
+
+    // resolves once sending to the client failed and its route was dropped
+    pub async fn route_failed(&self) {
+        match &self.route {
+            Some(route) => route.failed.notified().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn advertise(&mut self, prefix: Ipv4Addr, len: u8) -> anyhow::Result<()> {
+        let mut subnet_routes = self.router.subnet_routes.write().unwrap();
+        if let Some(gateway) = subnet_routes.get(prefix, len) {
+            bail!("{prefix}/{len} is already routed through {gateway}");
+        }
+        subnet_routes.insert(prefix, len, self.addr);
+        drop(subnet_routes);
+        self.advertised.push((prefix, len));
+        add_kernel_route(self.router.interface.as_deref(), prefix, len);
+        Ok(())
     }
 }
 
 impl<S: PacketSender + 'static> Drop for IpLease<S> {
     fn drop(&mut self) {
         let addr = self.addr;
+        self.router.leased.fetch_sub(1, Ordering::Relaxed);
+        if !self.advertised.is_empty() {
+            let mut subnet_routes = self.router.subnet_routes.write().unwrap();
+            for &(prefix, len) in &self.advertised {
+                subnet_routes.remove(prefix, len);
+                info!("route to {prefix}/{len} through {addr} withdrawn");
+            }
+            drop(subnet_routes);
+            if let Some(interface) = &self.router.interface {
+                for (prefix, len) in self.advertised.drain(..) {
+                    if let Err(e) = remove_interface_route(prefix, len, interface) {
+                        warn!("could not remove kernel route to {prefix}/{len}: {e:#}");
+                    }
+                }
+            }
+        }
+        let is_static = self.is_static;
+        let common_name = std::mem::take(&mut self.common_name);
         let router = self.router.clone();
-        tokio::spawn(async move {
+        self.router.cleanup_tasks.spawn(async move {
             let route = router.routes.write().await.remove(&addr);
-            if let Some(sink) = route {
-                if let Err(e) = sink.lock().await.close_dyn().await {
+            if let Some(route) = route {
+                info!(
+                    "route to {addr} removed, send queue high-water mark {}",
+                    route.high_water.load(Ordering::Relaxed)
+                );
+                if let Err(e) = route.sink.lock().await.close_dyn().await {
                     warn!("could not close stream to {addr}: {e}");
                 }
             }
-            router.ip_manager.lock().await.release(addr);
+            if is_static {
+                router.static_in_use.lock().await.remove(&addr);
+            } else if let Some(grace) = router.reclaim_grace {
+                router.hold(addr, common_name, grace);
+            } else {
+                router.ip_manager.lock().await.release(addr);
+            }
+            router.lease_table.remove(addr);
+        });
+    }
+}
+
+// without it only traffic between clients reaches the subnet, the server's own traffic and
+// forwarded traffic would not enter the TUN interface
+fn add_kernel_route(interface: Option<&str>, prefix: Ipv4Addr, len: u8) {
+    let Some(interface) = interface else {
+        return;
+    };
+    if let Err(e) = add_interface_route(prefix, len, interface) {
+        warn!("could not add kernel route to {prefix}/{len}: {e:#}");
+    }
+}
+
+// the bounded queue makes the reader wait for the router instead of reading ahead unboundedly
+async fn read_tun<R: PacketReceiver>(mut tun_receiver: R, packets: mpsc::Sender<Box<[u8]>>) {
+    loop {
+        let packet = match tun_receiver.receive().await {
+            Ok(packet) => packet,
+            Err(e) => {
+                error!("could not read packet from tun: {e}");
+                continue;
+            }
+        };
+        if packets.send(packet).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+
+    use super::*;
+    use crate::{
+        filter::{AllowAll, DestinationBlocklist},
+        packet_stream::{
+            mock::{self, MockReceiver, MockSender, StalledSender},
+            TimeoutSender,
+        },
+    };
+
+    struct TestRouter {
+        router: Arc<Router<MockSender>>,
+        tun_output: MockReceiver,
+        // packets arriving from the TUN, held open so the reader waits instead of failing
+        tun_input: MockSender,
+    }
+
+    fn router(configure: impl FnOnce(&mut RouterConfig)) -> TestRouter {
+        let (tun_sender, tun_output) = mock::channel();
+        let (tun_input, tun_receiver) = mock::channel();
+        let mut config = RouterConfig {
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            forward_broadcast: false,
+            static_leases: HashMap::new(),
+            allocation: AllocationStrategy::Sequential,
+            filter: Box::new(AllowAll),
+            lease_file: None,
+            reclaim_grace: None,
+            subnet_routes: Vec::new(),
+            interface: None,
+        };
+        configure(&mut config);
+        TestRouter {
+            router: Router::new(config, tun_sender, tun_receiver),
+            tun_output,
+            tun_input,
+        }
+    }
+
+    fn identity(common_name: &str) -> ClientIdentity {
+        ClientIdentity {
+            common_name: common_name.into(),
+            key_hash: 0,
+        }
+    }
+
+    async fn connect(
+        router: &Arc<Router<MockSender>>,
+        common_name: &str,
+    ) -> (IpLease<MockSender>, MockReceiver) {
+        let mut lease = router.clone().get_ip(&identity(common_name)).await.unwrap();
+        let (sender, receiver) = mock::channel();
+        lease.set_route(sender).await;
+        (lease, receiver)
+    }
+
+    fn packet(source: Ipv4Addr, destination: Ipv4Addr) -> Box<[u8]> {
+        let builder =
+            PacketBuilder::ipv4(source.octets(), destination.octets(), 64).udp(5353, 5353);
+        let mut packet = Vec::new();
+        builder.write(&mut packet, b"discovery").unwrap();
+        packet.into()
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_every_client_but_the_sender() {
+        let test = router(|config| config.forward_broadcast = true);
+        let (sender, mut sender_packets) = connect(&test.router, "sender").await;
+        let (_first, mut first_packets) = connect(&test.router, "first").await;
+        let (_second, mut second_packets) = connect(&test.router, "second").await;
+
+        for destination in [Ipv4Addr::new(10, 0, 0, 255), Ipv4Addr::new(224, 0, 0, 251)] {
+            let packet = packet(sender.get_address(), destination);
+            test.router
+                .route_packet(packet.clone(), sender.get_address())
+                .await
+                .unwrap();
+            assert_eq!(first_packets.try_receive(), Some(packet.clone()));
+            assert_eq!(second_packets.try_receive(), Some(packet));
+            assert_eq!(sender_packets.try_receive(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn spoofed_source_does_not_exclude_another_client() {
+        let test = router(|config| config.forward_broadcast = true);
+        let (sender, mut sender_packets) = connect(&test.router, "sender").await;
+        let (victim, mut victim_packets) = connect(&test.router, "victim").await;
+
+        let packet = packet(victim.get_address(), Ipv4Addr::new(10, 0, 0, 255));
+        test.router
+            .route_packet(packet.clone(), sender.get_address())
+            .await
+            .unwrap();
+        assert_eq!(victim_packets.try_receive(), Some(packet));
+        assert_eq!(sender_packets.try_receive(), None);
+    }
+
+    #[tokio::test]
+    async fn broadcast_is_not_fanned_out_unless_enabled() {
+        let mut test = router(|_| {});
+        let (sender, _) = connect(&test.router, "sender").await;
+        let (_other, mut other_packets) = connect(&test.router, "other").await;
+
+        let packet = packet(sender.get_address(), Ipv4Addr::new(10, 0, 0, 255));
+        test.router
+            .route_packet(packet.clone(), sender.get_address())
+            .await
+            .unwrap();
+        assert_eq!(other_packets.try_receive(), None);
+        assert_eq!(test.tun_output.try_receive(), Some(packet));
+    }
+
+    #[tokio::test]
+    async fn blocked_destination_is_dropped_from_clients() {
+        let blocked = Ipv4Addr::new(192, 0, 2, 1);
+        let mut test = router(|config| {
+            config.filter = Box::new(DestinationBlocklist::new([blocked]));
+        });
+        let (sender, _) = connect(&test.router, "sender").await;
+        let (other, mut other_packets) = connect(&test.router, "other").await;
+
+        let to_blocked = packet(sender.get_address(), blocked);
+        let to_internet = packet(sender.get_address(), Ipv4Addr::new(192, 0, 2, 2));
+        let to_other = packet(sender.get_address(), other.get_address());
+        for packet in [&to_blocked, &to_internet, &to_other] {
+            test.router
+                .route_packet(packet.clone(), sender.get_address())
+                .await
+                .unwrap();
+        }
+        assert_eq!(test.tun_output.try_receive(), Some(to_internet));
+        assert_eq!(test.tun_output.try_receive(), None);
+        assert_eq!(other_packets.try_receive(), Some(to_other));
+    }
+
+    #[tokio::test]
+    async fn blocked_destination_is_dropped_from_the_tun() {
+        // the first address handed out sequentially
+        let blocked = Ipv4Addr::new(10, 0, 0, 2);
+        let mut test = router(|config| {
+            config.filter = Box::new(DestinationBlocklist::new([blocked]));
         });
+        let (client, mut client_packets) = connect(&test.router, "client").await;
+        let (other, mut other_packets) = connect(&test.router, "other").await;
+        assert_eq!(client.get_address(), blocked);
+
+        let source = Ipv4Addr::new(192, 0, 2, 2);
+        let to_blocked = packet(source, blocked);
+        let to_other = packet(source, other.get_address());
+        for packet in [&to_blocked, &to_other] {
+            test.tun_input.send(packet).await.unwrap();
+        }
+        // packets from the TUN are routed in order, so the blocked one was handled first
+        let delivered = time::timeout(Duration::from_secs(5), other_packets.receive()).await;
+        assert_eq!(delivered.unwrap().unwrap(), to_other);
+        assert_eq!(client_packets.try_receive(), None);
+        assert_eq!(test.tun_output.try_receive(), None);
+    }
+
+    #[tokio::test]
+    async fn static_lease_is_kept_free_for_its_owner() {
+        let reserved = Ipv4Addr::new(10, 0, 0, 2);
+        let test = router(|config| {
+            config.static_leases = HashMap::from([("alice".into(), reserved)]);
+        });
+        let mut dynamic = Vec::new();
+        for index in 0..3 {
+            let (lease, _) = connect(&test.router, &format!("dynamic-{index}")).await;
+            assert_ne!(lease.get_address(), reserved);
+            dynamic.push(lease);
+        }
+        let (alice, _) = connect(&test.router, "alice").await;
+        assert_eq!(alice.get_address(), reserved);
+        // a second connection cannot take the address over
+        let res = test.router.clone().get_ip(&identity("alice")).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_route_is_dropped_without_failing_the_sender() {
+        let mut test = router(|_| {});
+        let (sender, _) = connect(&test.router, "sender").await;
+        let mut stalled = test
+            .router
+            .clone()
+            .get_ip(&identity("stalled"))
+            .await
+            .unwrap();
+        let timeout = Duration::from_secs(1);
+        stalled
+            .set_route(TimeoutSender::new(StalledSender, Some(timeout), timeout))
+            .await;
+
+        let packet = packet(sender.get_address(), stalled.get_address());
+        test.router
+            .route_packet(packet.clone(), sender.get_address())
+            .await
+            .unwrap();
+        time::timeout(Duration::ZERO, stalled.route_failed())
+            .await
+            .expect("the session of the stalled client was not told to end");
+        assert!(!test
+            .router
+            .routes
+            .read()
+            .await
+            .contains_key(&stalled.get_address()));
+
+        // without a route the address is no longer local, so the packet goes out the TUN device
+        test.router
+            .route_packet(packet.clone(), sender.get_address())
+            .await
+            .unwrap();
+        assert_eq!(test.tun_output.try_receive(), Some(packet));
+    }
+
+    #[tokio::test]
+    async fn failed_broadcast_route_is_dropped() {
+        let test = router(|config| config.forward_broadcast = true);
+        let (sender, _) = connect(&test.router, "sender").await;
+        let (gone, gone_packets) = connect(&test.router, "gone").await;
+        let (_other, mut other_packets) = connect(&test.router, "other").await;
+        drop(gone_packets);
+
+        let packet = packet(sender.get_address(), Ipv4Addr::new(10, 0, 0, 255));
+        test.router
+            .route_packet(packet.clone(), sender.get_address())
+            .await
+            .unwrap();
+        assert_eq!(other_packets.try_receive(), Some(packet));
+        assert!(!test
+            .router
+            .routes
+            .read()
+            .await
+            .contains_key(&gone.get_address()));
+    }
+
+    #[tokio::test]
+    async fn probe_fails_while_a_lock_is_held() {
+        let test = router(|_| {});
+        assert!(test.router.probe());
+        let routes = test.router.routes.write().await;
+        assert!(!test.router.probe());
+        drop(routes);
+        let tun_writer = test.router.tun_writer.lock().await;
+        assert!(!test.router.probe());
+        drop(tun_writer);
+        assert!(test.router.probe());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_send_does_not_hold_the_route_table() {
+        let test = router(|_| {});
+        let (sender, _) = connect(&test.router, "sender").await;
+        let mut stalled = test
+            .router
+            .clone()
+            .get_ip(&identity("stalled"))
+            .await
+            .unwrap();
+        stalled.set_route(StalledSender).await;
+
+        let router = test.router.clone();
+        let packet = packet(sender.get_address(), stalled.get_address());
+        let send =
+            tokio::spawn(async move { router.route_packet(packet, sender.get_address()).await });
+        tokio::task::yield_now().await;
+        assert!(!send.is_finished());
+        // leases can still be granted and released while the send hangs
+        assert!(test.router.routes.try_write().is_ok());
+        send.abort();
+    }
+
+    #[tokio::test]
+    async fn subnet_traffic_goes_to_its_gateway() {
+        let office = Ipv4Addr::new(10, 0, 0, 50);
+        let test = router(|config| {
+            config.static_leases.insert("office".into(), office);
+            config.subnet_routes.push(SubnetRoute {
+                prefix: Ipv4Addr::new(192, 168, 10, 0),
+                prefix_len: 24,
+                gateway: office,
+            });
+        });
+        let (_office, mut office_packets) = connect(&test.router, "office").await;
+        let (mut lab, mut lab_packets) = connect(&test.router, "lab").await;
+        let (sender, _) = connect(&test.router, "sender").await;
+        lab.advertise(Ipv4Addr::new(192, 168, 10, 128), 25).unwrap();
+
+        let to_office = packet(sender.get_address(), Ipv4Addr::new(192, 168, 10, 7));
+        let to_lab = packet(sender.get_address(), Ipv4Addr::new(192, 168, 10, 200));
+        for packet in [&to_office, &to_lab] {
+            test.router
+                .route_packet(packet.clone(), sender.get_address())
+                .await
+                .unwrap();
+        }
+        assert_eq!(office_packets.try_receive(), Some(to_office));
+        assert_eq!(lab_packets.try_receive(), Some(to_lab.clone()));
+
+        // once the lab leaves, the whole subnet is back with the office
+        drop(lab);
+        test.router
+            .route_packet(to_lab.clone(), sender.get_address())
+            .await
+            .unwrap();
+        assert_eq!(office_packets.try_receive(), Some(to_lab));
+    }
+
+    async fn exported(path: &Path, expected: &[&str]) {
+        loop {
+            if let Ok(json) = fs::read(path) {
+                let records: Vec<serde_json::Value> = serde_json::from_slice(&json).unwrap();
+                let names = records
+                    .iter()
+                    .map(|record| record["common_name"].as_str().unwrap())
+                    .collect::<Vec<_>>();
+                if names == expected {
+                    return;
+                }
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lease_file_follows_grants_and_revokes() {
+        let dir = std::env::temp_dir().join(format!("opaque-vpn-leases-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("leases.tmp");
+        let test = router(|config| config.lease_file = Some(path.clone()));
+        let deadline = Duration::from_secs(5);
+
+        time::timeout(deadline, exported(&path, &[])).await.unwrap();
+        let (alice, _) = connect(&test.router, "alice").await;
+        let (bob, _) = connect(&test.router, "bob").await;
+        time::timeout(deadline, exported(&path, &["alice", "bob"]))
+            .await
+            .unwrap();
+        drop(alice);
+        time::timeout(deadline, exported(&path, &["bob"]))
+            .await
+            .unwrap();
+        drop(bob);
+        time::timeout(deadline, exported(&path, &[])).await.unwrap();
+        assert!(!dir.join("leases.tmp.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn peak_leased_keeps_the_high_water_mark() {
+        let test = router(|_| {});
+        let mut leases = Vec::new();
+        for index in 0..3 {
+            leases.push(connect(&test.router, &format!("client-{index}")).await);
+        }
+        assert_eq!(test.router.peak_leased(), 3);
+        leases.truncate(1);
+        assert_eq!(test.router.leased(), 1);
+        assert_eq!(test.router.peak_leased(), 3);
+        // new leases below the peak leave it alone
+        leases.push(connect(&test.router, "client-3").await);
+        assert_eq!(test.router.peak_leased(), 3);
+        for index in 4..6 {
+            leases.push(connect(&test.router, &format!("client-{index}")).await);
+        }
+        assert_eq!(test.router.peak_leased(), 4);
     }
 }
@@ -1,49 +1,149 @@
 use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
     net::{Ipv4Addr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
 };
 
-use anyhow::Context;
-use futures::{io::AsyncRead, FutureExt};
+use anyhow::{anyhow, bail, Context};
+use futures::{
+    io::{BufReader, BufWriter},
+    FutureExt,
+};
 use log::{error, info, warn};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::{watch, OwnedSemaphorePermit},
+    time::{self, Instant},
+};
 use tokio_rustls::{
     rustls::{self, server::WebPkiClientVerifier},
     TlsAcceptor,
 };
 use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tokio_util::task::TaskTracker;
 use tun::{AbstractDevice, AsyncDevice};
 
+#[cfg(unix)]
+use crate::listener::UnixSocketListener;
 use crate::{
-    common::get_root_cert_store,
-    config::{ServerConfig, TlsConfig},
-    packet_stream::{PacketReceiver, TaggedPacketReceiver, TunReceiver, TunSender},
-    protocol::{Connection, NetworkConfig},
-    routing::{Router, RouterConfig},
+    admission::{Admission, IdentityLimit, IdentitySlot},
+    clock_skew::SkewTolerantVerifier,
+    common::{get_root_cert_store, ClientIdentity},
+    config::{BufferSizes, Keepalive, ServerConfig, TlsConfig, Transport},
+    cover,
+    echo::reflect,
+    events::{Event, EventSink, RevokeReason},
+    filter::{AllowAll, DestinationBlocklist, PacketFilter},
+    listener::{is_resource_exhaustion, systemd_listener, Listener, Peer, ACCEPT_BACKOFF},
+    packet_stream::{
+        DatagramKeys, KeepaliveReceiver, KeepaliveSender, PacketReceiver, PacketSender,
+        TimeoutSender, TunReceiver, TunSender, UdpEndpoint, DATAGRAM_IDLE_TIMEOUT,
+        DATAGRAM_KEEPALIVE,
+    },
+    prefix_table::covers,
+    protocol::{
+        is_heartbeat, negotiate_keepalive, rejection_frame, tunnel_mtu, Connection, NetworkConfig,
+        Rejection,
+    },
+    rate_limit::{AttemptVerdict, ConnectionThrottle, PacketRateLimiter},
+    routing::{IpLease, Router, RouterConfig},
+    watchdog::Watchdog,
 };
 
+const THROTTLE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const MTU_QUERY_ATTEMPTS: u32 = 5;
+const MTU_QUERY_DELAY: Duration = Duration::from_millis(100);
+const HANDSHAKE_RATE_CHECK: Duration = Duration::from_secs(1);
+const FALLBACK_MTU: u16 = 1500;
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+const TOP_DESTINATIONS: usize = 5;
+
 pub struct Server {
-    router: Arc<Router<TunSender>>,
+    router: Arc<Router<TimeoutSender<TunSender>>>,
+    admission: Option<Admission>,
+    identity_limit: Option<IdentityLimit>,
     acceptor: TlsAcceptor,
+    udp: Option<UdpEndpoint>,
+    alpn: Option<Vec<u8>>,
     socket_address: SocketAddr,
     gateway: Ipv4Addr,
     netmask: Ipv4Addr,
     mtu: u16,
+    max_pps: Option<u32>,
+    max_connects_per_sec: Option<u32>,
+    min_handshake_rate: Option<u64>,
+    pps_overrides: HashMap<String, u32>,
+    route_allowlist: HashMap<String, Vec<(Ipv4Addr, u8)>>,
+    dns: Vec<Ipv4Addr>,
+    search_domain: Option<String>,
+    write_timeout: Option<Duration>,
+    close_linger: Duration,
+    keepalive: Option<Keepalive>,
+    unix_socket: Option<PathBuf>,
+    event_socket: Option<PathBuf>,
+    events: EventSink,
+    clients: TaskTracker,
+    echo_mode: bool,
+    watchdog: Option<Duration>,
+    max_session: Option<Duration>,
+    expect_cover: bool,
+    cover_response: Vec<u8>,
+    buffers: BufferSizes,
+    maintenance: Arc<AtomicBool>,
+    stop_sender: watch::Sender<bool>,
+    stop_receiver: watch::Receiver<bool>,
 }
 
 impl Server {
-    pub fn try_new(config: ServerConfig, tls: TlsConfig) -> anyhow::Result<Arc<Self>> {
+    pub async fn try_new(config: ServerConfig, tls: TlsConfig) -> anyhow::Result<Arc<Self>> {
         let device = tun_create(&config)?;
-        let mtu = device.mtu().context("could not get MTU")?;
+        let mtu = query_mtu(|| device.mtu()).await;
+        let interface = device
+            .tun_name()
+            .context("could not get TUN interface name")?;
 
         let (tun_writer, tun_reader) = device.split().context("could not split tun device")?;
-        let tun_sender: TunSender = tun_writer.into();
+        let tun_sender = TimeoutSender::new(
+            TunSender::from(tun_writer),
+            config.write_timeout,
+            config.close_linger,
+        );
         let tun_receiver = TunReceiver::new(tun_reader, mtu as usize);
 
+        let filter: Box<dyn PacketFilter> = if config.blocked_destinations.is_empty() {
+            Box::new(AllowAll)
+        } else {
+            Box::new(DestinationBlocklist::new(config.blocked_destinations))
+        };
+        let udp = match config.transport {
+            Transport::Tcp => None,
+            Transport::Udp => Some(bind_udp(config.port)?),
+        };
+        let (stop_sender, stop_receiver) = watch::channel(false);
         let router = Router::new(
             RouterConfig {
                 address: config.virtual_address,
                 netmask: config.subnet_mask,
+                forward_broadcast: config.forward_broadcast,
+                static_leases: config.static_leases,
+                allocation: config.allocation,
+                filter,
+                lease_file: config.lease_file,
+                reclaim_grace: config.ip_reclaim_grace,
+                subnet_routes: config.subnet_routes,
+                interface: Some(interface),
             },
             tun_sender,
             tun_receiver,
@@ -51,72 +151,547 @@ impl Server {
 
         Ok(Self {
             router,
-            acceptor: Arc::new(configure_tls(tls)?).into(),
+            admission: config.client_limit.map(Admission::new),
+            identity_limit: config.max_connections_per_cn.map(IdentityLimit::new),
+            udp,
+            alpn: tls.alpn.clone(),
+            acceptor: Arc::new(configure_tls(tls, config.clock_skew_tolerance)?).into(),
             socket_address: SocketAddr::new(Ipv4Addr::from_bits(0).into(), config.port),
             gateway: config.virtual_address,
             netmask: config.subnet_mask,
             mtu,
+            max_pps: config.max_pps,
+            max_connects_per_sec: config.max_connects_per_sec,
+            min_handshake_rate: config.min_handshake_rate,
+            pps_overrides: config.pps_overrides,
+            route_allowlist: config.route_allowlist,
+            dns: config.dns,
+            search_domain: config.search_domain,
+            write_timeout: config.write_timeout,
+            close_linger: config.close_linger,
+            keepalive: config.keepalive,
+            unix_socket: config.unix_socket,
+            event_socket: config.event_socket,
+            events: EventSink::new(),
+            clients: TaskTracker::new(),
+            echo_mode: config.echo_mode,
+            watchdog: config.watchdog,
+            max_session: config.max_session,
+            expect_cover: config.expect_cover,
+            cover_response: config.cover_response,
+            buffers: config.buffers,
+            maintenance: register_maintenance_toggle()?,
+            stop_sender,
+            stop_receiver,
         }
         .into())
     }
 
+    pub fn stop_sender(&self) -> watch::Sender<bool> {
+        self.stop_sender.clone()
+    }
+
     pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(self.socket_address).await?;
+        let listener = match systemd_listener()? {
+            Some(listener) => {
+                info!("using listening socket passed by systemd");
+                listener
+            }
+            None => TcpListener::bind(self.socket_address)
+                .await
+                .map_err(|e| bind_error(e, self.socket_address.port()))?,
+        };
+        #[cfg(unix)]
+        if let Some(path) = &self.event_socket {
+            let listener = UnixSocketListener::bind(path)?;
+            let server = self.clone();
+            self.clients.spawn(async move {
+                server
+                    .events
+                    .serve(listener, server.stop_receiver.clone())
+                    .await
+            });
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.unix_socket {
+            let listener = UnixSocketListener::bind(path)?;
+            self.clients.spawn(self.clone().accept_loop(listener));
+        }
+        tokio::spawn(self.clone().report_stats());
+        let _watchdog = self.watchdog.map(|timeout| {
+            let router = self.router.clone();
+            Watchdog::start(timeout, move || router.probe())
+        });
+        self.clone().accept_loop(listener).await;
+
+        if time::timeout(SHUTDOWN_TIMEOUT, self.shutdown())
+            .await
+            .is_err()
+        {
+            warn!("timed out waiting for clients to disconnect");
+        }
+        info!("server stopped");
+        Ok(())
+    }
+
+    async fn report_stats(self: Arc<Self>) {
+        let mut stop_token = self.stop_receiver.clone();
+        let mut interval = time::interval(STATS_INTERVAL);
+        interval.tick().await;
+        let mut last_leases = (0, 0);
         loop {
-            match listener.accept().await {
-                Ok((socket, addr)) => {
-                    info!("incoming connection from {addr}");
-                    tokio::spawn(self.clone().handle_client(socket).map(|res| {
-                        if let Err(e) = res {
-                            warn!("{e}");
-                        }
-                    }));
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop_token.wait_for(|&stop| stop) => return,
+            }
+            let leases = (self.router.leased(), self.router.peak_leased());
+            if leases != last_leases {
+                info!("leases: {} active, peak {}", leases.0, leases.1);
+                last_leases = leases;
+            }
+            let (top, untracked) = self.router.top_destinations(TOP_DESTINATIONS);
+            if top.is_empty() {
+                continue;
+            }
+            let summary = top
+                .iter()
+                .map(|(addr, traffic)| {
+                    format!("{addr} {} packets/{} bytes", traffic.packets, traffic.bytes)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            info!("top destinations: {summary}");
+            if untracked.packets > 0 {
+                info!(
+                    "untracked destinations: {} packets/{} bytes",
+                    untracked.packets, untracked.bytes
+                );
+            }
+        }
+    }
+
+    async fn shutdown(&self) {
+        self.clients.close();
+        self.clients.wait().await;
+        self.router.shutdown().await;
+    }
+
+    async fn accept_loop<L: Listener>(self: Arc<Self>, listener: L) {
+        let mut stop_token = self.stop_receiver.clone();
+        let mut throttle = self.max_connects_per_sec.map(ConnectionThrottle::new);
+        // a flood would otherwise produce a warning per attempt
+        let mut throttle_report = time::interval(THROTTLE_REPORT_INTERVAL);
+        while !*stop_token.borrow_and_update() {
+            tokio::select! {
+                res = stop_token.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
                 }
-                Err(e) => error!("could not accept connection: {e}"),
-            };
+                _ = throttle_report.tick(), if throttle.is_some() => {
+                    let (dropped, sources) = throttle.as_mut().unwrap().take_dropped();
+                    if dropped > 0 {
+                        warn!(
+                            "dropped {dropped} connection attempts from {sources} sources in the \
+                             last {THROTTLE_REPORT_INTERVAL:?}: too many connection attempts"
+                        );
+                    }
+                }
+                accept_res = listener.accept() => match accept_res {
+                    Ok((socket, peer)) => {
+                        let verdict = admit_source(&mut throttle, &peer);
+                        if verdict == AttemptVerdict::Drop {
+                            continue;
+                        }
+                        info!("incoming connection from {peer}");
+                        let peer = peer.to_string();
+                        self.events.emit(Event::Connected { peer: peer.clone() });
+                        let server = self.clone();
+                        let throttled = verdict == AttemptVerdict::Refuse;
+                        let client = self.clone().handle_client(socket, peer.clone(), throttled);
+                        self.clients.spawn(client.map(move |res| {
+                            if let Err(e) = res {
+                                warn!("{e}");
+                                server.events.emit(Event::Error {
+                                    peer,
+                                    message: format!("{e:#}"),
+                                });
+                            }
+                        }));
+                    }
+                    Err(e) if is_resource_exhaustion(&e) => {
+                        error!("could not accept connection, backing off: {e}");
+                        time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                    Err(e) => error!("could not accept connection: {e}"),
+                },
+            }
         }
     }
 
-    async fn handle_client(self: Arc<Self>, socket: TcpStream) -> anyhow::Result<()> {
-        let client = self.acceptor.accept(socket).await?;
+    // a throttled client still gets its handshake, so that it can be told to back off
+    async fn handle_client<IO>(
+        self: Arc<Self>,
+        socket: IO,
+        peer: String,
+        throttled: bool,
+    ) -> anyhow::Result<()>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let received = Arc::new(AtomicU64::new(0));
+        let mut socket = CountingStream {
+            inner: socket,
+            received: received.clone(),
+        };
+        let handshake = async {
+            if self.expect_cover {
+                cover::accept(&mut (&mut socket).compat(), &self.cover_response)
+                    .await
+                    .context("cover exchange failed")?;
+            }
+            Ok(self.acceptor.accept(socket).await?)
+        };
+        let client = unless_stalled(handshake, received, self.min_handshake_rate)
+            .await
+            .with_context(|| format!("dropping {peer}"))?;
+        let negotiated_alpn = self
+            .alpn
+            .as_ref()
+            .is_none_or(|alpn| client.get_ref().1.alpn_protocol() == Some(alpn.as_slice()));
+        let datagram_keys = self
+            .udp
+            .as_ref()
+            .map(|_| DatagramKeys::export(client.get_ref().1, true))
+            .transpose()?;
+        let identity = client
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .context("client did not present a certificate")
+            .and_then(ClientIdentity::from_certificate)?;
+        let common_name = &identity.common_name;
+        info!("client {common_name} authenticated");
+
         let (client_reader, client_writer) = tokio::io::split(client);
-        let client_reader = client_reader.compat();
-        let client_writer = client_writer.compat_write();
+        let client_reader = BufReader::with_capacity(self.buffers.read, client_reader.compat());
+        let client_writer =
+            BufWriter::with_capacity(self.buffers.write, client_writer.compat_write());
         let mut protocol_connection = Connection::new(client_reader, client_writer);
-
-        let ip_lease = self
-            .router
-            .clone()
-            .get_ip()
+        protocol_connection
+            .receive_version()
+            .await
+            .with_context(|| format!("rejecting {common_name}"))?;
+        let path_mtu = protocol_connection
+            .receive_path_mtu()
+            .await
+            .context("could not receive client path MTU")?;
+        let transport = protocol_connection
+            .receive_transport()
             .await
-            .context("could not assign ip address")?;
+            .context("could not receive transport request")?;
+        let advertised_routes = protocol_connection
+            .receive_routes()
+            .await
+            .context("could not receive advertised routes")?;
+        let client_keepalive = protocol_connection
+            .receive_keepalive()
+            .await
+            .context("could not receive client keepalive interval")?;
+        let refusal = if throttled {
+            Some(Rejection::Throttled)
+        } else if !negotiated_alpn {
+            Some(Rejection::ProtocolMismatch)
+        } else if self.maintenance.load(Ordering::Relaxed) {
+            Some(Rejection::Maintenance)
+        } else {
+            None
+        };
+        let (mut ip_lease, _identity_slot, _slot) = admit(
+            &mut protocol_connection,
+            refusal,
+            self.identity_limit.as_ref(),
+            self.admission.as_ref(),
+            &self.router,
+            &identity,
+        )
+        .await?;
 
+        let mtu = tunnel_mtu(self.mtu, path_mtu, transport);
+        info!("advertising tunnel MTU {mtu} to {common_name}");
+        let datagrams = match transport {
+            Transport::Tcp => None,
+            Transport::Udp => {
+                let (endpoint, keys) = self
+                    .udp
+                    .as_ref()
+                    .zip(datagram_keys)
+                    .context("client requested the UDP transport, which is not enabled")?;
+                Some(endpoint.open(keys, None, mtu.into()))
+            }
+        };
+
+        let network_config = NetworkConfig {
+            client_ip: ip_lease.get_address(),
+            server_ip: self.gateway,
+            netmask: self.netmask,
+            mtu,
+            dns: self.dns.clone(),
+            search_domain: self.search_domain.clone(),
+        };
         protocol_connection
-            .send_config(NetworkConfig {
-                client_ip: ip_lease.get_address(),
-                server_ip: self.gateway,
-                netmask: self.netmask,
-                mtu: self.mtu,
-            })
+            .send_config(&network_config)
             .await
             .context("could not send network configuration")?;
+        protocol_connection
+            .send_keepalive(self.keepalive.map(|keepalive| keepalive.interval))
+            .await
+            .context("could not announce keepalive interval")?;
+        info!("assigned {network_config} to {common_name}");
+        self.events.emit(Event::LeaseGranted {
+            peer,
+            common_name: common_name.clone(),
+            address: ip_lease.get_address(),
+        });
+        let allowed = self
+            .route_allowlist
+            .get(common_name)
+            .map_or(&[][..], Vec::as_slice);
+        install_advertised_routes(&mut ip_lease, allowed, common_name, advertised_routes);
 
+        let accepts_disconnect = protocol_connection.accepts_disconnect();
         let (packet_sender, packet_receiver) = protocol_connection.into_parts();
-        ip_lease.set_route(packet_sender).await;
-        if let Err(e) = self.clone().forward_packets(packet_receiver).await {
+        // keepalives go through the write timeout as well, they share the stream with packets
+        let keepalive = negotiate_keepalive(self.keepalive, client_keepalive);
+        let packet_sender = KeepaliveSender::new(
+            TimeoutSender::new(packet_sender, self.write_timeout, self.close_linger),
+            keepalive.map(|keepalive| keepalive.interval),
+        );
+        let packet_receiver = KeepaliveReceiver::new(
+            packet_receiver,
+            keepalive.map(|keepalive| keepalive.timeout),
+        );
+        let (datagram_receiver, mut control_sender) = match datagrams {
+            // keepalives both ways let each side notice when the other goes quiet
+            // without relying on the control connection
+            Some((datagram_sender, datagram_receiver)) => {
+                ip_lease
+                    .set_route(KeepaliveSender::new(
+                        TimeoutSender::new(datagram_sender, self.write_timeout, self.close_linger),
+                        Some(DATAGRAM_KEEPALIVE),
+                    ))
+                    .await;
+                let datagram_receiver =
+                    KeepaliveReceiver::new(datagram_receiver, Some(DATAGRAM_IDLE_TIMEOUT));
+                (Some(datagram_receiver), Some(packet_sender))
+            }
+            None => {
+                ip_lease.set_route(packet_sender).await;
+                (None, None)
+            }
+        };
+        let max_pps = self
+            .pps_overrides
+            .get(common_name)
+            .copied()
+            .or(self.max_pps);
+        let mut rate_limiter = PacketRateLimiter::new(max_pps);
+        let client_ip = ip_lease.get_address();
+        let forward = async {
+            match datagram_receiver {
+                // with the UDP transport the TLS stream only tells when the session ends
+                Some(datagram_receiver) => {
+                    let forward = self.clone().forward_packets(
+                        datagram_receiver,
+                        client_ip,
+                        &mut rate_limiter,
+                    );
+                    tokio::select! {
+                        res = forward => res,
+                        res = await_disconnect(packet_receiver, common_name) => res,
+                    }
+                }
+                None => {
+                    self.clone()
+                        .forward_packets(packet_receiver, client_ip, &mut rate_limiter)
+                        .await
+                }
+            }
+        };
+        let forward = async {
+            tokio::select! {
+                res = forward => res,
+                _ = ip_lease.route_failed() => bail!("could not send to {common_name}"),
+            }
+        };
+        let (forward_res, reason) = match limit_lifetime(forward, self.max_session).await {
+            Some(res) => (res, RevokeReason::Disconnected),
+            None => {
+                info!("session of {common_name} reached its maximum lifetime, disconnecting");
+                if accepts_disconnect {
+                    let frame = rejection_frame(Rejection::SessionExpired);
+                    let sent = match &mut control_sender {
+                        // datagrams may be lost, the control connection delivers it
+                        Some(control) => {
+                            async {
+                                control.send(&frame).await?;
+                                control.close().await
+                            }
+                            .await
+                        }
+                        None => ip_lease.send(&frame).await,
+                    };
+                    if let Err(e) = sent {
+                        info!("could not tell {common_name} that its session expired: {e}");
+                    }
+                }
+                (Ok(()), RevokeReason::SessionExpired)
+            }
+        };
+        if let Err(e) = forward_res {
             info!("connection terminated: {e}");
         }
+        if rate_limiter.dropped() > 0 {
+            warn!(
+                "dropped {} packets from {common_name} over the packet rate limit",
+                rate_limiter.dropped()
+            );
+        }
+        self.events.emit(Event::LeaseRevoked {
+            common_name: common_name.clone(),
+            address: ip_lease.get_address(),
+            reason,
+        });
 
         Ok(())
     }
 
-    async fn forward_packets<IO: AsyncRead + Unpin + Send>(
+    async fn forward_packets<R: PacketReceiver>(
         self: Arc<Self>,
-        mut packet_receiver: TaggedPacketReceiver<IO>,
+        mut packet_receiver: R,
+        client_ip: Ipv4Addr,
+        rate_limiter: &mut PacketRateLimiter,
     ) -> anyhow::Result<()> {
+        let mut stop_token = self.stop_receiver.clone();
         loop {
-            let packet = packet_receiver.receive().await?;
-            self.router.route_packet(packet).await?;
+            let packet = tokio::select! {
+                packet_res = packet_receiver.receive() => packet_res?,
+                _ = stop_token.wait_for(|&stop| stop) => return Ok(()),
+            };
+            if is_heartbeat(&packet) || !rate_limiter.admit() {
+                continue;
+            }
+            let packet = if self.echo_mode {
+                match reflect(&packet, client_ip) {
+                    Some(reply) => reply,
+                    None => continue,
+                }
+            } else {
+                packet
+            };
+            self.router.route_packet(packet, client_ip).await?;
+        }
+    }
+}
+
+// None once the session outlived its maximum lifetime
+// the client waits for a config reply, so it is told why it is turned away instead of seeing
+// the close
+async fn admit<'a, R, W, S>(
+    connection: &mut Connection<R, W>,
+    refusal: Option<Rejection>,
+    identity_limit: Option<&'a IdentityLimit>,
+    admission: Option<&Admission>,
+    router: &Arc<Router<S>>,
+    identity: &ClientIdentity,
+) -> anyhow::Result<(
+    IpLease<S>,
+    Option<IdentitySlot<'a>>,
+    Option<OwnedSemaphorePermit>,
+)>
+where
+    R: futures::AsyncRead + Unpin + Send,
+    W: futures::AsyncWrite + Unpin + Send,
+    S: PacketSender + 'static,
+{
+    let common_name = &identity.common_name;
+    if let Some(rejection) = refusal {
+        reject(connection, rejection).await?;
+        bail!("rejecting {common_name}: {rejection}");
+    }
+    let identity_slot = identity_limit
+        .map(|limit| limit.acquire(common_name))
+        .transpose();
+    let identity_slot = or_reject(connection, Rejection::IdentityLimit, identity_slot)
+        .await
+        .with_context(|| format!("rejecting {common_name}"))?;
+    let slot = match admission {
+        Some(admission) => Some(
+            or_reject(connection, Rejection::ServerFull, admission.admit().await)
+                .await
+                .with_context(|| format!("not admitting client {common_name}"))?,
+        ),
+        None => None,
+    };
+    let ip_lease = router.clone().get_ip(identity).await;
+    let ip_lease = or_reject(connection, Rejection::NoFreeAddress, ip_lease)
+        .await
+        .context("could not assign ip address")?;
+    Ok((ip_lease, identity_slot, slot))
+}
+
+async fn or_reject<T, R, W>(
+    connection: &mut Connection<R, W>,
+    rejection: Rejection,
+    res: anyhow::Result<T>,
+) -> anyhow::Result<T>
+where
+    R: futures::AsyncRead + Unpin + Send,
+    W: futures::AsyncWrite + Unpin + Send,
+{
+    if res.is_err() {
+        reject(connection, rejection).await?;
+    }
+    res
+}
+
+async fn reject<R, W>(connection: &mut Connection<R, W>, rejection: Rejection) -> anyhow::Result<()>
+where
+    R: futures::AsyncRead + Unpin + Send,
+    W: futures::AsyncWrite + Unpin + Send,
+{
+    connection
+        .send_rejection(rejection)
+        .await
+        .context("could not send rejection")
+}
+
+async fn limit_lifetime<T>(
+    session: impl Future<Output = T>,
+    max_session: Option<Duration>,
+) -> Option<T> {
+    match max_session {
+        Some(max_session) => time::timeout(max_session, session).await.ok(),
+        None => Some(session.await),
+    }
+}
+
+// the routes live as long as the lease, they are withdrawn when the client disconnects
+fn install_advertised_routes<S: PacketSender + 'static>(
+    ip_lease: &mut IpLease<S>,
+    allowed: &[(Ipv4Addr, u8)],
+    common_name: &str,
+    routes: Vec<(Ipv4Addr, u8)>,
+) {
+    for (prefix, len) in routes {
+        if !allowed.iter().any(|&subnet| covers(subnet, (prefix, len))) {
+            warn!("ignoring route to {prefix}/{len} from {common_name}, it is not allowlisted");
+            continue;
+        }
+        match ip_lease.advertise(prefix, len) {
+            Ok(()) => info!("routing {prefix}/{len} through {common_name}"),
+            Err(e) => warn!("ignoring route advertised by {common_name}: {e}"),
         }
     }
 }
@@ -131,13 +706,534 @@ fn tun_create(config: &ServerConfig) -> anyhow::Result<AsyncDevice> {
     Ok(device)
 }
 
-fn configure_tls(tls: TlsConfig) -> anyhow::Result<rustls::ServerConfig> {
-    Ok(rustls::ServerConfig::builder()
-        .with_client_cert_verifier(
-            WebPkiClientVerifier::builder(
-                get_root_cert_store(tls.root_certificate.clone())?.into(),
-            )
-            .build()?,
+// the handler only writes a byte to the pair, the toggle is logged outside of it
+#[cfg(unix)]
+fn register_maintenance_toggle() -> anyhow::Result<Arc<AtomicBool>> {
+    let (signals, pipe) =
+        std::os::unix::net::UnixStream::pair().context("could not create signal pipe")?;
+    signal_hook::low_level::pipe::register(signal_hook::consts::SIGUSR1, pipe)
+        .context("could not register SIGUSR1 handler")?;
+    signals.set_nonblocking(true)?;
+    let signals = tokio::net::UnixStream::from_std(signals)?;
+    let maintenance = Arc::new(AtomicBool::new(false));
+    tokio::spawn(toggle_maintenance(signals, maintenance.clone()));
+    Ok(maintenance)
+}
+
+#[cfg(unix)]
+async fn toggle_maintenance(signals: tokio::net::UnixStream, maintenance: Arc<AtomicBool>) {
+    let mut buffer = [0u8; 16];
+    loop {
+        let res = match signals.readable().await {
+            Ok(()) => signals.try_read(&mut buffer),
+            Err(e) => Err(e),
+        };
+        let count = match res {
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => {
+                warn!("stopped listening for SIGUSR1: {e}");
+                return;
+            }
+        };
+        for _ in 0..count {
+            if maintenance.fetch_xor(true, Ordering::Relaxed) {
+                info!("maintenance mode off, accepting new clients");
+            } else {
+                info!("maintenance mode on, rejecting new clients");
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn register_maintenance_toggle() -> anyhow::Result<Arc<AtomicBool>> {
+    Ok(Arc::new(AtomicBool::new(false)))
+}
+
+// some platforms cannot report the MTU of a freshly created interface right away
+async fn query_mtu<E: fmt::Display>(mut query: impl FnMut() -> Result<u16, E>) -> u16 {
+    for attempt in 1..=MTU_QUERY_ATTEMPTS {
+        match query() {
+            Ok(mtu) => return mtu,
+            Err(e) if attempt == MTU_QUERY_ATTEMPTS => {
+                warn!("could not get TUN MTU, assuming {FALLBACK_MTU}: {e}");
+            }
+            Err(_) => time::sleep(MTU_QUERY_DELAY).await,
+        }
+    }
+    FALLBACK_MTU
+}
+
+fn admit_source(throttle: &mut Option<ConnectionThrottle>, peer: &Peer) -> AttemptVerdict {
+    match (throttle, peer.ip()) {
+        (Some(throttle), Some(ip)) => throttle.admit(ip),
+        _ => AttemptVerdict::Admit,
+    }
+}
+
+fn bind_error(err: io::Error, port: u16) -> anyhow::Error {
+    const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+    if err.kind() == io::ErrorKind::PermissionDenied && port < FIRST_UNPRIVILEGED_PORT {
+        anyhow!(err).context(format!(
+            "not permitted to bind privileged port {port}: run as root, grant the \
+             CAP_NET_BIND_SERVICE capability or choose a port above {}",
+            FIRST_UNPRIVILEGED_PORT - 1
+        ))
+    } else {
+        anyhow!(err).context(format!("could not bind port {port}"))
+    }
+}
+
+fn bind_udp(port: u16) -> anyhow::Result<UdpEndpoint> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+        .with_context(|| format!("could not bind UDP port {port}"))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpEndpoint::new(tokio::net::UdpSocket::from_std(socket)?))
+}
+
+// the cover exchange is guarded as well, a trickled cover request would hold the slot just the same
+async fn unless_stalled<T>(
+    handshake: impl Future<Output = anyhow::Result<T>>,
+    received: Arc<AtomicU64>,
+    min_rate: Option<u64>,
+) -> anyhow::Result<T> {
+    let Some(rate) = min_rate else {
+        return handshake.await;
+    };
+    tokio::select! {
+        res = handshake => res,
+        _ = handshake_stalled(received, rate) => {
+            bail!("handshake progressed slower than {rate} B/s")
+        }
+    }
+}
+
+// the average rate since the start is checked, so a legitimate client
+// waiting a round trip for the server's flight is not mistaken for a stall
+async fn handshake_stalled(received: Arc<AtomicU64>, min_rate: u64) {
+    let start = Instant::now();
+    let mut interval = time::interval_at(start + HANDSHAKE_RATE_CHECK, HANDSHAKE_RATE_CHECK);
+    loop {
+        interval.tick().await;
+        let expected = u128::from(min_rate) * start.elapsed().as_millis() / 1000;
+        if u128::from(received.load(Ordering::Relaxed)) < expected {
+            return;
+        }
+    }
+}
+
+struct CountingStream<IO> {
+    inner: IO,
+    received: Arc<AtomicU64>,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for CountingStream<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        self.received.fetch_add(read as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for CountingStream<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// only a clean close ends a datagram session, a control connection that fails because
+// the client moved to another network leaves the session to the datagram idle timeout
+async fn await_disconnect<R: PacketReceiver>(
+    mut control: R,
+    common_name: &str,
+) -> anyhow::Result<()> {
+    loop {
+        match control.receive().await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(e).context("control connection closed");
+            }
+            Err(e) => {
+                info!("control connection of {common_name} lost, continuing over datagrams: {e}");
+                return std::future::pending().await;
+            }
+        }
+    }
+}
+
+fn configure_tls(
+    tls: TlsConfig,
+    clock_skew_tolerance: Option<Duration>,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let mut verifier =
+        WebPkiClientVerifier::builder(get_root_cert_store(tls.root_certificate.clone())?.into())
+            .build()?;
+    if let Some(tolerance) = clock_skew_tolerance {
+        verifier = Arc::new(SkewTolerantVerifier::new(verifier, tolerance));
+    }
+    let mut config = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![tls.certificate, tls.root_certificate], tls.key)?;
+    config.alpn_protocols.extend(tls.alpn);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+
+    use super::*;
+    use futures::io::Cursor;
+
+    use crate::{
+        config::{AdmissionPolicy, AllocationStrategy, ClientLimit},
+        packet_stream::mock::{self, MockReceiver, MockSender},
+        protocol::ProtocolError,
+    };
+
+    fn packet(source: Ipv4Addr, destination: Ipv4Addr) -> Box<[u8]> {
+        let builder = PacketBuilder::ipv4(source.octets(), destination.octets(), 64).udp(53, 53);
+        let mut packet = Vec::new();
+        builder.write(&mut packet, b"query").unwrap();
+        packet.into()
+    }
+
+    fn identity(common_name: &str) -> ClientIdentity {
+        ClientIdentity {
+            common_name: common_name.into(),
+            key_hash: 0,
+        }
+    }
+
+    async fn lease(router: &Arc<Router<MockSender>>, common_name: &str) -> IpLease<MockSender> {
+        router.clone().get_ip(&identity(common_name)).await.unwrap()
+    }
+
+    const MIN_RATE: u64 = 100;
+
+    // the server side of a cover exchange under the stall guard
+    async fn guarded_cover(stream: tokio::io::DuplexStream) -> anyhow::Result<()> {
+        let received = Arc::new(AtomicU64::new(0));
+        let mut stream = CountingStream {
+            inner: stream,
+            received: received.clone(),
+        }
+        .compat();
+        let handshake = cover::accept(&mut stream, cover::DEFAULT_RESPONSE);
+        unless_stalled(handshake, received, Some(MIN_RATE)).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trickled_cover_request_is_dropped() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let trickle = async {
+            for &byte in cover::default_request("example.com").iter() {
+                if tokio::io::AsyncWriteExt::write_all(&mut client, &[byte])
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+        let start = Instant::now();
+        // the trickle ends once the dropped server side closes the stream
+        let (res, ()) = tokio::join!(guarded_cover(server), trickle);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!("handshake progressed slower than {MIN_RATE} B/s")
+        );
+        // well before the deadline of the cover exchange itself
+        assert_eq!(start.elapsed(), HANDSHAKE_RATE_CHECK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prompt_cover_request_passes_the_guard() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let request = cover::default_request("example.com");
+        tokio::io::AsyncWriteExt::write_all(&mut client, &request)
+            .await
+            .unwrap();
+        guarded_cover(server).await.unwrap();
+    }
+
+    // the TUN side of the router, kept so that its reader waits instead of failing
+    struct Tun {
+        output: MockReceiver,
+        _input: MockSender,
+    }
+
+    fn router() -> (Arc<Router<MockSender>>, Tun) {
+        let (tun_sender, output) = mock::channel();
+        let (input, tun_receiver) = mock::channel();
+        let router = Router::new(
+            RouterConfig {
+                address: Ipv4Addr::new(10, 0, 0, 1),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                forward_broadcast: false,
+                static_leases: HashMap::new(),
+                allocation: AllocationStrategy::Sequential,
+                filter: Box::new(AllowAll),
+                lease_file: None,
+                reclaim_grace: None,
+                subnet_routes: Vec::new(),
+                interface: None,
+            },
+            tun_sender,
+            tun_receiver,
+        );
+        (
+            router,
+            Tun {
+                output,
+                _input: input,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn allowlisted_routes_are_installed_until_disconnect() {
+        let (router, mut tun) = router();
+        let mut branch = lease(&router, "branch").await;
+        let (sink, mut branch_packets) = mock::channel();
+        branch.set_route(sink).await;
+        let sender = lease(&router, "sender").await;
+        let lan = Ipv4Addr::new(192, 168, 20, 0);
+        let other = Ipv4Addr::new(172, 16, 0, 0);
+        install_advertised_routes(
+            &mut branch,
+            &[(lan, 23)],
+            "branch",
+            vec![(lan, 24), (other, 16)],
+        );
+
+        let to_lan = packet(sender.get_address(), Ipv4Addr::new(192, 168, 20, 9));
+        let to_other = packet(sender.get_address(), Ipv4Addr::new(172, 16, 0, 9));
+        for packet in [&to_lan, &to_other] {
+            router
+                .route_packet(packet.clone(), sender.get_address())
+                .await
+                .unwrap();
+        }
+        assert_eq!(branch_packets.try_receive(), Some(to_lan.clone()));
+        assert_eq!(tun.output.try_receive(), Some(to_other));
+
+        drop(branch);
+        router
+            .route_packet(to_lan.clone(), sender.get_address())
+            .await
+            .unwrap();
+        assert_eq!(tun.output.try_receive(), Some(to_lan));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_ends_after_its_maximum_lifetime() {
+        const MAX_SESSION: Duration = Duration::from_secs(3600);
+        let (router, _tun) = router();
+        let mut client = lease(&router, "client").await;
+        let (sink, mut client_packets) = mock::channel();
+        client.set_route(sink).await;
+        // a client that never sends anything keeps forwarding busy forever
+        let (_client_input, mut client_receiver) = mock::channel();
+        let forward = async { client_receiver.receive().await.map(drop) };
+
+        let start = Instant::now();
+        assert!(limit_lifetime(forward, Some(MAX_SESSION)).await.is_none());
+        assert_eq!(start.elapsed(), MAX_SESSION);
+        client
+            .send(&rejection_frame(Rejection::SessionExpired))
+            .await
+            .unwrap();
+        assert_eq!(client_packets.try_receive().as_deref(), Some(&[0, 2][..]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_ending_on_their_own_are_not_expired() {
+        let start = Instant::now();
+        let session = async {
+            time::sleep(Duration::from_secs(10)).await;
+            "closed"
+        };
+        assert_eq!(
+            limit_lifetime(session, Some(Duration::from_secs(3600))).await,
+            Some("closed")
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert_eq!(
+            limit_lifetime(async { "closed" }, None).await,
+            Some("closed")
+        );
+    }
+
+    // what alice is told while waiting for her config, None if she is admitted
+    async fn rejection_for_alice(
+        refusal: Option<Rejection>,
+        identity_limit: Option<&IdentityLimit>,
+        admission: Option<&Admission>,
+        router: &Arc<Router<MockSender>>,
+    ) -> Option<Rejection> {
+        let mut output = Vec::new();
+        let mut connection = Connection::new(Cursor::new(Vec::new()), &mut output);
+        let admitted = admit(
+            &mut connection,
+            refusal,
+            identity_limit,
+            admission,
+            router,
+            &identity("alice"),
         )
-        .with_single_cert(vec![tls.certificate, tls.root_certificate], tls.key)?)
+        .await;
+        drop(connection);
+        if admitted.is_ok() {
+            assert!(output.is_empty());
+            return None;
+        }
+        let mut client = Connection::new(Cursor::new(output), Vec::new());
+        let err = client.receive_config().await.unwrap_err();
+        match err.downcast_ref() {
+            Some(ProtocolError::Rejected(rejection)) => Some(*rejection),
+            _ => panic!("expected a rejection, got {err:#}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_clients_are_told_why() {
+        let (router, _tun) = router();
+        let limit = IdentityLimit::new(1);
+        for rejection in [
+            Rejection::Throttled,
+            Rejection::ProtocolMismatch,
+            Rejection::Maintenance,
+        ] {
+            let told = rejection_for_alice(Some(rejection), Some(&limit), None, &router).await;
+            assert_eq!(told, Some(rejection));
+        }
+        // nothing was taken on behalf of the refused attempts
+        assert_eq!(router.peak_leased(), 0);
+        assert!(limit.acquire("alice").is_ok());
+    }
+
+    #[tokio::test]
+    async fn identity_over_its_session_limit_is_told_so() {
+        let (router, _tun) = router();
+        let limit = IdentityLimit::new(1);
+        let held = limit.acquire("alice").unwrap();
+        let told = rejection_for_alice(None, Some(&limit), None, &router).await;
+        assert_eq!(told, Some(Rejection::IdentityLimit));
+        drop(held);
+        assert_eq!(
+            rejection_for_alice(None, Some(&limit), None, &router).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn full_server_is_reported() {
+        let (router, _tun) = router();
+        let admission = Admission::new(ClientLimit {
+            max_clients: 1,
+            policy: AdmissionPolicy::Reject,
+        });
+        let held = admission.admit().await.unwrap();
+        let told = rejection_for_alice(None, None, Some(&admission), &router).await;
+        assert_eq!(told, Some(Rejection::ServerFull));
+        drop(held);
+        assert_eq!(
+            rejection_for_alice(None, None, Some(&admission), &router).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn exhausted_address_pool_is_reported() {
+        let (router, _tun) = router();
+        let mut leases = Vec::new();
+        while let Ok(lease) = router.clone().get_ip(&identity("other")).await {
+            leases.push(lease);
+        }
+        let told = rejection_for_alice(None, None, None, &router).await;
+        assert_eq!(told, Some(Rejection::NoFreeAddress));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mtu_query_is_retried() {
+        let mut failures = 2;
+        let start = time::Instant::now();
+        let mtu = query_mtu(|| match failures {
+            0 => Ok(1400),
+            _ => {
+                failures -= 1;
+                Err("not ready")
+            }
+        })
+        .await;
+        assert_eq!(mtu, 1400);
+        assert_eq!(start.elapsed(), MTU_QUERY_DELAY * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_mtu_query_falls_back() {
+        let mut queries = 0;
+        let mtu = query_mtu(|| {
+            queries += 1;
+            Err::<u16, _>("not supported")
+        })
+        .await;
+        assert_eq!(mtu, FALLBACK_MTU);
+        assert_eq!(queries, MTU_QUERY_ATTEMPTS);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn every_signal_flips_maintenance_mode() {
+        use std::io::Write;
+
+        let (signals, mut pipe) = std::os::unix::net::UnixStream::pair().unwrap();
+        signals.set_nonblocking(true).unwrap();
+        let signals = tokio::net::UnixStream::from_std(signals).unwrap();
+        let maintenance = Arc::new(AtomicBool::new(false));
+        let toggle = tokio::spawn(toggle_maintenance(signals, maintenance.clone()));
+        pipe.write_all(&[0]).unwrap();
+        while !maintenance.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        pipe.write_all(&[0, 0, 0]).unwrap();
+        drop(pipe);
+        toggle.await.unwrap();
+        assert!(!maintenance.load(Ordering::Relaxed));
+    }
 }
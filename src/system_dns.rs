@@ -0,0 +1,526 @@
+#[cfg(target_os = "linux")]
+pub use linux::DnsOverride;
+#[cfg(windows)]
+pub use windows::DnsOverride;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        fs,
+        net::Ipv4Addr,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use anyhow::{bail, Context};
+    use log::{info, warn};
+
+    use crate::{common::temp_path, undo_script};
+
+    const RESOLV_CONF: &str = "/etc/resolv.conf";
+    const RESOLVED_RUNTIME_DIR: &str = "/run/systemd/resolve";
+
+    pub struct DnsOverride {
+        previous: Previous,
+    }
+
+    enum Previous {
+        Resolved { interface: String },
+        ResolvConf(ResolvConf),
+    }
+
+    enum ResolvConf {
+        File(Vec<u8>),
+        Symlink(PathBuf),
+    }
+
+    impl DnsOverride {
+        pub fn apply(
+            interface: &str,
+            servers: &[Ipv4Addr],
+            search_domain: Option<&str>,
+        ) -> anyhow::Result<Self> {
+            let previous = if Path::new(RESOLVED_RUNTIME_DIR).is_dir() {
+                apply_resolved(interface, servers, search_domain)?;
+                Previous::Resolved {
+                    interface: interface.into(),
+                }
+            } else {
+                Previous::ResolvConf(apply_resolv_conf(
+                    Path::new(RESOLV_CONF),
+                    servers,
+                    search_domain,
+                )?)
+            };
+            if !servers.is_empty() {
+                info!("using DNS servers {servers:?}");
+            }
+            if let Some(domain) = search_domain {
+                info!("using search domain {domain}");
+            }
+            Ok(Self { previous })
+        }
+
+        pub fn undo_commands(&self) -> Vec<String> {
+            match &self.previous {
+                Previous::Resolved { interface } => {
+                    vec![undo_script::command(&["resolvectl", "revert", interface])]
+                }
+                Previous::ResolvConf(ResolvConf::File(contents)) => vec![format!(
+                    "{} > {RESOLV_CONF}",
+                    undo_script::command(&["printf", "%s", &String::from_utf8_lossy(contents)])
+                )],
+                Previous::ResolvConf(ResolvConf::Symlink(target)) => vec![undo_script::command(&[
+                    "ln",
+                    "-sfn",
+                    &target.to_string_lossy(),
+                    RESOLV_CONF,
+                ])],
+            }
+        }
+    }
+
+    impl Drop for DnsOverride {
+        fn drop(&mut self) {
+            let res = match &self.previous {
+                // the interface settings vanish with the interface, reverting only speeds it up
+                Previous::Resolved { interface } => {
+                    _ = resolvectl(&["revert", interface]);
+                    Ok(())
+                }
+                Previous::ResolvConf(previous) => {
+                    restore_resolv_conf(Path::new(RESOLV_CONF), previous)
+                }
+            };
+            match res {
+                Ok(()) => info!("DNS settings restored"),
+                Err(e) => warn!("could not restore DNS settings: {e:#}"),
+            }
+        }
+    }
+
+    // the catch-all routing domain sends every query to the tunnel's servers,
+    // without pushed servers only the search domain is set
+    fn apply_resolved(
+        interface: &str,
+        servers: &[Ipv4Addr],
+        search_domain: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut domains = vec!["domain", interface];
+        if !servers.is_empty() {
+            let servers = servers.iter().map(ToString::to_string).collect::<Vec<_>>();
+            let mut args = vec!["dns", interface];
+            args.extend(servers.iter().map(String::as_str));
+            resolvectl(&args)?;
+            domains.push("~.");
+        }
+        domains.extend(search_domain);
+        resolvectl(&domains)
+    }
+
+    fn resolvectl(args: &[&str]) -> anyhow::Result<()> {
+        let output = Command::new("resolvectl")
+            .args(args)
+            .output()
+            .context("could not run resolvectl")?;
+        if !output.status.success() {
+            bail!(
+                "resolvectl {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    fn apply_resolv_conf(
+        path: &Path,
+        servers: &[Ipv4Addr],
+        search_domain: Option<&str>,
+    ) -> anyhow::Result<ResolvConf> {
+        let is_symlink = fs::symlink_metadata(path)
+            .with_context(|| format!("could not read {}", path.display()))?
+            .is_symlink();
+        // writing through a symlink would change a file some other tool manages,
+        // so the link itself is kept and replaced, its target may not even exist
+        let (previous, contents) = if is_symlink {
+            let target = fs::read_link(path)
+                .with_context(|| format!("could not read symlink {}", path.display()))?;
+            (
+                ResolvConf::Symlink(target),
+                fs::read(path).unwrap_or_default(),
+            )
+        } else {
+            // restoring an empty file would leave the machine without DNS
+            let contents =
+                fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+            (ResolvConf::File(contents.clone()), contents)
+        };
+        let updated =
+            override_resolv_conf(&String::from_utf8_lossy(&contents), servers, search_domain);
+        replace(path, |temp| fs::write(temp, &updated))
+            .with_context(|| format!("could not write {}", path.display()))?;
+        Ok(previous)
+    }
+
+    // the new file is renamed over the old one, which stays in place if anything fails
+    fn replace(
+        path: &Path,
+        create: impl FnOnce(&Path) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let temp = temp_path(path);
+        let res = create(&temp).and_then(|()| fs::rename(&temp, path));
+        if res.is_err() {
+            _ = fs::remove_file(&temp);
+        }
+        res
+    }
+
+    // other settings stay in effect, the pushed domain is searched before the local ones
+    fn override_resolv_conf(
+        contents: &str,
+        servers: &[Ipv4Addr],
+        search_domain: Option<&str>,
+    ) -> String {
+        let mut updated = String::from("# written by opaque-vpn, restored on exit\n");
+        for server in servers {
+            updated.push_str(&format!("nameserver {server}\n"));
+        }
+        let mut search = Vec::from_iter(search_domain);
+        for line in contents.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") if !servers.is_empty() => {}
+                // the last of search and domain wins, so both are merged into one search line
+                Some("search" | "domain") if search_domain.is_some() => search.extend(words),
+                _ => {
+                    updated.push_str(line);
+                    updated.push('\n');
+                }
+            }
+        }
+        if search_domain.is_some() {
+            updated.push_str(&format!("search {}\n", search.join(" ")));
+        }
+        updated
+    }
+
+    fn restore_resolv_conf(path: &Path, previous: &ResolvConf) -> anyhow::Result<()> {
+        match previous {
+            ResolvConf::File(contents) => replace(path, |temp| fs::write(temp, contents))?,
+            ResolvConf::Symlink(target) => {
+                replace(path, |temp| std::os::unix::fs::symlink(target, temp))?
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const CONTENTS: &str = "nameserver 192.168.1.1\nsearch home.lan\noptions edns0\n";
+
+        #[test]
+        fn pushed_servers_replace_the_local_ones() {
+            assert_eq!(
+                override_resolv_conf(CONTENTS, &[Ipv4Addr::new(10, 0, 0, 1)], None),
+                "# written by opaque-vpn, restored on exit\nnameserver 10.0.0.1\n\
+                 search home.lan\noptions edns0\n"
+            );
+        }
+
+        #[test]
+        fn pushed_search_domain_comes_first() {
+            assert_eq!(
+                override_resolv_conf(
+                    "domain example.com\nnameserver 192.168.1.1\nsearch home.lan\n",
+                    &[],
+                    Some("corp.internal")
+                ),
+                "# written by opaque-vpn, restored on exit\nnameserver 192.168.1.1\n\
+                 search corp.internal example.com home.lan\n"
+            );
+        }
+
+        fn scratch_dir(name: &str) -> PathBuf {
+            let dir =
+                std::env::temp_dir().join(format!("opaque-vpn-{}-{name}", std::process::id()));
+            _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            dir
+        }
+
+        #[test]
+        fn resolv_conf_file_is_replaced_and_restored() {
+            let dir = scratch_dir("resolv-file");
+            let path = dir.join("resolv.conf");
+            fs::write(&path, CONTENTS).unwrap();
+            let previous = apply_resolv_conf(&path, &[Ipv4Addr::new(10, 0, 0, 1)], None).unwrap();
+            assert!(fs::read_to_string(&path)
+                .unwrap()
+                .contains("nameserver 10.0.0.1"));
+            assert!(!temp_path(&path).exists());
+            restore_resolv_conf(&path, &previous).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), CONTENTS);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn resolv_conf_symlink_is_kept_aside_and_put_back() {
+            let dir = scratch_dir("resolv-symlink");
+            let path = dir.join("resolv.conf");
+            fs::write(dir.join("stub-resolv.conf"), CONTENTS).unwrap();
+            std::os::unix::fs::symlink("stub-resolv.conf", &path).unwrap();
+            let previous = apply_resolv_conf(&path, &[Ipv4Addr::new(10, 0, 0, 1)], None).unwrap();
+            assert!(!fs::symlink_metadata(&path).unwrap().is_symlink());
+            // the file behind the link is left alone
+            assert_eq!(
+                fs::read_to_string(dir.join("stub-resolv.conf")).unwrap(),
+                CONTENTS
+            );
+            restore_resolv_conf(&path, &previous).unwrap();
+            assert_eq!(fs::read_link(&path).unwrap(), Path::new("stub-resolv.conf"));
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn missing_resolv_conf_is_not_backed_up_as_empty() {
+            let dir = scratch_dir("resolv-missing");
+            let path = dir.join("resolv.conf");
+            assert!(apply_resolv_conf(&path, &[Ipv4Addr::new(10, 0, 0, 1)], None).is_err());
+            assert!(!path.exists());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn failed_write_leaves_the_symlink_in_place() {
+            let dir = scratch_dir("resolv-failed");
+            let path = dir.join("resolv.conf");
+            std::os::unix::fs::symlink("stub-resolv.conf", &path).unwrap();
+            // a directory in the way of the temporary file makes the write fail
+            fs::create_dir(temp_path(&path)).unwrap();
+            assert!(apply_resolv_conf(&path, &[Ipv4Addr::new(10, 0, 0, 1)], None).is_err());
+            assert_eq!(fs::read_link(&path).unwrap(), Path::new("stub-resolv.conf"));
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn undo_puts_back_the_previous_resolv_conf() {
+            let commands = |previous| {
+                let dns = DnsOverride { previous };
+                let commands = dns.undo_commands();
+                // the drop would restore the machine's own settings
+                std::mem::forget(dns);
+                commands
+            };
+            assert_eq!(
+                commands(Previous::ResolvConf(ResolvConf::File(CONTENTS.into()))),
+                [
+                    "printf %s 'nameserver 192.168.1.1\nsearch home.lan\noptions edns0\n' \
+                  > /etc/resolv.conf"
+                ]
+            );
+            assert_eq!(
+                commands(Previous::ResolvConf(ResolvConf::Symlink(
+                    "../run/systemd/resolve/stub-resolv.conf".into()
+                ))),
+                ["ln -sfn ../run/systemd/resolve/stub-resolv.conf /etc/resolv.conf"]
+            );
+            assert_eq!(
+                commands(Previous::Resolved {
+                    interface: "tun0".into()
+                }),
+                ["resolvectl revert tun0"]
+            );
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{net::Ipv4Addr, process::Command};
+
+    use anyhow::{bail, Context};
+    use log::{info, warn};
+
+    // the tunnel adapter starts out without DNS servers or a suffix of its own, going
+    // back to DHCP and clearing the suffix on drop restores exactly that
+    pub struct DnsOverride {
+        interface: String,
+        search_domain: bool,
+    }
+
+    impl DnsOverride {
+        pub fn apply(
+            interface: &str,
+            servers: &[Ipv4Addr],
+            search_domain: Option<&str>,
+        ) -> anyhow::Result<Self> {
+            // created first, so that a partly applied list is reverted as well
+            let dns = Self {
+                interface: interface.into(),
+                search_domain: search_domain.is_some(),
+            };
+            for args in set_servers_args(interface, servers) {
+                netsh(&args)?;
+            }
+            if !servers.is_empty() {
+                info!("using DNS servers {servers:?}");
+            }
+            if let Some(domain) = search_domain {
+                powershell(&set_suffix_command(interface, Some(domain)))?;
+                info!("using search domain {domain}");
+            }
+            Ok(dns)
+        }
+
+        // the configuration only allows undo scripts on Linux
+        pub fn undo_commands(&self) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
+    impl Drop for DnsOverride {
+        fn drop(&mut self) {
+            let mut res = netsh(&revert_args(&self.interface));
+            if self.search_domain {
+                res = res.and(powershell(&set_suffix_command(&self.interface, None)));
+            }
+            match res {
+                Ok(()) => info!("DNS settings restored"),
+                Err(e) => warn!("could not restore DNS settings: {e:#}"),
+            }
+        }
+    }
+
+    fn set_servers_args(interface: &str, servers: &[Ipv4Addr]) -> Vec<Vec<String>> {
+        let name = format!("name={interface}");
+        servers
+            .iter()
+            .enumerate()
+            .map(|(index, server)| {
+                let address = format!("address={server}");
+                let args: &[&str] = match index {
+                    0 => &["set", &name, "source=static", &address, "register=none"],
+                    _ => &["add", &name, &address, &format!("index={}", index + 1)],
+                };
+                dnsservers(args.iter().copied().chain(["validate=no"]))
+            })
+            .collect()
+    }
+
+    fn revert_args(interface: &str) -> Vec<String> {
+        dnsservers(["set", &format!("name={interface}"), "source=dhcp"])
+    }
+
+    fn dnsservers<'a>(args: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+        let mut command = vec!["interface", "ipv4"];
+        let mut args = args.into_iter();
+        command.extend(args.next());
+        command.push("dnsservers");
+        command.extend(args);
+        command.into_iter().map(String::from).collect()
+    }
+
+    // netsh has no per-adapter suffix setting, the domain is validated so it needs no quoting
+    fn set_suffix_command(interface: &str, search_domain: Option<&str>) -> String {
+        let alias = interface.replace('\'', "''");
+        match search_domain {
+            Some(domain) => format!(
+                "Set-DnsClient -InterfaceAlias '{alias}' -ConnectionSpecificSuffix '{domain}'"
+            ),
+            None => {
+                format!("Set-DnsClient -InterfaceAlias '{alias}' -ResetConnectionSpecificSuffix")
+            }
+        }
+    }
+
+    fn powershell(command: &str) -> anyhow::Result<()> {
+        let output = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", command])
+            .output()
+            .context("could not run powershell")?;
+        if !output.status.success() {
+            bail!(
+                "{command} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    fn netsh(args: &[String]) -> anyhow::Result<()> {
+        let output = Command::new("netsh")
+            .args(args)
+            .output()
+            .context("could not run netsh")?;
+        if !output.status.success() {
+            // netsh reports its errors on stdout
+            bail!(
+                "netsh {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn servers_are_set_in_order() {
+            let servers = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+            assert_eq!(
+                set_servers_args("tun0", &servers),
+                [
+                    "interface ipv4 set dnsservers name=tun0 source=static address=10.0.0.1 \
+                     register=none validate=no",
+                    "interface ipv4 add dnsservers name=tun0 address=10.0.0.2 index=2 validate=no",
+                ]
+                .map(|command| command.split(' ').map(String::from).collect::<Vec<_>>())
+            );
+        }
+
+        #[test]
+        fn suffix_is_set_and_reset_per_adapter() {
+            assert_eq!(
+                set_suffix_command("opaque 'vpn'", Some("corp.internal")),
+                "Set-DnsClient -InterfaceAlias 'opaque ''vpn''' \
+                 -ConnectionSpecificSuffix 'corp.internal'"
+            );
+            assert_eq!(
+                set_suffix_command("tun0", None),
+                "Set-DnsClient -InterfaceAlias 'tun0' -ResetConnectionSpecificSuffix"
+            );
+        }
+
+        #[test]
+        fn revert_returns_the_adapter_to_dhcp() {
+            assert_eq!(
+                revert_args("tun0").join(" "),
+                "interface ipv4 set dnsservers name=tun0 source=dhcp"
+            );
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub struct DnsOverride;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl DnsOverride {
+    pub fn apply(
+        _interface: &str,
+        _servers: &[std::net::Ipv4Addr],
+        _search_domain: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        anyhow::bail!("applying pushed DNS settings is only supported on Linux and Windows")
+    }
+
+    pub fn undo_commands(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
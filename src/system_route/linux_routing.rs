@@ -0,0 +1,243 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+
+use crate::undo_script;
+
+pub struct DefaultRoute {
+    original: Option<Vec<String>>,
+    rerouted: Option<Rerouted>,
+}
+
+struct Rerouted {
+    server: IpAddr,
+    server_route: Option<Vec<String>>,
+    gateway: Ipv4Addr,
+    interface: String,
+}
+
+impl DefaultRoute {
+    pub fn try_new() -> anyhow::Result<Self> {
+        let routes = ip(&["-4", "route", "show", "default"])?;
+        let original = routes.lines().next().map(|route| {
+            route
+                .split_whitespace()
+                .filter(|&token| token != "linkdown")
+                .map(String::from)
+                .collect()
+        });
+        Ok(Self {
+            original,
+            rerouted: None,
+        })
+    }
+
+    pub fn reroute(
+        &mut self,
+        server: IpAddr,
+        gateway: Ipv4Addr,
+        interface: &str,
+    ) -> anyhow::Result<()> {
+        if self.rerouted.as_ref().is_some_and(|rerouted| {
+            rerouted.server == server
+                && rerouted.gateway == gateway
+                && rerouted.interface == interface
+        }) {
+            return Ok(());
+        }
+        self.reset()?;
+
+        // the server has to stay reachable the old way once everything else enters the tunnel
+        let path = ip(&["route", "get", &server.to_string()])?;
+        let server_route = host_route(server, &path);
+        if let Some(route) = &server_route {
+            ip(&prepend(&["route", "replace"], route))
+                .context("could not pin the route to the server")?;
+        }
+        self.rerouted = Some(Rerouted {
+            server,
+            server_route,
+            gateway,
+            interface: interface.into(),
+        });
+        ip(&[
+            "route",
+            "replace",
+            "default",
+            "via",
+            &gateway.to_string(),
+            "dev",
+            interface,
+        ])
+        .context("could not replace the default route")?;
+        info!("default route replaced with {gateway} on {interface}");
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        let Some(rerouted) = self.rerouted.take() else {
+            return Ok(());
+        };
+        // the route is already gone if the kernel removed the interface first
+        _ = ip(&[
+            "route",
+            "del",
+            "default",
+            "via",
+            &rerouted.gateway.to_string(),
+            "dev",
+            &rerouted.interface,
+        ]);
+        if let Some(original) = &self.original {
+            ip(&prepend(&["route", "replace"], original))
+                .context("could not restore the default route")?;
+        }
+        if let Some(route) = &rerouted.server_route {
+            ip(&prepend(&["route", "del"], route))
+                .context("could not remove the route to the server")?;
+        }
+        info!("default route restored");
+        Ok(())
+    }
+
+    // the same steps as reset, for when the process is gone before it could run them
+    pub fn undo_commands(&self) -> Vec<String> {
+        let Some(rerouted) = &self.rerouted else {
+            return Vec::new();
+        };
+        let mut commands = vec![undo_script::command(&[
+            "ip",
+            "route",
+            "del",
+            "default",
+            "via",
+            &rerouted.gateway.to_string(),
+            "dev",
+            &rerouted.interface,
+        ])];
+        if let Some(original) = &self.original {
+            commands.push(undo_script::command(&prepend(
+                &["ip", "route", "replace"],
+                original,
+            )));
+        }
+        if let Some(route) = &rerouted.server_route {
+            commands.push(undo_script::command(&prepend(
+                &["ip", "route", "del"],
+                route,
+            )));
+        }
+        commands
+    }
+}
+
+impl Drop for DefaultRoute {
+    fn drop(&mut self) {
+        if let Err(e) = self.reset() {
+            warn!("{e:#}");
+        }
+    }
+}
+
+// the kernel drops these together with the interface, so only withdrawn routes are removed
+pub fn add_interface_route(prefix: Ipv4Addr, len: u8, interface: &str) -> anyhow::Result<()> {
+    ip(&[
+        "route",
+        "replace",
+        &format!("{prefix}/{len}"),
+        "dev",
+        interface,
+    ])?;
+    Ok(())
+}
+
+pub fn remove_interface_route(prefix: Ipv4Addr, len: u8, interface: &str) -> anyhow::Result<()> {
+    ip(&["route", "del", &format!("{prefix}/{len}"), "dev", interface])?;
+    Ok(())
+}
+
+// local and directly connected servers are not affected by the default route
+fn host_route(server: IpAddr, path: &str) -> Option<Vec<String>> {
+    let tokens = path.split_whitespace().collect::<Vec<_>>();
+    if tokens.first() == Some(&"local") {
+        return None;
+    }
+    let value = |key| {
+        tokens
+            .windows(2)
+            .find(|pair| pair[0] == key)
+            .map(|pair| pair[1].to_string())
+    };
+    Some(vec![
+        server.to_string(),
+        "via".into(),
+        value("via")?,
+        "dev".into(),
+        value("dev")?,
+    ])
+}
+
+fn prepend<'a>(command: &[&'a str], args: &'a [String]) -> Vec<&'a str> {
+    command
+        .iter()
+        .copied()
+        .chain(args.iter().map(String::as_str))
+        .collect()
+}
+
+fn ip(args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .context("could not run ip")?;
+    if !output.status.success() {
+        bail!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_restores_the_original_default_route() {
+        let mut route = DefaultRoute {
+            original: Some(
+                "default via 192.168.1.1 dev eth0 proto dhcp metric 100"
+                    .split(' ')
+                    .map(String::from)
+                    .collect(),
+            ),
+            rerouted: Some(Rerouted {
+                server: "203.0.113.7".parse().unwrap(),
+                server_route: host_route(
+                    "203.0.113.7".parse().unwrap(),
+                    "203.0.113.7 via 192.168.1.1 dev eth0 src 192.168.1.20 uid 0",
+                ),
+                gateway: Ipv4Addr::new(10, 0, 0, 1),
+                interface: "tun0".into(),
+            }),
+        };
+        assert_eq!(
+            route.undo_commands(),
+            [
+                "ip route del default via 10.0.0.1 dev tun0",
+                "ip route replace default via 192.168.1.1 dev eth0 proto dhcp metric 100",
+                "ip route del 203.0.113.7 via 192.168.1.1 dev eth0",
+            ]
+        );
+        // restoring what was never changed would only get in the way, this also keeps
+        // the drop from touching the routes of the machine running the test
+        route.rerouted = None;
+        assert!(route.undo_commands().is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+#[cfg(target_os = "linux")]
+mod linux_routing;
+
+#[cfg(target_os = "linux")]
+pub use linux_routing::{add_interface_route, remove_interface_route, DefaultRoute};
+
+#[cfg(not(target_os = "linux"))]
+pub struct DefaultRoute;
+
+#[cfg(not(target_os = "linux"))]
+impl DefaultRoute {
+    pub fn try_new() -> anyhow::Result<Self> {
+        anyhow::bail!("managing the default route is only supported on Linux")
+    }
+
+    pub fn reroute(
+        &mut self,
+        _server: std::net::IpAddr,
+        _gateway: std::net::Ipv4Addr,
+        _interface: &str,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    pub fn undo_commands(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn add_interface_route(
+    _prefix: std::net::Ipv4Addr,
+    _len: u8,
+    _interface: &str,
+) -> anyhow::Result<()> {
+    anyhow::bail!("installing subnet routes is only supported on Linux")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn remove_interface_route(
+    _prefix: std::net::Ipv4Addr,
+    _len: u8,
+    _interface: &str,
+) -> anyhow::Result<()> {
+    Ok(())
+}
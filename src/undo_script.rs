@@ -0,0 +1,111 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::warn;
+
+const HEADER: &str = "#!/bin/sh\n\
+    # written by opaque-vpn, restores the routes and DNS settings it changed\n\
+    # run it only if opaque-vpn exited without restoring them itself\n";
+
+// kept up to date while the client runs, removed once the client has restored everything
+pub struct UndoScript {
+    path: PathBuf,
+}
+
+impl UndoScript {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn write(&self, commands: &[String]) -> anyhow::Result<()> {
+        write_executable(&self.path, &render(commands))
+            .with_context(|| format!("could not write undo script {}", self.path.display()))
+    }
+}
+
+impl Drop for UndoScript {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("could not remove undo script {}: {e}", self.path.display()),
+        }
+    }
+}
+
+// every command runs even if an earlier one fails, a partial restore beats none
+fn render(commands: &[String]) -> String {
+    let mut script = String::from(HEADER);
+    for command in commands {
+        script.push_str(command);
+        script.push('\n');
+    }
+    script
+}
+
+pub fn command(args: &[&str]) -> String {
+    args.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"./:_-=,@%+".contains(&byte));
+    if plain {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(unix)]
+fn write_executable(path: &Path, script: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::write(path, script)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(not(unix))]
+fn write_executable(path: &Path, script: &str) -> std::io::Result<()> {
+    fs::write(path, script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_are_quoted_for_the_shell() {
+        assert_eq!(
+            command(&["ip", "route", "replace", "default", "via", "192.168.1.1"]),
+            "ip route replace default via 192.168.1.1"
+        );
+        assert_eq!(
+            command(&["printf", "%s", "search home.lan\n"]),
+            "printf %s 'search home.lan\n'"
+        );
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn script_lives_as_long_as_the_guard() {
+        let path = std::env::temp_dir().join(format!("opaque-vpn-{}-undo.sh", std::process::id()));
+        let script = UndoScript::new(path.clone());
+        script.write(&["resolvectl revert tun0".into()]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{HEADER}resolvectl revert tun0\n")
+        );
+        drop(script);
+        assert!(!path.exists());
+    }
+}
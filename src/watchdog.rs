@@ -0,0 +1,106 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::error;
+use tokio::time;
+
+const PROBES_PER_TIMEOUT: u32 = 4;
+
+pub struct Watchdog {
+    state: Arc<State>,
+}
+
+#[derive(Default)]
+struct State {
+    heartbeats: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl Watchdog {
+    pub fn start(timeout: Duration, probe: impl Fn() -> bool + Send + 'static) -> Self {
+        Self::with_stall_handler(timeout, probe, || std::process::abort())
+    }
+
+    // a heartbeat needs both the runtime to run the probe and the probe to pass
+    fn with_stall_handler(
+        timeout: Duration,
+        probe: impl Fn() -> bool + Send + 'static,
+        on_stall: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        let state = Arc::new(State::default());
+
+        let probe_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(timeout / PROBES_PER_TIMEOUT);
+            while !probe_state.stopped.load(Ordering::Relaxed) {
+                interval.tick().await;
+                if probe() {
+                    probe_state.heartbeats.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        // runs outside the runtime so that it notices the runtime itself being stuck
+        let monitor_state = state.clone();
+        thread::spawn(move || {
+            let mut last_heartbeats = monitor_state.heartbeats.load(Ordering::Relaxed);
+            loop {
+                thread::sleep(timeout);
+                if monitor_state.stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                let heartbeats = monitor_state.heartbeats.load(Ordering::Relaxed);
+                if heartbeats == last_heartbeats {
+                    error!("watchdog: router made no progress for {timeout:?}, aborting");
+                    on_stall();
+                    return;
+                }
+                last_heartbeats = heartbeats;
+            }
+        });
+
+        Self { state }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn watchdog(probe: impl Fn() -> bool + Send + 'static) -> (Watchdog, oneshot::Receiver<()>) {
+        let (stalled, stall_receiver) = oneshot::channel();
+        let watchdog = Watchdog::with_stall_handler(TIMEOUT, probe, move || {
+            _ = stalled.send(());
+        });
+        (watchdog, stall_receiver)
+    }
+
+    #[tokio::test]
+    async fn passing_probes_keep_the_watchdog_quiet() {
+        let (_watchdog, mut stalled) = watchdog(|| true);
+        time::sleep(3 * TIMEOUT).await;
+        assert!(stalled.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn failing_probes_trigger_the_stall_handler() {
+        let (_watchdog, stalled) = watchdog(|| false);
+        time::timeout(10 * TIMEOUT, stalled).await.unwrap().unwrap();
+    }
+}